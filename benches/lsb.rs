@@ -0,0 +1,89 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kiki::methods::data::{BatchIterator, BitIterator, FromBits};
+use kiki::methods::lsb::{read_bytes, write_bits, Layout};
+use kiki::methods::pixel::{KeyedPermutation, PixelOrder, RandomPixelIterator};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const WIDTH: u32 = 2048;
+const HEIGHT: u32 = 2048;
+const STRIDE: usize = 4;
+const BITS_PER_PIXEL: usize = 3;
+
+fn carrier() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(1);
+    (0..WIDTH as usize * HEIGHT as usize * STRIDE)
+        .map(|_| rng.gen())
+        .collect()
+}
+
+fn message() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(2);
+    let len = WIDTH as usize * HEIGHT as usize * BITS_PER_PIXEL / 8 / 2;
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+// The bit by bit pipeline used before the word-wise one, kept as a reference.
+fn reference_write(buf: &mut [u8], data: &[u8]) {
+    let pixels = RandomPixelIterator::new((WIDTH, HEIGHT), StdRng::seed_from_u64(3));
+    for ((x, y), bits) in pixels.zip(BatchIterator::new(BitIterator::new(data), BITS_PER_PIXEL)) {
+        let pos = (y as usize * WIDTH as usize + x as usize) * STRIDE;
+        for (channel, bit) in bits.into_iter().enumerate() {
+            if let Some(bit) = bit {
+                buf[pos + channel] = buf[pos + channel] & !1 | bit;
+            }
+        }
+    }
+}
+
+fn reference_read(buf: &[u8], len: usize) -> Vec<u8> {
+    let pixels = RandomPixelIterator::new((WIDTH, HEIGHT), StdRng::seed_from_u64(3));
+    let bits = pixels
+        .take((len * 8).div_ceil(BITS_PER_PIXEL))
+        .flat_map(|(x, y)| {
+            let pos = (y as usize * WIDTH as usize + x as usize) * STRIDE;
+            buf[pos..pos + BITS_PER_PIXEL]
+                .iter()
+                .map(|c| c & 1)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    bits.chunks(8).take(len).map(u8::from_bits).collect()
+}
+
+fn keyed_order() -> PixelOrder {
+    let total = WIDTH as u64 * HEIGHT as u64;
+    PixelOrder::Keyed(KeyedPermutation::new(total, &mut StdRng::seed_from_u64(3)))
+}
+
+fn bench_lsb(c: &mut Criterion) {
+    let data = message();
+    let mut group = c.benchmark_group("lsb");
+    group.sample_size(10);
+
+    group.bench_function("embed/reference", |b| {
+        let mut buf = carrier();
+        b.iter(|| reference_write(black_box(&mut buf), black_box(&data)))
+    });
+    group.bench_function("embed/word", |b| {
+        let mut buf = carrier();
+        let order = keyed_order();
+        b.iter(|| write_bits(black_box(&mut buf), Layout::RGBA, &order, black_box(&data)))
+    });
+
+    let mut buf = carrier();
+    reference_write(&mut buf, &data);
+    group.bench_function("extract/reference", |b| {
+        b.iter(|| reference_read(black_box(&buf), data.len()))
+    });
+
+    let order = keyed_order();
+    write_bits(&mut buf, Layout::RGBA, &order, &data);
+    group.bench_function("extract/word", |b| {
+        b.iter(|| read_bytes(black_box(&buf), Layout::RGBA, &order, 0, data.len()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_lsb);
+criterion_main!(benches);
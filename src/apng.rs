@@ -0,0 +1,229 @@
+use crate::chunks::{self, Chunk};
+use crate::errors::{AppError, AppErrorKind};
+use std::io::Cursor;
+
+fn image_error(err: impl std::fmt::Display) -> AppError {
+    AppError::new(AppErrorKind::Image, err.to_string())
+}
+
+/// The raw rows of a frame, unfiltered but still packed as in the file.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// A PNG file, still or animated, decoded frame by frame without compositing.
+///
+/// Only the pixels of the frames may be changed. When encoded again, every
+/// other chunk of the original file is kept, so that the palette, the frame
+/// timings, disposal and blend operations and the loop count are unchanged.
+pub struct Png {
+    original: Vec<u8>,
+    pub info: Box<png::Info<'static>>,
+    pub frames: Vec<Frame>,
+}
+
+impl Png {
+    pub fn decode(data: Vec<u8>) -> Result<Self, AppError> {
+        let mut decoder = png::Decoder::new(Cursor::new(&data));
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let mut reader = decoder.read_info().map_err(image_error)?;
+        let info = Box::new(reader.info().clone());
+
+        let count = match &info.animation_control {
+            None => 1,
+            Some(actl) if info.frame_control.is_none() => actl.num_frames + 1,
+            Some(actl) => actl.num_frames,
+        };
+
+        // Interlaced frames are written at the stride of the whole image.
+        let stride = info.raw_row_length_from_width(info.width) - 1;
+        let mut buf = vec![0; reader.output_buffer_size().unwrap_or_default()];
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let output = reader.next_frame(&mut buf).map_err(image_error)?;
+            let stride = if info.interlaced {
+                stride
+            } else {
+                output.line_size
+            };
+            let data = buf
+                .chunks(stride)
+                .take(output.height as usize)
+                .flat_map(|row| &row[..output.line_size])
+                .copied()
+                .collect();
+            frames.push(Frame {
+                width: output.width,
+                height: output.height,
+                data,
+            });
+        }
+
+        Ok(Png {
+            original: data,
+            info,
+            frames,
+        })
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.info.animation_control.is_some()
+    }
+
+    /// Compresses the frame as the image data of a still PNG, and returns its
+    /// zlib stream.
+    fn compress(&self, frame: &Frame) -> Result<Vec<u8>, AppError> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, frame.width, frame.height);
+        encoder.set_color(self.info.color_type);
+        encoder.set_depth(self.info.bit_depth);
+        if let Some(palette) = &self.info.palette {
+            encoder.set_palette(palette.to_vec());
+        }
+        let mut writer = encoder.write_header().map_err(image_error)?;
+        writer.write_image_data(&frame.data).map_err(image_error)?;
+        writer.finish().map_err(image_error)?;
+
+        Ok(chunks::split(&out)?
+            .into_iter()
+            .filter(|c| c.is(b"IDAT"))
+            .flat_map(|c| c.data)
+            .collect())
+    }
+
+    /// Encodes the PNG again with the current frames.
+    ///
+    /// The image data of each frame is replaced by a single IDAT or fdAT chunk
+    /// and the sequence numbers of the animation chunks are renumbered. The
+    /// frames are not interlaced anymore.
+    pub fn encode(&self) -> Result<Vec<u8>, AppError> {
+        let mut streams = self.frames.iter().map(|f| self.compress(f));
+        let mut out = Vec::new();
+        let mut sequence = 0u32;
+        let mut in_data = false;
+
+        for mut chunk in chunks::split(&self.original)? {
+            let is_data = chunk.is(b"IDAT") || chunk.is(b"fdAT");
+            if is_data && in_data {
+                continue;
+            }
+            in_data = is_data;
+
+            if chunk.is(b"IHDR") {
+                chunk.data[12] = 0;
+            } else if chunk.is(b"fcTL") {
+                chunk.data[..4].copy_from_slice(&sequence.to_be_bytes());
+                sequence += 1;
+            } else if is_data {
+                let stream = streams
+                    .next()
+                    .ok_or_else(|| image_error("The PNG file has more frames than decoded."))??;
+                chunk = if chunk.is(b"IDAT") {
+                    Chunk::new(b"IDAT", stream)
+                } else {
+                    let mut data = sequence.to_be_bytes().to_vec();
+                    data.extend(stream);
+                    sequence += 1;
+                    Chunk::new(b"fdAT", data)
+                };
+            }
+            out.push(chunk);
+        }
+        Ok(chunks::join(&out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 20x10 RGB animation of three frames, the second one 8x4 at (5, 3).
+    fn animation() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 20, 10);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_animated(3, 2).unwrap();
+        encoder
+            .add_text_chunk("Title".into(), "frames".into())
+            .unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        for (idx, (width, height)) in [(20, 10), (8, 4), (20, 10)].into_iter().enumerate() {
+            writer.set_frame_position(0, 0).unwrap();
+            writer.set_frame_dimension(width, height).unwrap();
+            writer
+                .set_frame_position(5 * (idx as u32 % 2), 3 * (idx as u32 % 2))
+                .unwrap();
+            writer.set_frame_delay(idx as u16 + 1, 10).unwrap();
+            let pixels = (0..width * height * 3).map(|i| (i as usize * (idx + 3)) as u8);
+            writer
+                .write_image_data(&pixels.collect::<Vec<_>>())
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        data
+    }
+
+    /// The chunks of a PNG, with the sequence numbers cleared.
+    fn skeleton(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let chunks = chunks::split(data).unwrap();
+        let skeleton = chunks
+            .into_iter()
+            .filter(|c| !c.is(b"IDAT") && !c.is(b"fdAT"));
+        skeleton
+            .map(|c| {
+                let mut data = c.data;
+                if c.kind == *b"fcTL" {
+                    data[..4].fill(0);
+                }
+                (String::from_utf8_lossy(&c.kind).into_owned(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_rewritten_in_place() {
+        let original = animation();
+        let mut png = Png::decode(original.clone()).unwrap();
+        assert!(png.is_animated());
+        let sizes = png.frames.iter().map(|f| (f.width, f.height));
+        assert_eq!(sizes.collect::<Vec<_>>(), [(20, 10), (8, 4), (20, 10)]);
+        for frame in &mut png.frames {
+            frame.data.iter_mut().for_each(|b| *b ^= 1);
+        }
+
+        let encoded = png.encode().unwrap();
+        assert_eq!(skeleton(&encoded), skeleton(&original));
+        let sequence = chunks::split(&encoded)
+            .unwrap()
+            .into_iter()
+            .filter(|c| c.is(b"fcTL") || c.is(b"fdAT"))
+            .map(|c| u32::from_be_bytes(c.data[..4].try_into().unwrap()));
+        assert!(sequence.eq(0..5));
+
+        let decoded = Png::decode(encoded).unwrap();
+        for (frame, expected) in decoded.frames.iter().zip(&png.frames) {
+            assert_eq!(frame.data, expected.data);
+        }
+    }
+
+    #[test]
+    fn still_png_round_trip() {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 13, 7);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Two);
+        let mut writer = encoder.write_header().unwrap();
+        let rows = (0..7 * 4).map(|i| (i * 37) as u8).collect::<Vec<_>>();
+        writer.write_image_data(&rows).unwrap();
+        writer.finish().unwrap();
+
+        let png = Png::decode(data.clone()).unwrap();
+        assert!(!png.is_animated());
+        assert_eq!(png.frames[0].data, rows);
+        let decoded = Png::decode(png.encode().unwrap()).unwrap();
+        assert_eq!(decoded.frames[0].data, rows);
+        assert_eq!(skeleton(&png.encode().unwrap()), skeleton(&data));
+    }
+}
@@ -0,0 +1,405 @@
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+// Archive layout, all integers big endian:
+//   count    4 bytes
+//   entries  kind (1 byte), path length (2 bytes), path (utf-8, '/' separated),
+//            mode (4 bytes), mtime (8 bytes), size (4 bytes), crc32 (4 bytes),
+//            data (size bytes)
+
+const KIND_FILE: u8 = 0;
+const KIND_DIR: u8 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub path: String,
+    pub is_dir: bool,
+    pub mode: u32,
+    pub mtime: u64,
+    pub size: u32,
+    pub crc: u32,
+    pub crc_ok: bool,
+}
+
+fn malformed() -> AppError {
+    AppError::new(AppErrorKind::MalformedPayload, "Malformed archive")
+}
+
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+}
+
+fn push_entry(
+    out: &mut Vec<u8>,
+    path: &str,
+    metadata: &fs::Metadata,
+    data: Option<&[u8]>,
+) -> Result<(), AppError> {
+    let data = data.unwrap_or_default();
+    let size = u32::try_from(data.len()).map_err(|_| {
+        AppError::new(
+            AppErrorKind::DataOverflow,
+            format!("{} is too large to be archived.", path),
+        )
+    })?;
+    let path_len = u16::try_from(path.len()).map_err(|_| {
+        AppError::new(
+            AppErrorKind::DataOverflow,
+            format!("{} is too long to be archived.", path),
+        )
+    })?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    out.push(if metadata.is_dir() {
+        KIND_DIR
+    } else {
+        KIND_FILE
+    });
+    out.extend(path_len.to_be_bytes());
+    out.extend_from_slice(path.as_bytes());
+    out.extend(file_mode(metadata).to_be_bytes());
+    out.extend(mtime.to_be_bytes());
+    out.extend(size.to_be_bytes());
+    out.extend(crc32fast::hash(data).to_be_bytes());
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+/// Packs the file or directory. Symbolic links inside directories are
+/// refused, as they may point outside of them or back to a parent.
+fn pack_path(out: &mut Vec<u8>, count: &mut u32, path: &Path, name: &str) -> Result<(), AppError> {
+    let metadata = fs::symlink_metadata(path).context(Stage::Read, path)?;
+
+    if metadata.is_symlink() {
+        return Err(AppError::new(
+            AppErrorKind::UnsafePath,
+            "Symbolic links inside directories cannot be archived.",
+        )
+        .with_path(path));
+    } else if metadata.is_dir() {
+        push_entry(out, name, &metadata, None)?;
+        *count += 1;
+
+        let mut children = fs::read_dir(path)
+            .and_then(|dir| dir.collect::<Result<Vec<_>, _>>())
+            .context(Stage::Read, path)?;
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let child_name = child.file_name();
+            let child_name = child_name.to_str().ok_or_else(|| {
+                AppError::new(
+                    AppErrorKind::UnsafePath,
+                    format!("{} is not a valid UTF-8 filename.", child.path().display()),
+                )
+            })?;
+            pack_path(
+                out,
+                count,
+                &child.path(),
+                &format!("{}/{}", name, child_name),
+            )?;
+        }
+    } else {
+        let data = fs::read(path).context(Stage::Read, path)?;
+        push_entry(out, name, &metadata, Some(&data))?;
+        *count += 1;
+    }
+    Ok(())
+}
+
+/// Packs the given files and directories into an archive. Each path is stored
+/// under its own name, directories recursively. The paths themselves may be
+/// symbolic links, but not two of them may have the same name.
+pub fn pack(paths: &[PathBuf]) -> Result<Vec<u8>, AppError> {
+    let mut out = vec![0; 4];
+    let mut count = 0;
+    let mut names = HashSet::new();
+
+    for path in paths {
+        let target = path.canonicalize().context(Stage::Read, path)?;
+        let name = target
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.to_string())
+            .ok_or_else(|| {
+                AppError::new(
+                    AppErrorKind::UnsafePath,
+                    format!("{} cannot be archived.", path.display()),
+                )
+            })?;
+        if !names.insert(name.clone()) {
+            return Err(AppError::new(
+                AppErrorKind::InvalidOption,
+                format!(
+                    "Several of the paths are named {}, their entries would collide.",
+                    name
+                ),
+            )
+            .with_path(path));
+        }
+        pack_path(&mut out, &mut count, &target, &name)?;
+    }
+
+    out[..4].copy_from_slice(&count.to_be_bytes());
+    Ok(out)
+}
+
+fn read<'a>(data: &'a [u8], idx: &mut usize, len: usize) -> Result<&'a [u8], AppError> {
+    let slice = data.get(*idx..*idx + len).ok_or_else(malformed)?;
+    *idx += len;
+    Ok(slice)
+}
+
+/// Reads the archive, calling `visit` with every entry and its data.
+fn walk(
+    data: &[u8],
+    mut visit: impl FnMut(&Entry, &[u8]) -> Result<(), AppError>,
+) -> Result<Vec<Entry>, AppError> {
+    let mut idx = 0;
+    let count = u32::from_be_bytes(read(data, &mut idx, 4)?.try_into().unwrap());
+    let mut entries = Vec::new();
+
+    for _ in 0..count {
+        let kind = read(data, &mut idx, 1)?[0];
+        let path_len = u16::from_be_bytes(read(data, &mut idx, 2)?.try_into().unwrap());
+        let path = String::from_utf8_lossy(read(data, &mut idx, path_len as usize)?).into_owned();
+        let mode = u32::from_be_bytes(read(data, &mut idx, 4)?.try_into().unwrap());
+        let mtime = u64::from_be_bytes(read(data, &mut idx, 8)?.try_into().unwrap());
+        let size = u32::from_be_bytes(read(data, &mut idx, 4)?.try_into().unwrap());
+        let crc = u32::from_be_bytes(read(data, &mut idx, 4)?.try_into().unwrap());
+        let content = read(data, &mut idx, size as usize)?;
+
+        let entry = Entry {
+            path,
+            is_dir: kind == KIND_DIR,
+            mode,
+            mtime,
+            size,
+            crc,
+            crc_ok: crc32fast::hash(content) == crc,
+        };
+        visit(&entry, content)?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Lists the entries of the archive without unpacking it.
+pub fn list(data: &[u8]) -> Result<Vec<Entry>, AppError> {
+    walk(data, |_, _| Ok(()))
+}
+
+/// Resolves an archive path inside the output directory, rejecting any path
+/// that would end up outside of it.
+fn safe_join(dir: &Path, path: &str) -> Result<PathBuf, AppError> {
+    let relative = Path::new(path);
+    let safe = !path.contains(['\\', '\0'])
+        && relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+
+    if safe && !path.is_empty() {
+        Ok(dir.join(relative))
+    } else {
+        Err(AppError::new(
+            AppErrorKind::UnsafePath,
+            format!("Refusing to unpack unsafe path {:?}", path),
+        ))
+    }
+}
+
+/// Unpacks the archive into the given directory. Existing files are never
+/// overwritten, and entries failing their CRC check are not written.
+pub fn unpack(data: &[u8], dir: &Path, verbose: bool) -> Result<Vec<Entry>, AppError> {
+    fs::create_dir_all(dir)?;
+    let mut dirs = Vec::new();
+
+    let entries = walk(data, |entry, content| {
+        let path = safe_join(dir, &entry.path)?;
+
+        if entry.is_dir {
+            fs::create_dir_all(&path)?;
+            dirs.push((path, entry.mode, entry.mtime));
+            return Ok(());
+        }
+
+        if !entry.crc_ok {
+            return Err(AppError::new(
+                AppErrorKind::CRCMismatch,
+                format!("CRC32 mismatch in {}: invalid data", entry.path),
+            ));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| {
+                AppError::new(
+                    AppErrorKind::Io,
+                    format!("Cannot create {}: {}", path.display(), err.kind()),
+                )
+            })?;
+        file.write_all(content)?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+        drop(file);
+        set_mode(&path, entry.mode)?;
+
+        if verbose {
+            eprintln!("Unpacked {}", path.display());
+        }
+        Ok(())
+    })?;
+
+    // Directory attributes are applied last, as writing their files would
+    // otherwise change their mtime, and their mode might forbid writing.
+    for (path, mode, mtime) in dirs.into_iter().rev() {
+        set_mode(&path, mode)?;
+        if let Ok(dir) = fs::File::open(&path) {
+            let _ = dir.set_modified(UNIX_EPOCH + Duration::from_secs(mtime));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn set_mode(path: &Path, mode: u32) -> Result<(), AppError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an archive of files, each with its path, data and whether its
+    /// CRC is right.
+    fn archive(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = (files.len() as u32).to_be_bytes().to_vec();
+        for (path, data, crc_ok) in files {
+            out.push(KIND_FILE);
+            out.extend((path.len() as u16).to_be_bytes());
+            out.extend_from_slice(path.as_bytes());
+            out.extend(0o644u32.to_be_bytes());
+            out.extend(0u64.to_be_bytes());
+            out.extend((data.len() as u32).to_be_bytes());
+            let crc = crc32fast::hash(data) ^ if *crc_ok { 0 } else { 1 };
+            out.extend(crc.to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kiki_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn safe_join_stays_inside() {
+        let dir = Path::new("out");
+        assert_eq!(safe_join(dir, "a/b.txt").unwrap(), dir.join("a/b.txt"));
+        for path in ["", "../x", "a/../../x", "/etc/x", "./x", "a\\..\\x", "a\0b"] {
+            let err = safe_join(dir, path).unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::UnsafePath, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn unpack_refuses_escaping_paths() {
+        let root = temp_dir("escape");
+        let dir = root.join("out");
+        for path in ["../x", "/etc/x"] {
+            let data = archive(&[("ok.txt", b"fine", true), (path, b"evil", true)]);
+            let err = unpack(&data, &dir, false).unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::UnsafePath);
+            fs::remove_file(dir.join("ok.txt")).unwrap();
+        }
+        assert!(!root.join("x").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unpack_never_overwrites() {
+        let dir = temp_dir("duplicate");
+        let data = archive(&[("a.txt", b"first", true), ("a.txt", b"second", true)]);
+        let err = unpack(&data, &dir, false).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::Io);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"first");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_skips_corrupt_entries() {
+        let dir = temp_dir("crc");
+        let data = archive(&[("bad.txt", b"corrupt", false)]);
+        assert!(!list(&data).unwrap()[0].crc_ok);
+        let err = unpack(&data, &dir, false).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::CRCMismatch);
+        assert!(!dir.join("bad.txt").exists());
+
+        let mut truncated = archive(&[("a.txt", b"data", true)]);
+        truncated.pop();
+        let err = list(&truncated).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::MalformedPayload);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let src = temp_dir("pack").join("docs");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"alpha").unwrap();
+        fs::write(src.join("sub/b.bin"), [0, 1, 2, 255]).unwrap();
+
+        let data = pack(std::slice::from_ref(&src)).unwrap();
+        let paths: Vec<_> = list(&data).unwrap().into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, ["docs", "docs/a.txt", "docs/sub", "docs/sub/b.bin"]);
+
+        let dir = temp_dir("unpack");
+        unpack(&data, &dir, false).unwrap();
+        assert_eq!(fs::read(dir.join("docs/a.txt")).unwrap(), b"alpha");
+        assert_eq!(
+            fs::read(dir.join("docs/sub/b.bin")).unwrap(),
+            [0, 1, 2, 255]
+        );
+
+        let err = pack(&[src.clone(), src.clone()]).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::InvalidOption);
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(src.parent().unwrap()).unwrap();
+    }
+}
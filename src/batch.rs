@@ -0,0 +1,434 @@
+use crate::embed::{embed, load_secret};
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::extract::{extract, ExtractOutput};
+use crate::methods::data::LengthPolicy;
+use crate::report::Report;
+use image::ImageFormat;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+pub const EMBED_TEMPLATE: &str = "{name}";
+pub const EXTRACT_TEMPLATE: &str = "{stem}.out";
+
+/// A line of the manifest: the secret, and optionally the key, of an image.
+#[derive(Debug, Deserialize)]
+pub struct ManifestEntry {
+    pub image: String,
+    pub secret: String,
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+/// Options shared by all the files of a batch.
+pub struct BatchOptions<'a> {
+    pub method: Option<&'a String>,
+    pub key: Option<&'a String>,
+    pub verbose: bool,
+    pub options: Vec<&'a String>,
+    pub format: Option<ImageFormat>,
+    pub template: Option<&'a String>,
+    pub jobs: usize,
+}
+
+/// Returns true if the image argument selects several files: a directory or a
+/// glob pattern.
+pub fn is_batch(image: &str) -> bool {
+    image.contains(['*', '?', '[']) || Path::new(image).is_dir()
+}
+
+/// Lists the files in the directory, or matching the glob pattern.
+pub fn find_images(pattern: &str) -> Result<Vec<PathBuf>, AppError> {
+    let mut images = if Path::new(pattern).is_dir() {
+        fs::read_dir(pattern)
+            .context(Stage::Read, Path::new(pattern))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context(Stage::Read, Path::new(pattern))?
+    } else {
+        glob::glob(pattern)
+            .map_err(|err| {
+                AppError::new(
+                    AppErrorKind::InvalidOption,
+                    format!("{} is not a valid pattern: {}", pattern, err),
+                )
+            })?
+            .filter_map(Result::ok)
+            .collect()
+    };
+
+    images.retain(|p| p.is_file());
+    images.sort();
+    Ok(images)
+}
+
+/// Builds the output filename from the template. `{name}`, `{stem}`, `{ext}`
+/// and `{index}` are replaced by the filename, the filename without extension,
+/// the extension and the position of the image in the batch.
+pub fn output_name(template: &str, image: &Path, index: usize) -> Result<PathBuf, AppError> {
+    let part = |s: Option<&std::ffi::OsStr>| s.and_then(|s| s.to_str()).unwrap_or("").to_string();
+
+    let name = template
+        .replace("{name}", &part(image.file_name()))
+        .replace("{stem}", &part(image.file_stem()))
+        .replace("{ext}", &part(image.extension()))
+        .replace("{index}", &index.to_string());
+
+    let path = PathBuf::from(&name);
+    if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(AppError::new(
+            AppErrorKind::UnsafePath,
+            format!("The template gives the unsafe output path {:?}", name),
+        ));
+    }
+    Ok(path)
+}
+
+/// Returns true if both paths exist and resolve to the same file.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Reads a manifest of per-image secrets, either as JSON lines with `image`,
+/// `secret` and `key` fields, or as CSV lines `image,secret[,key]`. Entries are
+/// looked up by path and by filename.
+pub fn read_manifest(path: &Path) -> Result<HashMap<String, ManifestEntry>, AppError> {
+    let content = fs::read_to_string(path).context(Stage::Read, path)?;
+    let is_json = path
+        .extension()
+        .is_some_and(|e| e == "jsonl" || e == "json" || e == "ndjson");
+
+    let mut manifest = HashMap::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |reason: String| {
+            AppError::new(
+                AppErrorKind::InvalidOption,
+                format!("Invalid manifest line {}: {}", lineno + 1, reason),
+            )
+            .with_path(path)
+        };
+
+        let entry = if is_json {
+            serde_json::from_str::<ManifestEntry>(line).map_err(|e| invalid(e.to_string()))?
+        } else {
+            let fields = line.split(',').map(|f| f.trim()).collect::<Vec<_>>();
+            match fields.as_slice() {
+                ["image", "secret", ..] if lineno == 0 => continue,
+                [image, secret] => ManifestEntry {
+                    image: image.to_string(),
+                    secret: secret.to_string(),
+                    key: None,
+                },
+                [image, secret, key] => ManifestEntry {
+                    image: image.to_string(),
+                    secret: secret.to_string(),
+                    key: Some(key.to_string()).filter(|k| !k.is_empty()),
+                },
+                _ => return Err(invalid("expected image,secret[,key]".to_string())),
+            }
+        };
+
+        manifest.insert(entry.image.clone(), entry);
+    }
+    Ok(manifest)
+}
+
+fn lookup<'a>(
+    manifest: &'a HashMap<String, ManifestEntry>,
+    image: &Path,
+) -> Option<&'a ManifestEntry> {
+    manifest.get(&image.display().to_string()).or_else(|| {
+        image
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| manifest.get(n))
+    })
+}
+
+pub(crate) fn run_pool<T: Send>(jobs: usize, f: impl FnOnce() -> T + Send) -> Result<T, AppError> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|err| AppError::new(AppErrorKind::InvalidOption, err.to_string()))?;
+    Ok(pool.install(f))
+}
+
+/// Prints the outcome of every file and fails if any of them did.
+pub(crate) fn summarize(results: Vec<Report>, report: &mut Report) -> Result<(), AppError> {
+    let failed = results.iter().filter(|r| !r.success).count();
+
+    if !report.json {
+        for result in &results {
+            let image = result.image.as_deref().unwrap_or_default();
+            match &result.error {
+                None => println!(
+                    "ok   {} -> {}",
+                    image,
+                    result.output.as_deref().unwrap_or_default()
+                ),
+                Some(error) => println!("FAIL {}: {}", image, error.message),
+            }
+        }
+        println!("{} succeeded, {} failed", results.len() - failed, failed);
+    }
+
+    let total = results.len();
+    report.results = Some(results);
+
+    if failed > 0 {
+        Err(AppError::new(
+            AppErrorKind::BatchFailed,
+            format!("{} of {} files failed", failed, total),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn finish_job(mut job: Report, result: Result<(), AppError>) -> Report {
+    match result {
+        Ok(()) => job.success = true,
+        Err(err) => job.error(&err),
+    }
+    job
+}
+
+/// Embeds into every image selected by the pattern, writing the results into
+/// the output directory. The secret comes from the manifest if given, or is
+/// shared by all the images otherwise.
+#[allow(clippy::too_many_arguments)]
+pub fn embed_batch(
+    pattern: &str,
+    output_dir: &Path,
+    fd_secrets: &[&str],
+    manifest: Option<&Path>,
+    metadata: bool,
+    verify: bool,
+    strip_metadata: bool,
+    opts: BatchOptions,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let images = find_images(pattern)?;
+    let template = opts.template.map(|t| t.as_str()).unwrap_or(EMBED_TEMPLATE);
+    // An output written over its cover would leave nothing to embed again
+    // from, so such a batch is refused before any file is written.
+    for (index, image) in images.iter().enumerate() {
+        let Ok(name) = output_name(template, image, index) else {
+            continue;
+        };
+        if is_same_file(image, &output_dir.join(name)) {
+            return Err(AppError::new(
+                AppErrorKind::InvalidOption,
+                "The output would overwrite the image: choose another output directory or --template.",
+            )
+            .with_path(image));
+        }
+    }
+    fs::create_dir_all(output_dir).context(Stage::Save, output_dir)?;
+
+    let manifest = manifest.map(read_manifest).transpose()?;
+    let shared = match &manifest {
+        Some(_) => None,
+        None => Some(load_secret(fd_secrets, metadata, &mut Report::default())?),
+    };
+
+    let results = run_pool(opts.jobs, || {
+        images
+            .par_iter()
+            .enumerate()
+            .map(|(index, image)| {
+                let mut job = Report::new("embed");
+                job.image = Some(image.display().to_string());
+                job.method = opts.method.cloned();
+                job.options = opts.options.iter().map(|o| o.to_string()).collect();
+
+                let result = (|| {
+                    let output = output_dir.join(output_name(template, image, index)?);
+                    job.output = Some(output.display().to_string());
+
+                    let (secret, key) = match (&shared, &manifest) {
+                        (Some(secret), _) => (secret.clone(), opts.key.cloned()),
+                        (None, Some(manifest)) => {
+                            let entry = lookup(manifest, image).ok_or_else(|| {
+                                AppError::new(
+                                    AppErrorKind::InvalidOption,
+                                    "The image is not listed in the manifest.",
+                                )
+                                .with_path(image)
+                            })?;
+                            let secret = load_secret(&[entry.secret.as_str()], metadata, &mut job)?;
+                            (secret, entry.key.clone().or_else(|| opts.key.cloned()))
+                        }
+                        (None, None) => unreachable!(),
+                    };
+
+                    embed(
+                        image,
+                        &output,
+                        &secret,
+                        opts.method,
+                        key.as_ref(),
+                        opts.verbose,
+                        opts.options.clone(),
+                        opts.format,
+                        verify,
+                        strip_metadata,
+                        &mut job,
+                    )
+                })();
+                finish_job(job, result)
+            })
+            .collect::<Vec<_>>()
+    })?;
+
+    summarize(results, report)
+}
+
+/// Extracts from every image selected by the pattern, writing the data into
+/// the output directory.
+pub fn extract_batch(
+    pattern: &str,
+    output_dir: &Path,
+    opts: BatchOptions,
+    mut policy: LengthPolicy,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let images = find_images(pattern)?;
+    fs::create_dir_all(output_dir).context(Stage::Save, output_dir)?;
+    let template = opts
+        .template
+        .map(|t| t.as_str())
+        .unwrap_or(EXTRACT_TEMPLATE);
+
+    // Files are processed in parallel, so there is no way to ask the user.
+    policy.stdin_free = false;
+
+    let results = run_pool(opts.jobs, || {
+        images
+            .par_iter()
+            .enumerate()
+            .map(|(index, image)| {
+                let mut job = Report::new("extract");
+                job.image = Some(image.display().to_string());
+                job.method = opts.method.cloned();
+                job.options = opts.options.iter().map(|o| o.to_string()).collect();
+
+                let result = (|| {
+                    let output = output_dir.join(output_name(template, image, index)?);
+                    job.output = Some(output.display().to_string());
+
+                    extract(
+                        image,
+                        ExtractOutput::Path(output),
+                        opts.method,
+                        opts.key,
+                        opts.verbose,
+                        opts.options.clone(),
+                        false,
+                        opts.format,
+                        policy.clone(),
+                        &mut job,
+                    )
+                })();
+                finish_job(job, result)
+            })
+            .collect::<Vec<_>>()
+    })?;
+
+    summarize(results, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn output_names_follow_the_template() {
+        let image = Path::new("covers/photo.png");
+        let name = output_name("{stem}_{index}.{ext}", image, 3).unwrap();
+        assert_eq!(name, PathBuf::from("photo_3.png"));
+        assert_eq!(
+            output_name(EMBED_TEMPLATE, image, 0).unwrap(),
+            PathBuf::from("photo.png")
+        );
+        for template in ["../{name}", "/tmp/{name}", ""] {
+            let err = output_name(template, image, 0).unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::UnsafePath);
+        }
+    }
+
+    #[test]
+    fn embed_batch_refuses_to_overwrite_the_covers() {
+        let dir = std::env::temp_dir().join(format!("kiki_{}_inplace", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.png");
+        let secret = dir.with_extension("txt");
+        RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 90]))
+            .save(&cover)
+            .unwrap();
+        fs::write(&secret, b"secret").unwrap();
+        let before = fs::read(&cover).unwrap();
+
+        let opts = || BatchOptions {
+            method: None,
+            key: None,
+            verbose: false,
+            options: vec![],
+            format: None,
+            template: None,
+            jobs: 1,
+        };
+        let pattern = dir.to_str().unwrap();
+        let secrets = [secret.to_str().unwrap()];
+        let same = dir.join(".");
+        let err = embed_batch(
+            pattern,
+            &same,
+            &secrets,
+            None,
+            false,
+            false,
+            false,
+            opts(),
+            &mut Report::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::InvalidOption);
+        assert_eq!(fs::read(&cover).unwrap(), before);
+
+        // Another name in the same directory is fine.
+        let template = "{stem}.out.png".to_string();
+        let opts = BatchOptions {
+            template: Some(&template),
+            ..opts()
+        };
+        embed_batch(
+            pattern,
+            &dir,
+            &secrets,
+            None,
+            false,
+            false,
+            false,
+            opts,
+            &mut Report::default(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&cover).unwrap(), before);
+        assert!(dir.join("cover.out.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(secret).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use crate::errors::{AppError, AppErrorKind};
+
+pub const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// A chunk of a PNG file: its four letter type and its data.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub kind: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(kind: &[u8; 4], data: Vec<u8>) -> Self {
+        Chunk { kind: *kind, data }
+    }
+
+    pub fn is(&self, kind: &[u8; 4]) -> bool {
+        &self.kind == kind
+    }
+}
+
+fn malformed() -> AppError {
+    AppError::new(AppErrorKind::Image, "Malformed PNG file")
+}
+
+/// Returns true if the data starts with the PNG signature.
+pub fn is_png(data: &[u8]) -> bool {
+    data.starts_with(SIGNATURE)
+}
+
+/// Splits a PNG file into its chunks, checking their CRC.
+pub fn split(data: &[u8]) -> Result<Vec<Chunk>, AppError> {
+    if !is_png(data) {
+        return Err(malformed());
+    }
+
+    let mut chunks = Vec::new();
+    let mut idx = SIGNATURE.len();
+    while idx < data.len() {
+        let header = data.get(idx..idx + 8).ok_or_else(malformed)?;
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let body = data.get(idx + 4..idx + 8 + len).ok_or_else(malformed)?;
+        let crc = data
+            .get(idx + 8 + len..idx + 12 + len)
+            .ok_or_else(malformed)?;
+
+        if crc32fast::hash(body).to_be_bytes() != crc {
+            return Err(malformed());
+        }
+        chunks.push(Chunk::new(
+            body[..4].try_into().unwrap(),
+            body[4..].to_vec(),
+        ));
+        idx += 12 + len;
+    }
+    Ok(chunks)
+}
+
+/// Builds a PNG file from its chunks.
+pub fn join(chunks: &[Chunk]) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    for chunk in chunks {
+        let mut body = chunk.kind.to_vec();
+        body.extend_from_slice(&chunk.data);
+        out.extend((chunk.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&body);
+        out.extend(crc32fast::hash(&body).to_be_bytes());
+    }
+    out
+}
@@ -1,12 +1,80 @@
-use std::path::PathBuf;
+use image::ImageFormat;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 // use crate::methods::{lsb,kiki,jpeg};
-use crate::errors::{AppError, AppErrorKind};
-use crate::methods::lsb;
+use crate::archive;
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio;
+use crate::metadata::{self, Metadata};
+use crate::methods::data::LengthPolicy;
+use crate::methods::{bpcs, chunk, lsb, palette, pvd, text, watermark, wav};
+use crate::payload::{self, FileMeta, FLAG_ARCHIVE};
+use crate::report::Report;
 
-fn supported_methods(extension: &str) -> Result<Vec<&'static str>, AppError> {
+fn get_secret(fd_secret: &str) -> Result<Vec<u8>, AppError> {
+    let mut secret = Vec::<u8>::new();
+
+    match fd_secret {
+        "-" => io::stdin().read_to_end(&mut secret)?,
+        _ => File::open(PathBuf::from(fd_secret))?.read_to_end(&mut secret)?,
+    };
+    Ok(secret)
+}
+
+/// Reads the secret and builds the payload to embed. Several paths, or a
+/// directory, are packed into an archive. With `metadata`, the name, type,
+/// mtime and mode of a single file are stored along with it.
+pub fn load_secret(
+    fd_secrets: &[&str],
+    metadata: bool,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let fd_secret = match fd_secrets {
+        [] => "-",
+        [fd_secret] => fd_secret,
+        _ => "",
+    };
+
+    let is_archive = fd_secrets.len() > 1 || (fd_secret != "-" && Path::new(fd_secret).is_dir());
+
+    let secret = if is_archive {
+        let paths = fd_secrets.iter().map(PathBuf::from).collect::<Vec<_>>();
+        archive::pack(&paths).map_err(|err| err.with_stage(Stage::Read))?
+    } else {
+        get_secret(fd_secret).context(Stage::Read, Path::new(fd_secret))?
+    };
+    report.payload_bytes = Some(secret.len());
+
+    let meta = match (metadata && !is_archive, fd_secret) {
+        (false, _) => None,
+        (true, "-") => Some(FileMeta::default()),
+        (true, path) => {
+            Some(FileMeta::from_path(Path::new(path)).context(Stage::Read, Path::new(path))?)
+        }
+    };
+    let flags = if is_archive { FLAG_ARCHIVE } else { 0 };
+    let secret = payload::encode(&secret, meta.as_ref(), flags);
+    report.metadata = meta;
+
+    Ok(secret)
+}
+
+/// Returns the methods that can embed into a file with the given extension,
+/// the default one first.
+pub fn supported_methods(extension: &str) -> Result<Vec<&'static str>, AppError> {
     match extension {
-        "bmp" | "png" => Ok(vec!["LSB"]),
+        "bmp" | "webp" | "tif" | "tiff" | "qoi" => Ok(vec!["LSB", "PVD", "BPCS", "WM"]),
+        "ppm" | "pgm" | "pam" | "pnm" => Ok(vec!["LSB", "PVD", "BPCS", "WM"]),
+        "png" => Ok(vec!["LSB", "PAL", "PVD", "BPCS", "CHUNK", "WM"]),
+        "jpg" | "jpeg" => Ok(vec!["WM"]),
+        "gif" => Ok(vec!["PAL"]),
+        "wav" => Ok(vec!["ALSB"]),
+        "txt" => Ok(vec!["ZW", "WS"]),
+        // Two trailing spaces are a hard line break in Markdown.
+        "md" => Ok(vec!["ZW"]),
         _ => Err(AppError::new(
             AppErrorKind::UnsupportedExtension,
             format!("{} is not yet supported.", extension),
@@ -14,24 +82,30 @@ fn supported_methods(extension: &str) -> Result<Vec<&'static str>, AppError> {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn embed(
-    image_path: &PathBuf,
-    output_path: &PathBuf,
+    image_path: &Path,
+    output_path: &Path,
     secret_data: &[u8],
     method: Option<&String>,
     key: Option<&String>,
     verbose: bool,
     options: Vec<&String>,
+    format: Option<ImageFormat>,
+    verify: bool,
+    strip_metadata: bool,
+    report: &mut Report,
 ) -> Result<(), AppError> {
-    let method = match output_path.extension().and_then(|e| e.to_str()) {
+    let method = match imageio::extension(output_path, format) {
         Some(extension) => {
+            let extension = extension.as_str();
             if verbose {
-                println!("Output file has extension {}", extension)
+                eprintln!("Output file has extension {}", extension)
             }
 
             let supported = supported_methods(extension)?;
             if verbose {
-                println!("{} supports {:?}", extension, supported)
+                eprintln!("{} supports {:?}", extension, supported)
             }
 
             match method {
@@ -48,22 +122,201 @@ pub fn embed(
                 None => &supported[0].to_string(),
             }
         }
+        // Text has no format to give, its method is enough.
+        None if method.is_some_and(|m| text::METHODS.contains(&m.as_str())) => method.unwrap(),
         None => {
             return Err(AppError::new(
                 AppErrorKind::MissingExtension,
-                "Specified file is missing the extension.",
+                "Specified file is missing the extension. Specify the format with --format.",
             ))
         }
     };
     if verbose {
-        println!("Determined method: {}", method)
+        eprintln!("Determined method: {}", method)
+    };
+    report.method = Some(method.to_string());
+
+    // Read before embedding, as the output may replace the cover.
+    let piped = imageio::is_std(image_path) || imageio::is_std(output_path);
+    let cover_metadata = if strip_metadata || piped {
+        None
+    } else {
+        Some(Metadata::read(image_path)?)
     };
 
     match method.as_str() {
-        "LSB" => lsb::embed(image_path, output_path, secret_data, key, verbose, options),
+        "LSB" => lsb::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            format,
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "PAL" => palette::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "PVD" => pvd::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            format,
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "BPCS" => bpcs::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            format,
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "ZW" | "WS" => text::embed(
+            image_path,
+            output_path,
+            secret_data,
+            method,
+            key,
+            verbose,
+            options.clone(),
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "CHUNK" => chunk::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            strip_metadata,
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "ALSB" => wav::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            report,
+        )
+        .context(Stage::Embed, image_path),
+        "WM" => watermark::embed(
+            image_path,
+            output_path,
+            secret_data,
+            key,
+            verbose,
+            options.clone(),
+            format,
+            report,
+        )
+        .context(Stage::Embed, image_path),
         method => Err(AppError::new(
             AppErrorKind::UnsupportedMethod,
             format!("{} is not a supported method.", method),
         )),
+    }?;
+
+    if let Some(cover_metadata) = cover_metadata {
+        let count = cover_metadata.restore(output_path)?;
+        if verbose {
+            eprintln!("Metadata: {} chunks or segments carried over", count);
+        }
+    } else if piped {
+        if verbose && !strip_metadata {
+            eprintln!("Image piped through stdin or stdout, metadata not carried over");
+        }
+    } else if method != "CHUNK" {
+        // CHUNK strips the cover itself, as its payload is an ancillary chunk.
+        let count = metadata::strip(output_path)?;
+        if verbose {
+            eprintln!("Metadata: {} chunks stripped", count);
+        }
+    }
+
+    if !verify {
+        return Ok(());
+    }
+    if imageio::is_std(output_path) {
+        if verbose {
+            eprintln!("Output written to stdout, skipping verification");
+        }
+        return Ok(());
     }
+
+    let start = Instant::now();
+    verify_output(output_path, secret_data, method, key, options, format)?;
+    report.verified = Some(true);
+    report.time("verify", start);
+    if verbose {
+        eprintln!("Verified: the saved image holds the data");
+    }
+    Ok(())
+}
+
+/// Reads the saved image back with the same method, key and options, and
+/// checks that it holds the secret. This catches outputs whose encoding does
+/// not keep the embedded bits.
+fn verify_output(
+    output_path: &Path,
+    secret_data: &[u8],
+    method: &str,
+    key: Option<&String>,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+) -> Result<(), AppError> {
+    // The length is known to be right, there is nothing to ask.
+    let policy = LengthPolicy {
+        limit: usize::MAX,
+        answer: Some(true),
+        stdin_free: false,
+    };
+    let report = &mut Report::default();
+    let extracted = match method {
+        "LSB" => lsb::extract(output_path, key, false, options, format, &policy, report),
+        "PAL" => palette::extract(output_path, key, false, options, &policy, report),
+        "PVD" => pvd::extract(output_path, key, false, options, format, &policy, report),
+        "BPCS" => bpcs::extract(output_path, key, false, options, format, &policy, report),
+        "CHUNK" => chunk::extract(output_path, key, false, options, &policy, report),
+        "ALSB" => wav::extract(output_path, key, false, options, &policy, report),
+        "ZW" | "WS" => text::extract(output_path, method, key, false, options, &policy, report),
+        "WM" => watermark::extract(output_path, key, false, options, format, report),
+        _ => unreachable!(),
+    };
+
+    let cause = match extracted {
+        Ok(data) if data == secret_data => return Ok(()),
+        Ok(_) => "the data read back differs from the secret".to_string(),
+        Err(err) => err.to_string(),
+    };
+    Err(AppError::new(
+        AppErrorKind::VerifyFailed,
+        format!(
+            "The saved image does not hold the data ({}): its format may be lossy \
+            or not keep what {} changed.",
+            cause, method
+        ),
+    )
+    .with_stage(Stage::Verify)
+    .with_path(output_path))
 }
@@ -1,13 +1,17 @@
-use image;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::{fmt, io};
 
 #[derive(Debug)]
 pub struct AppError {
-    _kind: AppErrorKind,
+    pub kind: AppErrorKind,
+    pub stage: Option<Stage>,
+    pub path: Option<PathBuf>,
+    pub source: Option<Box<dyn Error + Send + Sync>>,
     message: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AppErrorKind {
     Io,
     Image,
@@ -15,35 +19,206 @@ pub enum AppErrorKind {
     UnsupportedExtension,
     DataOverflow,
     UnsupportedMethod,
+    InvalidOption,
     NotImplemented,
     CRCMismatch,
     UserStopped,
+    MalformedPayload,
+    UnsafePath,
+    BinaryOutput,
+    NoPayload,
+    BatchFailed,
+    VerifyFailed,
+}
+
+/// The step of the operation during which the error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Read,
+    Decode,
+    Embed,
+    Extract,
+    Encode,
+    Save,
+    Verify,
+}
+
+impl AppErrorKind {
+    /// The process exit code for this kind of error, as listed in the help.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppErrorKind::BatchFailed => 1,
+            AppErrorKind::MissingExtension
+            | AppErrorKind::UnsupportedExtension
+            | AppErrorKind::UnsupportedMethod
+            | AppErrorKind::InvalidOption
+            | AppErrorKind::NotImplemented
+            | AppErrorKind::BinaryOutput => 2,
+            AppErrorKind::Io => 3,
+            AppErrorKind::Image => 4,
+            AppErrorKind::DataOverflow => 5,
+            AppErrorKind::NoPayload => 6,
+            AppErrorKind::CRCMismatch => 7,
+            AppErrorKind::MalformedPayload => 8,
+            AppErrorKind::UserStopped => 9,
+            AppErrorKind::UnsafePath => 10,
+            AppErrorKind::VerifyFailed => 11,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            Stage::Read => "reading",
+            Stage::Decode => "decoding",
+            Stage::Embed => "embedding into",
+            Stage::Extract => "extracting from",
+            Stage::Encode => "encoding",
+            Stage::Save => "saving",
+            Stage::Verify => "verifying",
+        };
+        write!(f, "{}", stage)
+    }
 }
 
 impl AppError {
     pub fn new(kind: AppErrorKind, message: impl Into<String>) -> Self {
         AppError {
-            _kind: kind,
+            kind,
+            stage: None,
+            path: None,
+            source: None,
             message: message.into(),
         }
     }
+
+    /// Sets the stage, unless a more specific one was already set.
+    pub fn with_stage(mut self, stage: Stage) -> Self {
+        self.stage.get_or_insert(stage);
+        self
+    }
+
+    /// Sets the path, unless a more specific one was already set.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        if self.path.is_none() {
+            self.path = Some(path.into());
+        }
+        self
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.kind.exit_code()
+    }
+}
+
+/// Adds the stage and path to the error of a result.
+pub trait Context<T> {
+    fn context(self, stage: Stage, path: &Path) -> Result<T, AppError>;
+}
+
+impl<T, E: Into<AppError>> Context<T> for Result<T, E> {
+    fn context(self, stage: Stage, path: &Path) -> Result<T, AppError> {
+        self.map_err(|err| err.into().with_stage(stage).with_path(path))
+    }
 }
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.stage, &self.path) {
+            (Some(stage), Some(path)) => write!(f, "Error while {} {}: ", stage, path.display())?,
+            (Some(stage), None) => write!(f, "Error while {}: ", stage)?,
+            (None, Some(path)) => write!(f, "{}: ", path.display())?,
+            (None, None) => {}
+        }
         write!(f, "{}", self.message)
     }
 }
 
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|s| s.as_ref() as &(dyn Error + 'static))
+    }
+}
+
 impl From<io::Error> for AppError {
     fn from(error: io::Error) -> Self {
-        let message = error.kind().to_string();
-        AppError::new(AppErrorKind::Io, message)
+        let mut err = AppError::new(AppErrorKind::Io, error.to_string());
+        err.source = Some(Box::new(error));
+        err
     }
 }
 
 impl From<image::ImageError> for AppError {
     fn from(error: image::ImageError) -> Self {
-        AppError::new(AppErrorKind::Image, format!("{}", error))
+        let kind = match error {
+            image::ImageError::IoError(_) => AppErrorKind::Io,
+            image::ImageError::Unsupported(_) => AppErrorKind::UnsupportedExtension,
+            _ => AppErrorKind::Image,
+        };
+        let mut err = AppError::new(kind, error.to_string());
+        err.source = Some(Box::new(error));
+        err
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_match_the_table() {
+        let codes = [
+            (AppErrorKind::BatchFailed, 1),
+            (AppErrorKind::MissingExtension, 2),
+            (AppErrorKind::UnsupportedExtension, 2),
+            (AppErrorKind::UnsupportedMethod, 2),
+            (AppErrorKind::InvalidOption, 2),
+            (AppErrorKind::NotImplemented, 2),
+            (AppErrorKind::BinaryOutput, 2),
+            (AppErrorKind::Io, 3),
+            (AppErrorKind::Image, 4),
+            (AppErrorKind::DataOverflow, 5),
+            (AppErrorKind::NoPayload, 6),
+            (AppErrorKind::CRCMismatch, 7),
+            (AppErrorKind::MalformedPayload, 8),
+            (AppErrorKind::UserStopped, 9),
+            (AppErrorKind::UnsafePath, 10),
+            (AppErrorKind::VerifyFailed, 11),
+        ];
+        for (kind, code) in codes {
+            assert_eq!(kind.exit_code(), code, "{:?}", kind);
+            assert_eq!(AppError::new(kind, "").exit_code(), code);
+        }
+    }
+
+    #[test]
+    fn context_keeps_the_innermost_stage_and_path() {
+        let inner: Result<(), AppError> =
+            Err(AppError::new(AppErrorKind::NoPayload, "nothing").with_stage(Stage::Extract));
+        let err = inner
+            .context(Stage::Read, Path::new("a.png"))
+            .context(Stage::Save, Path::new("b.png"))
+            .unwrap_err();
+        assert_eq!(err.stage, Some(Stage::Extract));
+        assert_eq!(err.path.as_deref(), Some(Path::new("a.png")));
+        assert_eq!(
+            err.to_string(),
+            "Error while extracting from a.png: nothing"
+        );
+
+        let io = io::Error::new(io::ErrorKind::NotFound, "gone");
+        let err = Err::<(), _>(io)
+            .context(Stage::Read, Path::new("c.txt"))
+            .unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::Io);
+        assert!(err.source().is_some());
+        assert_eq!(err.to_string(), "Error while reading c.txt: gone");
+        assert_eq!(
+            AppError::new(AppErrorKind::Io, "plain").to_string(),
+            "plain"
+        );
     }
 }
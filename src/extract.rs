@@ -1,17 +1,131 @@
-use crate::errors::{AppError, AppErrorKind};
-use crate::methods::lsb;
-use std::{fs::OpenOptions, io::Write, path::PathBuf};
+use crate::archive;
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio;
+use crate::methods::data::LengthPolicy;
+use crate::methods::{bpcs, chunk, lsb, palette, pvd, text, watermark, wav};
+use crate::payload::{self, Payload};
+use crate::report::Report;
+use image::ImageFormat;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
+pub enum ExtractOutput {
+    /// Write to the given path.
+    Path(PathBuf),
+    /// Write the raw data to stdout.
+    Stdout,
+    /// Restore under the stored filename, or print text if there is none.
+    Original,
+}
+
+fn write_file(path: &Path, data: &[u8], create_new: bool) -> Result<(), AppError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(create_new)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+fn list(payload: &Payload, report: &mut Report) -> Result<(), AppError> {
+    if payload.header.as_ref().is_some_and(|h| h.is_archive()) {
+        let entries = archive::list(&payload.data)?;
+        if report.json {
+            report.entries = Some(entries);
+            return Ok(());
+        }
+        for entry in entries {
+            println!(
+                "{} {:04o} {:>10} {:>10} {:08x} {} {}",
+                if entry.is_dir { 'd' } else { '-' },
+                entry.mode,
+                entry.size,
+                entry.mtime,
+                entry.crc,
+                if entry.crc_ok { "ok " } else { "BAD" },
+                entry.path
+            );
+        }
+        return Ok(());
+    }
+
+    // A single file, named by its metadata if it was stored.
+    let meta = payload.meta.clone().unwrap_or_default();
+    if report.json {
+        report.entries = Some(vec![archive::Entry {
+            path: meta.name.unwrap_or_default(),
+            is_dir: false,
+            mode: meta.mode.unwrap_or_default(),
+            mtime: meta.mtime.unwrap_or_default(),
+            size: payload.data.len() as u32,
+            crc: crc32fast::hash(&payload.data),
+            crc_ok: true,
+        }]);
+    } else {
+        println!(
+            "- {:>4} {:>10} {:>10} {:08x} ok  {}",
+            meta.mode.map(|m| format!("{:04o}", m)).unwrap_or_default(),
+            payload.data.len(),
+            meta.mtime.map(|m| m.to_string()).unwrap_or_default(),
+            crc32fast::hash(&payload.data),
+            meta.name.as_deref().unwrap_or("<unnamed>")
+        );
+    }
+    Ok(())
+}
+
+fn unpack(payload: &Payload, output: ExtractOutput, verbose: bool) -> Result<(), AppError> {
+    let dir = match output {
+        ExtractOutput::Path(dir) => dir,
+        ExtractOutput::Original => PathBuf::from("."),
+        ExtractOutput::Stdout => {
+            return Err(AppError::new(
+                AppErrorKind::BinaryOutput,
+                "The data is an archive. Specify an output directory.",
+            ))
+        }
+    };
+
+    let entries = archive::unpack(&payload.data, &dir, verbose).context(Stage::Save, &dir)?;
+    if verbose {
+        eprintln!("Unpacked {} entries into {}", entries.len(), dir.display());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn extract(
-    image_path: &PathBuf,
-    output_path: Option<&PathBuf>,
+    image_path: &Path,
+    output: ExtractOutput,
     method: Option<&String>,
     key: Option<&String>,
     verbose: bool,
     options: Vec<&String>,
+    list_only: bool,
+    format: Option<ImageFormat>,
+    mut policy: LengthPolicy,
+    report: &mut Report,
 ) -> Result<(), AppError> {
+    policy.stdin_free &= !imageio::is_std(image_path);
+
+    // A payload chunk is found without reading the pixels.
+    let detected = method.is_none()
+        && !imageio::is_std(image_path)
+        && chunk::holds_payload(&imageio::read_input(image_path)?, &options);
     let method = match method {
-        Some(method) => method,
+        Some(method) => method.as_str(),
+        None if detected => {
+            if verbose {
+                eprintln!("Found a payload chunk, using CHUNK");
+            }
+            report.method = Some("CHUNK".to_string());
+            "CHUNK"
+        }
         None => {
             return Err(AppError::new(
                 AppErrorKind::NotImplemented,
@@ -20,24 +134,133 @@ pub fn extract(
         }
     };
 
-    let data = match method.as_str() {
-        "LSB" => lsb::extract(image_path, key, verbose, options),
+    let pixel_options = options.clone();
+    let found = match method {
+        "LSB" => lsb::extract(image_path, key, verbose, options, format, &policy, report)
+            .context(Stage::Extract, image_path),
+        "PAL" => palette::extract(image_path, key, verbose, options, &policy, report)
+            .context(Stage::Extract, image_path),
+        "PVD" => pvd::extract(image_path, key, verbose, options, format, &policy, report)
+            .context(Stage::Extract, image_path),
+        "BPCS" => bpcs::extract(image_path, key, verbose, options, format, &policy, report)
+            .context(Stage::Extract, image_path),
+        "ALSB" => wav::extract(image_path, key, verbose, options, &policy, report)
+            .context(Stage::Extract, image_path),
+        "ZW" | "WS" => text::extract(image_path, method, key, verbose, options, &policy, report)
+            .context(Stage::Extract, image_path),
+        "WM" => watermark::extract(image_path, key, verbose, options, format, report)
+            .context(Stage::Extract, image_path),
+        "CHUNK" => chunk::extract(image_path, key, verbose, options, &policy, report)
+            .context(Stage::Extract, image_path),
         method => Err(AppError::new(
             AppErrorKind::UnsupportedMethod,
             format!("{} is not a supported method.", method),
         )),
-    }?;
-
-    match output_path {
-        Some(path) => {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(path)?;
-            file.write_all(&data)?;
-        }
-        None => println!("{}", String::from_utf8_lossy(&data)),
+    };
+    let data = match found {
+        // The text chunk of some editor may read like a payload: the pixels
+        // are tried next, with the default method of PNG covers.
+        Err(err)
+            if detected
+                && matches!(
+                    err.kind,
+                    AppErrorKind::NoPayload | AppErrorKind::CRCMismatch
+                ) =>
+        {
+            if verbose {
+                eprintln!("The chunk holds no payload, trying LSB");
+            }
+            let data = lsb::extract(
+                image_path,
+                key,
+                verbose,
+                pixel_options,
+                format,
+                &policy,
+                report,
+            )
+            .context(Stage::Extract, image_path);
+            if data.is_ok() {
+                report.method = Some("LSB".to_string());
+            }
+            // The error of the chunk is the one that tells what is wrong.
+            data.map_err(|_| err)?
+        }
+        found => found?,
+    };
+
+    let payload = payload::decode(data)?;
+    report.payload_bytes = Some(payload.data.len());
+    if let Some(header) = &payload.header {
+        report.header(header);
+    }
+    report.metadata = payload.meta.clone();
+
+    if verbose {
+        if let Some(header) = &payload.header {
+            eprintln!(
+                "Kiki header:      version {}, flags {:#010b}",
+                header.version, header.flags
+            );
+        }
+        if let Some(meta) = &payload.meta {
+            eprintln!("Stored name:      {:?}", meta.name);
+            eprintln!("Stored type:      {:?}", meta.mime);
+            eprintln!("Stored mtime:     {:?}", meta.mtime);
+            eprintln!(
+                "Stored mode:      {:?}",
+                meta.mode.map(|m| format!("{:o}", m))
+            );
+        }
+    }
+
+    if list_only {
+        return list(&payload, report);
+    }
+
+    if payload.header.as_ref().is_some_and(|h| h.is_archive()) {
+        return unpack(&payload, output, verbose);
+    }
+
+    match output {
+        ExtractOutput::Path(path) => {
+            write_file(&path, &payload.data, false).context(Stage::Save, &path)?;
+            if let Some(meta) = &payload.meta {
+                meta.apply(&path)?;
+            }
+        }
+        ExtractOutput::Stdout => std::io::stdout()
+            .write_all(&payload.data)
+            .context(Stage::Save, Path::new("stdout"))?,
+        ExtractOutput::Original => {
+            match payload.meta.as_ref().map(|m| m.safe_name()).transpose()? {
+                Some(Some(path)) => {
+                    if path.exists() {
+                        return Err(AppError::new(
+                            AppErrorKind::Io,
+                            format!(
+                                "{} already exists. Specify an output path to overwrite it.",
+                                path.display()
+                            ),
+                        ));
+                    }
+                    write_file(&path, &payload.data, true).context(Stage::Save, &path)?;
+                    payload.meta.as_ref().unwrap().apply(&path)?;
+                    if verbose {
+                        eprintln!("Restored to {}", path.display());
+                    }
+                }
+                _ => match std::str::from_utf8(&payload.data) {
+                    Ok(_) => std::io::stdout().write_all(&payload.data)?,
+                    Err(_) => {
+                        return Err(AppError::new(
+                            AppErrorKind::BinaryOutput,
+                            "The data is binary. Specify an output path, or \"-\" to write it to stdout.",
+                        ))
+                    }
+                },
+            }
+        }
     }
     Ok(())
 }
@@ -0,0 +1,365 @@
+use crate::batch::{finish_job, output_name, run_pool, summarize, BatchOptions};
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::{self, load_image};
+use crate::methods::data::hash_key;
+use crate::methods::watermark::{self, Spreading};
+use crate::report::{Report, SuspectReport};
+use image::{DynamicImage, ImageFormat};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::f64::consts::FRAC_PI_2;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+// Every recipient gets a Tardos codeword, embedded with the robust watermark.
+// The biases of the code are derived from the key and the codeword of a
+// recipient from the key and its id, so tracing only needs the key and the
+// recipient list. A copy averaged from several others still scores its
+// sources, up to the coalition size the code is tuned for.
+
+pub const FINGERPRINT_TEMPLATE: &str = "{stem}_{recipient}.{ext}";
+
+/// Symbols of the code, each spread over four coefficients like the bits of WM.
+const CODE_BITS: usize = watermark::BAND_COEFS / 4;
+/// Largest number of colluding recipients the code is tuned for.
+const COALITION: f64 = 4.0;
+/// Score above which a recipient is accused. Innocent recipients score about
+/// a standard normal variable.
+const THRESHOLD: f64 = 5.0;
+/// Mixed into the key so that the code does not follow the WM method.
+const BIAS_SEED: u64 = 0x5441_5244;
+const SPREAD_SEED: u64 = 0x4650_5254;
+
+/// Reads the recipient ids, one per line. Empty lines and lines starting with
+/// '#' are skipped.
+fn read_recipients(path: &Path) -> Result<Vec<String>, AppError> {
+    let content = fs::read_to_string(path).context(Stage::Read, path)?;
+    let mut seen = HashSet::new();
+    let mut recipients = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !seen.insert(line) {
+            return Err(AppError::new(
+                AppErrorKind::InvalidOption,
+                format!("The recipient {} is listed twice.", line),
+            )
+            .with_path(path));
+        }
+        recipients.push(line.to_string());
+    }
+
+    if recipients.is_empty() {
+        return Err(
+            AppError::new(AppErrorKind::InvalidOption, "The recipient list is empty.")
+                .with_path(path),
+        );
+    }
+    Ok(recipients)
+}
+
+/// The probability of a 1 at each position of the code, from the arcsine
+/// distribution cut off at 1 / (300 c).
+fn biases(key: Option<&String>) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(hash_key(key) ^ BIAS_SEED);
+    let cutoff = (1.0 / (300.0 * COALITION)).sqrt().asin();
+    (0..CODE_BITS)
+        .map(|_| rng.gen_range(cutoff..FRAC_PI_2 - cutoff).sin().powi(2))
+        .collect()
+}
+
+/// The codeword of a recipient.
+fn codeword(key: Option<&String>, recipient: &str, biases: &[f64]) -> Vec<u8> {
+    let seed = format!(
+        "{}\0{}",
+        key.map(String::as_str).unwrap_or_default(),
+        recipient
+    );
+    let mut rng = StdRng::seed_from_u64(hash_key(Some(&seed)));
+    biases
+        .iter()
+        .map(|&p| (rng.gen::<f64>() < p) as u8)
+        .collect()
+}
+
+/// The symmetric Tardos score of a codeword, the symbols read being weighted
+/// by how sure they are. It is normalized by the length of the code.
+fn accusation(codeword: &[u8], soft: &[f32], biases: &[f64]) -> f64 {
+    let total: f64 = codeword
+        .iter()
+        .zip(soft)
+        .zip(biases)
+        .map(|((&symbol, &soft), &p)| {
+            // A soft value of 1 reads a 0, -1 reads a 1.
+            let soft = soft as f64;
+            if symbol == 1 {
+                -soft * ((1.0 - p) / p).sqrt()
+            } else {
+                soft * (p / (1.0 - p)).sqrt()
+            }
+        })
+        .sum();
+    total / (CODE_BITS as f64).sqrt()
+}
+
+fn spreading(key: Option<&String>, options: &[&String]) -> Result<Spreading, AppError> {
+    Ok(Spreading::new(
+        hash_key(key) ^ SPREAD_SEED,
+        watermark::step(options)?,
+        CODE_BITS,
+    ))
+}
+
+/// Marks one copy with the codeword and, if asked, checks that the saved
+/// image accuses its recipient.
+#[allow(clippy::too_many_arguments)]
+fn mark_copy(
+    img: &DynamicImage,
+    output: &Path,
+    recipient: &str,
+    biases: &[f64],
+    spreading: &Spreading,
+    verify: bool,
+    opts: &BatchOptions,
+    job: &mut Report,
+) -> Result<(), AppError> {
+    let code = codeword(opts.key, recipient, biases);
+    let marked = watermark::mark(img, opts.key, spreading, &code, opts.verbose);
+    watermark::save(img, marked, output, opts.format).context(Stage::Save, output)?;
+    if !verify {
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let saved = load_image(output, opts.format).context(Stage::Verify, output)?;
+    let soft = watermark::read(&saved, opts.key, spreading, false, |soft| {
+        accusation(&code, soft, biases) > THRESHOLD
+    });
+    let score = accusation(&code, &soft, biases);
+    job.score = Some(score);
+    job.time("verify", start);
+    if score <= THRESHOLD {
+        return Err(AppError::new(
+            AppErrorKind::VerifyFailed,
+            format!(
+                "The saved image does not carry the fingerprint of {} (score {:.2}).",
+                recipient, score
+            ),
+        )
+        .with_stage(Stage::Verify)
+        .with_path(output));
+    }
+    job.verified = Some(true);
+    Ok(())
+}
+
+/// Writes one copy of the cover per recipient of the list into the output
+/// directory, each carrying the codeword of its recipient.
+pub fn fingerprint(
+    cover: &Path,
+    output_dir: &Path,
+    recipients: &Path,
+    verify: bool,
+    opts: BatchOptions,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let recipients = read_recipients(recipients)?;
+    let start = Instant::now();
+    let img = load_image(cover, opts.format).context(Stage::Decode, cover)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+    fs::create_dir_all(output_dir).context(Stage::Save, output_dir)?;
+
+    let biases = biases(opts.key);
+    let spreading = spreading(opts.key, &opts.options)?;
+    let template = opts
+        .template
+        .map(|t| t.as_str())
+        .unwrap_or(FINGERPRINT_TEMPLATE);
+
+    let results = run_pool(opts.jobs, || {
+        recipients
+            .par_iter()
+            .enumerate()
+            .map(|(index, recipient)| {
+                let mut job = Report::new("fingerprint");
+                job.image = Some(cover.display().to_string());
+                job.options = opts.options.iter().map(|o| o.to_string()).collect();
+
+                let result = (|| {
+                    let name = template.replace("{recipient}", recipient);
+                    let output = output_dir.join(output_name(&name, cover, index)?);
+                    job.output = Some(output.display().to_string());
+                    mark_copy(
+                        &img, &output, recipient, &biases, &spreading, verify, &opts, &mut job,
+                    )
+                })();
+                finish_job(job, result)
+            })
+            .collect::<Vec<_>>()
+    })?;
+
+    summarize(results, report)
+}
+
+/// Ranks the recipients of the list by how likely the suspect image derives
+/// from their copy, and fails if none of them is accused.
+pub fn trace(
+    image_path: &Path,
+    recipients: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    if imageio::is_std(image_path) {
+        return Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            "trace needs the suspect image as a file, it cannot read stdin.",
+        ));
+    }
+    let recipients = read_recipients(recipients)?;
+
+    let start = Instant::now();
+    let img = load_image(image_path, format).context(Stage::Decode, image_path)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+
+    let start = Instant::now();
+    let biases = biases(key);
+    let codes: Vec<Vec<u8>> = recipients
+        .iter()
+        .map(|recipient| codeword(key, recipient, &biases))
+        .collect();
+    let spreading = spreading(key, &options)?;
+    let soft = watermark::read(&img, key, &spreading, verbose, |soft| {
+        codes
+            .iter()
+            .any(|code| accusation(code, soft, &biases) > THRESHOLD)
+    });
+
+    let mut suspects: Vec<SuspectReport> = recipients
+        .iter()
+        .zip(&codes)
+        .map(|(recipient, code)| {
+            let score = accusation(code, &soft, &biases);
+            SuspectReport {
+                recipient: recipient.clone(),
+                score,
+                accused: score > THRESHOLD,
+            }
+        })
+        .collect();
+    suspects.sort_by(|a, b| b.score.total_cmp(&a.score));
+    report.time("trace", start);
+    report.score = Some(watermark::score(&soft) as f64);
+
+    let accused = suspects.iter().filter(|s| s.accused).count();
+    let best = suspects[0].score;
+    if !report.json {
+        println!("Watermark score: {:.3}", watermark::score(&soft));
+        for suspect in suspects.iter().take(accused.max(10)) {
+            println!(
+                "{:>8.2}  {}{}",
+                suspect.score,
+                suspect.recipient,
+                if suspect.accused { "  (accused)" } else { "" }
+            );
+        }
+    }
+    report.suspects = Some(suspects);
+
+    if accused == 0 {
+        return Err(AppError::new(
+            AppErrorKind::NoPayload,
+            format!(
+                "No recipient is accused, the best score is {:.2} (threshold {:.1}).",
+                best, THRESHOLD
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{RgbImage, RgbaImage};
+
+    #[test]
+    fn codewords_follow_key_and_recipient() {
+        let (key, other) = ("key".to_string(), "other".to_string());
+        let biases = biases(Some(&key));
+        assert_eq!(biases.len(), CODE_BITS);
+        assert!(biases.iter().all(|&p| p > 0.0 && p < 1.0));
+
+        let code = codeword(Some(&key), "alice", &biases);
+        assert_eq!(code, codeword(Some(&key), "alice", &biases));
+        assert_ne!(code, codeword(Some(&key), "bob", &biases));
+        assert_ne!(code, codeword(Some(&other), "alice", &biases));
+    }
+
+    #[test]
+    fn innocents_stay_below_the_threshold() {
+        let key = "key".to_string();
+        let biases = biases(Some(&key));
+        let guilty = codeword(Some(&key), "guilty", &biases);
+        let soft: Vec<f32> = guilty
+            .iter()
+            .map(|&s| if s == 0 { 0.7 } else { -0.7 })
+            .collect();
+        assert!(accusation(&guilty, &soft, &biases) > THRESHOLD);
+        for idx in 0..500 {
+            let code = codeword(Some(&key), &format!("innocent {}", idx), &biases);
+            assert!(accusation(&code, &soft, &biases) < THRESHOLD);
+        }
+    }
+
+    #[test]
+    fn averaged_copies_accuse_their_sources() {
+        let key = "key".to_string();
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(256, 256, |x, y| {
+            let value = 128.0 + 60.0 * (x as f32 / 23.0).sin() * (y as f32 / 31.0).cos();
+            image::Rgb([value as u8, (value * 0.8) as u8, 255 - value as u8])
+        }));
+        let biases = biases(Some(&key));
+        let spreading = spreading(Some(&key), &[]).unwrap();
+
+        let colluders = ["alice", "bob", "carol"];
+        let copies: Vec<RgbaImage> = colluders
+            .iter()
+            .map(|recipient| {
+                let code = codeword(Some(&key), recipient, &biases);
+                watermark::mark(&img, Some(&key), &spreading, &code, false)
+            })
+            .collect();
+        let average = RgbaImage::from_fn(256, 256, |x, y| {
+            let sum = |c: usize| {
+                copies
+                    .iter()
+                    .map(|i| i.get_pixel(x, y)[c] as u32)
+                    .sum::<u32>()
+            };
+            let n = copies.len() as u32;
+            image::Rgba([sum(0) / n, sum(1) / n, sum(2) / n, 255].map(|v| v as u8))
+        });
+
+        let average = DynamicImage::ImageRgba8(average);
+        let soft = watermark::read(&average, Some(&key), &spreading, false, |_| true);
+        let scores: Vec<f64> = colluders
+            .iter()
+            .map(|recipient| accusation(&codeword(Some(&key), recipient, &biases), &soft, &biases))
+            .collect();
+        assert!(scores.iter().any(|&s| s > THRESHOLD), "{:?}", scores);
+        for idx in 0..50 {
+            let code = codeword(Some(&key), &format!("innocent {}", idx), &biases);
+            assert!(accusation(&code, &soft, &biases) < THRESHOLD);
+        }
+    }
+}
@@ -1,31 +1,155 @@
-pub const AFTER_HELP: &'static str = color_print::cstr!(
+pub const AFTER_HELP: &str = color_print::cstr!(
     "<bold><underline>Methods list:</underline></bold>
-    - LSB      Least significant bit. (lossless only)
+    - LSB      Least significant bit. (BMP, PNG, lossless WebP, TIFF, QOI, PPM/PGM/PAM, 8 and 16 bit,
+               all the frames of animated PNGs and pages of TIFFs)
+    - PAL      Palette parity (EzStego), keeps the palette. (GIF and indexed PNG, all frames)
+    - PVD      Pixel value differencing, hides more bits at edges than in smooth areas.
+               (8 bit images of the LSB formats)
+    - BPCS     Bit-plane complexity segmentation, replaces noisy 8x8 bit plane blocks, up to about
+               half of the image. (8 bit images of the LSB formats)
+    - ALSB     Least significant bit of audio samples. (8, 16 and 24 bit PCM WAV)
+    - CHUNK    Encrypted payload in a PNG chunk, leaves every pixel and chunk as it was. Anyone
+               listing the chunks sees it: perfect fidelity, weak hiding. (PNG)
+    - ZW       Zero-width characters between words, a byte per gap. (UTF-8 text, .txt and .md)
+    - WS       Trailing spaces and tabs, a byte per line. (UTF-8 text, .txt)
+    - WM       Robust watermark of up to 32 bytes, survives JPEG, resizing and crops of up to 6%
+               per side. (JPEG and the lossless image formats)
 <bold><underline>Method options list:</underline></bold>
   <underline>LSB Least Significant Bit:</underline>
     - SEQ : uses consecutive pixels, starting from the top left.
-    - RNG : uses random pixels, determined by the passed key."
+    - RNG : uses random pixels, determined by the passed key.
+    - LEGACY : uses the random pixel order of earlier kiki versions. Extracting tries it anyway when nothing is found.
+    - PAD : fills the unused capacity with random data, hiding the payload size.
+    - PAD=N : pads the data up to a multiple of N bytes (e.g. PAD=4K).
+    - MASK=path : only uses the pixels that are white in the mask image, of the same size.
+    - RECT=x,y,w,h : only uses the pixels of the rectangle, can be repeated and combined with MASK.
+      Masks apply to still images, and must be given again to extract.
+    - CHROMA : embeds in the Cb and Cr planes of the reversible color transform (8 bit RGB and
+      RGBA), where changes are less visible. Must be given again to extract.
+  <underline>PAL Palette:</underline>
+    - SEQ : uses consecutive pixels, starting from the top left.
+    - PAD, PAD=N, MASK=path, RECT=x,y,w,h : as for LSB.
+  <underline>PVD Pixel Value Differencing:</underline>
+    - SEQ : uses consecutive pairs of pixels, starting from the top left.
+    - PAD, PAD=N : as for LSB.
+  <underline>BPCS Bit-Plane Complexity Segmentation:</underline>
+    - THRESHOLD=N : complexity a block needs to carry data, from 0 to 0.5 (default 0.3). Lower
+      values hold more but are more visible. It must be given again to extract.
+    - SEQ : uses consecutive blocks, starting from the top left.
+    - PAD, PAD=N : as for LSB.
+  <underline>ALSB Audio Least Significant Bit:</underline>
+    - SEQ : uses consecutive samples, starting from the beginning.
+    - PAD, PAD=N : as for LSB.
+  <underline>CHUNK PNG Chunk:</underline>
+    - CHUNK=name : the type of the private chunk: two lowercase letters, an uppercase one and
+      any letter (default prVt).
+    - TEXT, TEXT=keyword : stores the payload in base64 in an iTXt chunk (default keyword Comment),
+      like ordinary metadata. Extract finds both defaults without -m nor options.
+    - PAD=N : as for LSB.
+  <underline>ZW Zero-Width, WS Whitespace:</underline>
+    - SEQ : uses consecutive gaps or lines, starting from the beginning.
+    - PAD, PAD=N : as for LSB.
+  <underline>WM Watermark:</underline>
+    - STRENGTH=N : scales the strength of the mark (default 1), more robust but more visible.
+      It must be given again to extract, inspect or trace.
+<bold><underline>Exit codes:</underline></bold>
+    0  success
+    1  some files of a batch failed
+    2  invalid usage: unknown method, format or option
+    3  I/O error
+    4  image decoding or encoding error
+    5  capacity exceeded
+    6  no payload found (wrong key or method)
+    7  CRC mismatch
+    8  malformed payload
+    9  stopped by the user or by --no / --limit
+    10 unsafe output path
+    11 the saved image does not hold the data (see --no-verify)"
 );
 
-pub const EMBED_IMAGE: &'static str = "The path to the image to hide the data in.";
+pub const EMBED_IMAGE: &str =
+    "The path to the image to hide the data in. If \"-\", read the image from stdin. \
+If a directory or a glob pattern, embed into every image it contains (batch mode).";
+
+pub const EMBED_OUTPUT: &str =
+    "Path of the output image. If \"-\", write the image to stdout (requires --format). \
+In batch mode, the output directory.";
+
+pub const EMBED_SECRET: &str =
+    "Path to the file containing the secret. If unspecified or \"-\", read from stdin. \
+Multiple files or directories are packed into an archive: \
+they must have different names, and the directories no symbolic links.";
+
+pub const EXTRACT_IMAGE: &str =
+    "The path to the image to extract data from. If \"-\", read the image from stdin. \
+If a directory or a glob pattern, extract from every image it contains (batch mode).";
+
+pub const FINGERPRINT_COVER: &str = "The path to the image to give every recipient a copy of.";
+
+pub const FINGERPRINT_OUTPUT: &str = "The directory to write the copies to.";
+
+pub const RECIPIENTS: &str =
+    "Path to the list of recipient ids, one per line. Lines starting with '#' are skipped.";
+
+pub const FINGERPRINT_TEMPLATE: &str = "Name of the copies, where {recipient}, {name}, {stem} \
+and {ext} are replaced. Defaults to {stem}_{recipient}.{ext}.";
+
+pub const TRACE_IMAGE: &str = "The path to the suspect image.";
+
+pub const RANK_COVERS: &str = "The directory or glob pattern of the candidate covers.";
+
+pub const RANK_EMBED: &str =
+    "Embed the secret with LSB into the best cover, writing it to this path.";
 
-pub const EMBED_OUTPUT: &'static str = "Path of the output image.";
+pub const INSPECT_IMAGE: &str = "The path to the image or audio file to inspect.";
 
-pub const EMBED_SECRET: &'static str =
-    "Path to the file containing the secret. If unspecified or \"-\", read from stdin.";
+pub const EXTRACT_OUTPUT: &str =
+    "The file path to write the data to. If \"-\", write the raw data to stdout. \
+If unspecified, restore the file under its stored name, or print the data if it is text. \
+Archives are unpacked into this directory, or the current one if unspecified. \
+In batch mode, the output directory.";
 
-pub const EXTRACT_IMAGE: &'static str = "The path to the image to extract data from.";
+pub const YES: &str = "Continue without asking when the message is larger than the limit.";
 
-pub const EXTRACT_OUTPUT: &'static str =
-    "The file path to write the data to. If unspecified or \"-\", write to stdout.";
+pub const NO: &str = "Stop without asking when the message is larger than the limit.";
 
-pub const METHOD: &'static str =
+pub const LIMIT: &str = "Message size above which confirmation is required (e.g. 512K, 4M). \
+Defaults to 1M. Without a terminal on stdin, larger messages are refused unless --yes is given.";
+
+pub const JSON: &str = "Print a single JSON object describing the operation and its outcome. \
+It goes to stdout, or to stderr when stdout carries the image or the data.";
+
+pub const MANIFEST: &str = "Batch mode: CSV (image,secret[,key]) or JSON lines \
+({\"image\", \"secret\", \"key\"}) file giving the secret of each image, by path or filename.";
+
+pub const TEMPLATE: &str = "Batch mode: name of the output files, where {name}, {stem}, {ext} and \
+{index} are replaced. Defaults to {name} for embed and {stem}.out for extract.";
+
+pub const JOBS: &str =
+    "Batch mode: number of files processed in parallel. Defaults to the number of cores.";
+
+pub const LIST: &str = "List the stored files instead of extracting them.";
+
+pub const NO_VERIFY: &str = "Do not read the saved image back to check that it holds the data. \
+Verification is skipped anyway when writing to stdout.";
+
+pub const STRIP_METADATA: &str = "Do not carry the cover's EXIF, color profile, text and other \
+metadata over to the output, and remove what PNG outputs keep of it. \
+With CHUNK, the chunk holding the payload stays.";
+
+pub const METADATA: &str =
+    "Store the secret's filename, type, modification time and permissions with the data.";
+
+pub const METHOD: &str =
     "The method to use for the operation. The list is available on the help menu.";
 
-pub const KEY: &'static str =
+pub const KEY: &str =
     "The key to use for the operation. If unspecified, an empty string will be used.";
 
-pub const VERBOSE: &'static str = "Gives additional output, useful for debugging.";
+pub const VERBOSE: &str = "Gives additional output on stderr, useful for debugging.";
+
+pub const FORMAT: &str = "The image format, as an extension (e.g. png). Required when writing \
+the image to stdout, otherwise inferred from the extension or the content of the file.";
 
-pub const OPTIONS: &'static str =
+pub const OPTIONS: &str =
     "Additional method-specific options. The list is available on the help menu.";
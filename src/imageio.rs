@@ -0,0 +1,238 @@
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+use image::{ColorType, DynamicImage, ImageFormat, ImageReader};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+/// Returns true if the path stands for stdin or stdout.
+pub fn is_std(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Parses the value of the --format flag, given as a file extension.
+pub fn parse_format(format: &str) -> Result<ImageFormat, AppError> {
+    ImageFormat::from_extension(format).ok_or_else(|| {
+        AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            format!("{} is not a known image format.", format),
+        )
+    })
+}
+
+/// Returns the extension used to choose the method: the one of the given
+/// format if specified, otherwise the one of the path.
+pub fn extension(path: &Path, format: Option<ImageFormat>) -> Option<String> {
+    match format {
+        Some(format) => format_extension(format),
+        None if is_std(path) => None,
+        None => path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase()),
+    }
+}
+
+/// Returns the extension of a format, as used to choose the method.
+pub fn format_extension(format: ImageFormat) -> Option<String> {
+    match format {
+        // The first extension of PNM is "pbm", which only holds bilevel images.
+        ImageFormat::Pnm => Some("pnm".to_string()),
+        format => format.extensions_str().first().map(|e| e.to_string()),
+    }
+}
+
+/// Reads the whole file at the path, or stdin if the path is "-".
+pub fn read_input(path: &Path) -> Result<Vec<u8>, AppError> {
+    if is_std(path) {
+        let mut data = Vec::new();
+        io::stdin()
+            .read_to_end(&mut data)
+            .context(Stage::Read, Path::new("stdin"))?;
+        Ok(data)
+    } else {
+        std::fs::read(path).context(Stage::Read, path)
+    }
+}
+
+/// Writes the data to the file at the path, or to stdout if the path is "-".
+pub fn write_output(path: &Path, data: &[u8]) -> Result<(), AppError> {
+    if is_std(path) {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(data)
+            .and_then(|_| stdout.flush())
+            .context(Stage::Save, Path::new("stdout"))
+    } else {
+        std::fs::write(path, data).context(Stage::Save, path)
+    }
+}
+
+/// Opens the image at the path, or reads it from stdin if the path is "-".
+pub fn load_image(path: &Path, format: Option<ImageFormat>) -> Result<DynamicImage, AppError> {
+    decode_image(read_input(path)?, path, format)
+}
+
+/// Decodes the image read from the path. Unless a format is given, it is
+/// sniffed from the magic bytes of the data.
+pub fn decode_image(
+    data: Vec<u8>,
+    path: &Path,
+    format: Option<ImageFormat>,
+) -> Result<DynamicImage, AppError> {
+    let reader = match format {
+        Some(format) => ImageReader::with_format(Cursor::new(data), format),
+        None => ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .context(Stage::Read, path)?,
+    };
+
+    if reader.format().is_none() {
+        return Err(AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            "Could not determine the image format. Specify it with --format.",
+        )
+        .with_stage(Stage::Decode)
+        .with_path(path));
+    }
+
+    reader.decode().context(Stage::Decode, path)
+}
+
+/// Encodes the image as PPM or PGM if the path asks for it, otherwise as PAM.
+fn encode_pnm(img: &DynamicImage, path: &Path) -> Result<Vec<u8>, AppError> {
+    let extension = extension(path, None);
+    let subtype = match (extension.as_deref(), img.color()) {
+        (Some("ppm"), ColorType::Rgb8) => Some(PnmSubtype::Pixmap(SampleEncoding::Binary)),
+        (Some("pgm"), ColorType::L8) => Some(PnmSubtype::Graymap(SampleEncoding::Binary)),
+        (Some(ext @ ("ppm" | "pgm")), color) => {
+            return Err(AppError::new(
+                AppErrorKind::UnsupportedExtension,
+                format!(
+                    "A {:?} image cannot be written as {}, use pam instead.",
+                    color,
+                    ext.to_ascii_uppercase()
+                ),
+            )
+            .with_stage(Stage::Encode)
+            .with_path(path))
+        }
+        _ => None,
+    };
+
+    let mut data = Vec::new();
+    let encoder = PnmEncoder::new(&mut data);
+    match subtype {
+        Some(subtype) => img.write_with_encoder(encoder.with_subtype(subtype)),
+        None => img.write_with_encoder(encoder),
+    }
+    .context(Stage::Encode, path)?;
+    Ok(data)
+}
+
+/// Saves the image to the path, or writes it to stdout if the path is "-".
+/// The format is the given one, or inferred from the extension of the path.
+pub fn save_image(
+    img: &DynamicImage,
+    path: &Path,
+    format: Option<ImageFormat>,
+) -> Result<(), AppError> {
+    let format = match format {
+        Some(format) => format,
+        None if is_std(path) => {
+            return Err(AppError::new(
+                AppErrorKind::MissingExtension,
+                "The output format must be specified with --format when writing to stdout.",
+            ))
+        }
+        None => ImageFormat::from_path(path).context(Stage::Encode, path)?,
+    };
+
+    if format == ImageFormat::Pnm {
+        write_output(path, &encode_pnm(img, path)?)?;
+    } else if is_std(path) {
+        let mut data = Cursor::new(Vec::new());
+        img.write_to(&mut data, format)
+            .context(Stage::Encode, Path::new("stdout"))?;
+        write_output(path, data.get_ref())?;
+    } else {
+        img.save_with_format(path, format)
+            .context(Stage::Save, path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, Rgb32FImage, RgbImage};
+
+    fn photo() -> RgbImage {
+        RgbImage::from_fn(23, 17, |x, y| {
+            Rgb([(x * 11) as u8, (y * 13) as u8, (x ^ y) as u8])
+        })
+    }
+
+    #[test]
+    fn formats_have_a_method_extension() {
+        assert_eq!(format_extension(ImageFormat::Pnm).unwrap(), "pnm");
+        assert_eq!(format_extension(ImageFormat::Tiff).unwrap(), "tiff");
+        let format = Some(parse_format("PPM").unwrap());
+        assert_eq!(extension(Path::new("a.png"), format).unwrap(), "pnm");
+        assert_eq!(extension(Path::new("a.TIF"), None).unwrap(), "tif");
+        assert_eq!(extension(Path::new("-"), None), None);
+        let err = parse_format("xyz").unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::UnsupportedExtension);
+    }
+
+    #[test]
+    fn pnm_subtype_follows_the_extension() {
+        let rgb = DynamicImage::ImageRgb8(photo());
+        let gray = DynamicImage::ImageLuma8(rgb.to_luma8());
+        let cases = [
+            (&rgb, "a.ppm", "P6"),
+            (&gray, "a.pgm", "P5"),
+            (&rgb, "a.pam", "P7"),
+            (&gray, "a.pnm", "P7"),
+        ];
+        for (img, path, magic) in cases {
+            let data = encode_pnm(img, Path::new(path)).unwrap();
+            assert!(data.starts_with(magic.as_bytes()), "{}", path);
+        }
+
+        let deep = DynamicImage::ImageRgb16(rgb.to_rgb16());
+        for (img, path) in [(&deep, "a.ppm"), (&rgb, "a.pgm")] {
+            let err = encode_pnm(img, Path::new(path)).unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::UnsupportedExtension);
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let rgb = DynamicImage::ImageRgb8(photo());
+        let images = [
+            (rgb.clone(), "ppm"),
+            (DynamicImage::ImageLuma8(rgb.to_luma8()), "pgm"),
+            (DynamicImage::ImageRgba8(rgb.to_rgba8()), "pam"),
+            (rgb.clone(), "tif"),
+            (DynamicImage::ImageRgb16(rgb.to_rgb16()), "tiff"),
+        ];
+        for (img, ext) in images {
+            let path = std::env::temp_dir().join(format!("kiki_{}_io.{}", std::process::id(), ext));
+            save_image(&img, &path, None).unwrap();
+            let loaded = load_image(&path, None).unwrap();
+            assert_eq!(loaded, img, "{}", ext);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn unknown_data_needs_a_format() {
+        let path = Path::new("-");
+        let err = decode_image(b"not an image".to_vec(), path, None).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::UnsupportedExtension);
+        let err = save_image(&DynamicImage::ImageRgb8(photo()), path, None).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::MissingExtension);
+        let float = DynamicImage::ImageRgb32F(Rgb32FImage::new(2, 2));
+        assert!(encode_pnm(&float, Path::new("a.pam")).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::embed::supported_methods;
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::{self, read_input};
+use crate::methods::data::LengthPolicy;
+use crate::methods::{bpcs, chunk, lsb, palette, pvd, text, watermark, wav};
+use crate::payload;
+use crate::report::{HeaderReport, MethodReport, PayloadStatus, Report};
+use image::{ImageDecoder, ImageFormat, ImageReader};
+use std::io::Cursor;
+use std::path::Path;
+
+/// What the file is: its format, the extension used to choose the methods,
+/// its color type, its bits per channel and, for images, its size.
+struct Description {
+    format: String,
+    extension: String,
+    color: String,
+    bit_depth: Option<u16>,
+    dimensions: Option<(u32, u32)>,
+}
+
+fn is_wav(data: &[u8]) -> bool {
+    data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE")
+}
+
+fn describe(data: &[u8], format: Option<ImageFormat>) -> Result<Description, AppError> {
+    if is_wav(data) {
+        let (channels, bits) = wav::spec(data.to_vec())?;
+        return Ok(Description {
+            format: "WAV".to_string(),
+            extension: "wav".to_string(),
+            color: format!("PCM, {} channels", channels),
+            bit_depth: Some(bits),
+            dimensions: None,
+        });
+    }
+
+    let format = match format {
+        Some(format) => format,
+        None => match (image::guess_format(data), std::str::from_utf8(data)) {
+            (Err(_), Ok(text)) => {
+                return Ok(Description {
+                    format: "TEXT".to_string(),
+                    extension: "txt".to_string(),
+                    color: format!("UTF-8, {} lines", text.lines().count()),
+                    bit_depth: None,
+                    dimensions: None,
+                })
+            }
+            (format, _) => format?,
+        },
+    };
+    let extension = imageio::format_extension(format).unwrap_or_default();
+    let (color, bit_depth) = match format {
+        // The image decoders expand palettes, the file itself is read instead.
+        ImageFormat::Png => {
+            let reader = png::Decoder::new(Cursor::new(data))
+                .read_info()
+                .map_err(|err| AppError::new(AppErrorKind::Image, err.to_string()))?;
+            let info = reader.info();
+            let color = match info.color_type {
+                png::ColorType::Grayscale => "L",
+                png::ColorType::GrayscaleAlpha => "La",
+                png::ColorType::Rgb => "Rgb",
+                png::ColorType::Rgba => "Rgba",
+                png::ColorType::Indexed => "Indexed",
+            };
+            (color.to_string(), info.bit_depth as u16)
+        }
+        ImageFormat::Gif => ("Indexed".to_string(), 8),
+        format => {
+            let decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder()?;
+            let color = decoder.original_color_type();
+            let name = format!("{:?}", color);
+            let bits = color.bits_per_pixel() / color.channel_count().max(1) as u16;
+            (name.trim_end_matches(char::is_numeric).to_string(), bits)
+        }
+    };
+
+    let dimensions = ImageReader::with_format(Cursor::new(data), format).into_dimensions()?;
+
+    Ok(Description {
+        format: format!("{:?}", format).to_ascii_uppercase(),
+        extension,
+        color,
+        bit_depth: Some(bit_depth),
+        dimensions: Some(dimensions),
+    })
+}
+
+/// Reads the carrier with one method, without writing the payload anywhere.
+fn inspect_method(
+    image_path: &Path,
+    method: &str,
+    key: Option<&String>,
+    options: &[&String],
+    format: Option<ImageFormat>,
+) -> MethodReport {
+    let mut result = MethodReport {
+        method: method.to_string(),
+        capacity_bits: 0,
+        capacity_bytes: 0,
+        payload: PayloadStatus::Unreadable,
+        payload_bytes: None,
+        score: None,
+        blocks: None,
+        header: None,
+        metadata: None,
+        message: None,
+    };
+
+    let capacity = match method {
+        "LSB" => lsb::capacity(image_path, format, options),
+        "PAL" => palette::capacity(image_path, options),
+        "PVD" => pvd::capacity(image_path, format),
+        "BPCS" => bpcs::capacity(image_path, format, options).map(|(bits, blocks)| {
+            result.blocks = Some(blocks);
+            bits
+        }),
+        "WM" => Ok(watermark::capacity()),
+        "CHUNK" => Ok(chunk::capacity()),
+        "ZW" | "WS" => text::capacity(image_path, method),
+        _ => wav::capacity(image_path),
+    };
+    match capacity {
+        // The watermark frames its message itself, the others use 8 bytes.
+        Ok(bits) if method == "WM" => {
+            result.capacity_bits = bits;
+            result.capacity_bytes = bits / 8;
+        }
+        Ok(bits) => {
+            result.capacity_bits = bits;
+            result.capacity_bytes = (bits / 8).saturating_sub(8);
+        }
+        Err(err) => {
+            result.message = Some(err.to_string());
+            return result;
+        }
+    }
+
+    // Any length that fits is read, there is nobody to ask.
+    let policy = LengthPolicy {
+        limit: usize::MAX,
+        answer: Some(true),
+        stdin_free: false,
+    };
+    let options = options.to_vec();
+    let report = &mut Report::default();
+    let extracted = match method {
+        "LSB" => lsb::extract(image_path, key, false, options, format, &policy, report),
+        "PAL" => palette::extract(image_path, key, false, options, &policy, report),
+        "PVD" => pvd::extract(image_path, key, false, options, format, &policy, report),
+        "BPCS" => bpcs::extract(image_path, key, false, options, format, &policy, report),
+        "WM" => watermark::extract(image_path, key, false, options, format, report),
+        "ZW" | "WS" => text::extract(image_path, method, key, false, options, &policy, report),
+        "CHUNK" => chunk::extract(image_path, key, false, options, &policy, report),
+        _ => wav::extract(image_path, key, false, options, &policy, report),
+    };
+    result.score = report.score;
+
+    let data = match extracted {
+        Ok(data) => data,
+        Err(err) => {
+            result.payload = match err.kind {
+                AppErrorKind::NoPayload => PayloadStatus::None,
+                AppErrorKind::CRCMismatch => PayloadStatus::CrcMismatch,
+                _ => PayloadStatus::Unreadable,
+            };
+            result.message = Some(err.to_string());
+            return result;
+        }
+    };
+
+    match payload::decode(data) {
+        Ok(payload) => {
+            result.payload = PayloadStatus::Found;
+            result.payload_bytes = Some(payload.data.len());
+            result.header = payload.header.map(|h| HeaderReport {
+                version: h.version,
+                flags: h.flags,
+            });
+            result.metadata = payload.meta;
+        }
+        Err(err) => {
+            result.payload = PayloadStatus::Malformed;
+            result.message = Some(err.to_string());
+        }
+    }
+    result
+}
+
+fn print(report: &Report) {
+    println!(
+        "Format:     {}",
+        report.format.as_deref().unwrap_or_default()
+    );
+    match report.bit_depth {
+        Some(bits) => println!(
+            "Color:      {}, {} bit",
+            report.color.as_deref().unwrap_or_default(),
+            bits
+        ),
+        None => println!(
+            "Content:    {}",
+            report.color.as_deref().unwrap_or_default()
+        ),
+    }
+    if let (Some(width), Some(height)) = (report.width, report.height) {
+        println!("Size:       {}x{}", width, height);
+    }
+
+    for method in report.methods.iter().flatten() {
+        println!(
+            "{:<5} capacity {} bytes ({} bits)",
+            method.method, method.capacity_bytes, method.capacity_bits
+        );
+        let status = match method.payload {
+            PayloadStatus::Found => "payload found, CRC ok",
+            PayloadStatus::None => "no payload found with this key and options",
+            PayloadStatus::CrcMismatch => "CRC mismatch: wrong key, or damaged payload",
+            PayloadStatus::Malformed => "CRC ok, but the kiki header is malformed",
+            PayloadStatus::Unreadable => "cannot be read with this method",
+        };
+        println!("      {}", status);
+        if let Some(score) = method.score {
+            println!("      watermark score {:.3}", score);
+        }
+        if let Some(blocks) = method.blocks {
+            println!(
+                "      {} of {} blocks complex enough",
+                blocks.qualified, blocks.total
+            );
+        }
+        if let Some(bytes) = method.payload_bytes {
+            println!("      {} bytes of data", bytes);
+        }
+        match &method.header {
+            Some(header) => println!(
+                "      kiki header: version {}, flags {:#010b}{}{}",
+                header.version,
+                header.flags,
+                if header.flags & payload::FLAG_FILE_META != 0 {
+                    " metadata"
+                } else {
+                    ""
+                },
+                if header.flags & payload::FLAG_ARCHIVE != 0 {
+                    " archive"
+                } else {
+                    ""
+                },
+            ),
+            None if method.payload == PayloadStatus::Found => println!("      no kiki header"),
+            None => {}
+        }
+        if let Some(name) = method.metadata.as_ref().and_then(|m| m.name.as_ref()) {
+            println!("      stored name: {}", name);
+        }
+        if matches!(
+            method.payload,
+            PayloadStatus::Malformed | PayloadStatus::Unreadable
+        ) {
+            if let Some(message) = &method.message {
+                println!("      {}", message);
+            }
+        }
+    }
+}
+
+/// Reports the format of the carrier and, for each method that applies to
+/// it, its capacity and whether it holds a payload. Nothing is written but
+/// the report.
+pub fn inspect(
+    image_path: &Path,
+    method: Option<&String>,
+    key: Option<&String>,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    if imageio::is_std(image_path) {
+        return Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            "inspect reads the file once per method, it cannot read stdin.",
+        ));
+    }
+
+    let data = read_input(image_path)?;
+    let description = describe(&data, format).context(Stage::Decode, image_path)?;
+    report.format = Some(description.format);
+    report.color = Some(description.color);
+    report.bit_depth = description.bit_depth;
+    if let Some((width, height)) = description.dimensions {
+        report.width = Some(width);
+        report.height = Some(height);
+    }
+
+    let mut methods = supported_methods(&description.extension)?;
+    if let Some(method) = method {
+        if !methods.contains(&method.as_str()) {
+            return Err(AppError::new(
+                AppErrorKind::UnsupportedMethod,
+                format!(
+                    "{} does not apply to {} files.",
+                    method, description.extension
+                ),
+            ));
+        }
+        methods.retain(|m| m == method);
+    }
+
+    report.methods = Some(
+        methods
+            .into_iter()
+            .map(|m| inspect_method(image_path, m, key, &options, format))
+            .collect(),
+    );
+
+    if !report.json {
+        print(report);
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+pub mod apng;
+pub mod archive;
+pub mod batch;
+pub mod chunks;
+pub mod embed;
+pub mod errors;
+pub mod extract;
+pub mod fingerprint;
+pub mod imageio;
+pub mod inspect;
+pub mod metadata;
+pub mod methods;
+pub mod pages;
+pub mod payload;
+pub mod rank;
+pub mod report;
@@ -1,26 +1,341 @@
-use clap::{Arg, ArgAction, Command};
-use embed::embed;
-use errors::AppError;
-use extract::extract;
-use std::fs::File;
-use std::io::{self, Read};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use image::ImageFormat;
+use kiki::batch::BatchOptions;
+use kiki::embed::{embed, load_secret};
+use kiki::errors::{AppError, AppErrorKind};
+use kiki::extract::{extract, ExtractOutput};
+use kiki::fingerprint::{fingerprint, trace};
+use kiki::imageio::parse_format;
+use kiki::inspect::inspect;
+use kiki::methods::data::{parse_size, LengthPolicy};
+use kiki::rank::rank;
+use kiki::report::Report;
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::Instant;
 
-mod embed;
-mod errors;
-mod extract;
 mod help_text;
-mod methods;
 
-fn get_secret(fd_secret: &String) -> Result<Vec<u8>, AppError> {
-    let mut secret = Vec::<u8>::new();
+fn get_format(sub: &ArgMatches) -> Result<Option<ImageFormat>, AppError> {
+    sub.get_one::<String>("format")
+        .map(|f| parse_format(f))
+        .transpose()
+}
+
+fn get_options(sub: &ArgMatches) -> Vec<&String> {
+    sub.get_many::<String>("options")
+        .map(|v| v.collect::<Vec<_>>())
+        .unwrap_or_default()
+}
+
+fn run_embed(sub: &ArgMatches, report: &mut Report) -> Result<(), AppError> {
+    let image = PathBuf::from(sub.get_one::<String>("image").unwrap());
+    let output = PathBuf::from(sub.get_one::<String>("output").unwrap());
+
+    let method = sub.get_one::<String>("method");
+    let key = sub.get_one::<String>("key");
+
+    let verbose = sub.get_flag("verbose");
+
+    let fd_secrets = sub
+        .get_many::<String>("secret")
+        .map(|v| v.map(|s| s.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let format = get_format(sub)?;
+    let options = get_options(sub);
 
-    match fd_secret.as_str() {
-        "-" => io::stdin().read_to_end(&mut secret)?,
-        _ => File::open(PathBuf::from(fd_secret))?.read_to_end(&mut secret)?,
+    report.image = Some(image.display().to_string());
+    report.output = Some(output.display().to_string());
+    report.method = method.cloned();
+    report.options = options.iter().map(|o| o.to_string()).collect();
+
+    if kiki::batch::is_batch(&image.to_string_lossy()) {
+        let opts = BatchOptions {
+            method,
+            key,
+            verbose,
+            options,
+            format,
+            template: sub.get_one::<String>("template"),
+            jobs: sub.get_one::<usize>("jobs").copied().unwrap_or_default(),
+        };
+        let manifest = sub.get_one::<String>("manifest").map(PathBuf::from);
+        return kiki::batch::embed_batch(
+            &image.to_string_lossy(),
+            &output,
+            &fd_secrets,
+            manifest.as_deref(),
+            sub.get_flag("metadata"),
+            !sub.get_flag("no-verify"),
+            sub.get_flag("strip-metadata"),
+            opts,
+            report,
+        );
+    }
+
+    if matches!(fd_secrets.as_slice(), [] | ["-"]) && kiki::imageio::is_std(&image) {
+        return Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            "The image and the secret cannot both be read from stdin.",
+        ));
+    }
+
+    let start = Instant::now();
+    let secret = load_secret(&fd_secrets, sub.get_flag("metadata"), report)?;
+    report.time("read_secret", start);
+
+    if verbose {
+        eprintln!("Kiki embed");
+        eprintln!("Image:        {}", image.to_str().unwrap());
+        eprintln!("Output:       {}", output.to_str().unwrap());
+        eprintln!("Secret:       {} bytes", secret.len());
+        match method {
+            Some(method) => eprintln!("Method:       {}", method),
+            None => eprintln!("Method will be determined by filetype."),
+        }
+        match key {
+            Some(key) => eprintln!("Key:          {}", key),
+            None => eprintln!("Key not specified"),
+        }
+        eprintln!("Options:      {:?}", options);
+        if let Some(meta) = &report.metadata {
+            eprintln!("Metadata:     {:?}", meta);
+        }
+    }
+
+    let verify = !sub.get_flag("no-verify");
+    let strip_metadata = sub.get_flag("strip-metadata");
+    embed(
+        &image,
+        &output,
+        &secret,
+        method,
+        key,
+        verbose,
+        options,
+        format,
+        verify,
+        strip_metadata,
+        report,
+    )
+}
+
+fn run_extract(sub: &ArgMatches, report: &mut Report) -> Result<(), AppError> {
+    let image = PathBuf::from(sub.get_one::<String>("image").unwrap());
+
+    let output = match sub.get_one::<String>("output").map(|s| s.as_str()) {
+        Some("-") => ExtractOutput::Stdout,
+        Some(path) => ExtractOutput::Path(PathBuf::from(path)),
+        None => ExtractOutput::Original,
     };
-    Ok(secret)
+
+    let method = sub.get_one::<String>("method");
+    let key = sub.get_one::<String>("key");
+
+    let verbose = sub.get_flag("verbose");
+
+    let options = get_options(sub);
+
+    report.image = Some(image.display().to_string());
+    report.output = match &output {
+        ExtractOutput::Path(path) => Some(path.display().to_string()),
+        ExtractOutput::Stdout => Some("-".to_string()),
+        ExtractOutput::Original => None,
+    };
+    report.method = method.cloned();
+    report.options = options.iter().map(|o| o.to_string()).collect();
+
+    if verbose {
+        eprintln!("Kiki extract");
+        eprintln!("Image:        {}", image.to_str().unwrap());
+        match &output {
+            ExtractOutput::Path(output) => {
+                eprintln!("Output:       {}", output.to_str().unwrap())
+            }
+            ExtractOutput::Stdout => eprintln!("Output to stdout"),
+            ExtractOutput::Original => eprintln!("Output to the stored filename"),
+        }
+        match method {
+            Some(method) => eprintln!("Method:       {}", method),
+            None => eprintln!("Method will be inferred"),
+        }
+        match key {
+            Some(key) => eprintln!("Key:          {}", key),
+            None => eprintln!("Key not specified"),
+        }
+        eprintln!("Options:      {:?}", options);
+    }
+    let list = sub.get_flag("list");
+    let format = get_format(sub)?;
+
+    let mut policy = LengthPolicy::default();
+    if sub.get_flag("yes") {
+        policy.answer = Some(true);
+    } else if sub.get_flag("no") {
+        policy.answer = Some(false);
+    }
+    if let Some(limit) = sub.get_one::<String>("limit") {
+        policy.limit = parse_size(limit).ok_or_else(|| {
+            AppError::new(
+                AppErrorKind::InvalidOption,
+                format!("{} is not a valid size.", limit),
+            )
+        })?;
+    }
+
+    if kiki::batch::is_batch(&image.to_string_lossy()) {
+        let output_dir = match output {
+            ExtractOutput::Path(dir) => dir,
+            ExtractOutput::Original => PathBuf::from("."),
+            ExtractOutput::Stdout => {
+                return Err(AppError::new(
+                    AppErrorKind::InvalidOption,
+                    "Batch mode needs an output directory.",
+                ))
+            }
+        };
+        let opts = BatchOptions {
+            method,
+            key,
+            verbose,
+            options,
+            format,
+            template: sub.get_one::<String>("template"),
+            jobs: sub.get_one::<usize>("jobs").copied().unwrap_or_default(),
+        };
+        return kiki::batch::extract_batch(
+            &image.to_string_lossy(),
+            &output_dir,
+            opts,
+            policy,
+            report,
+        );
+    }
+
+    extract(
+        &image, output, method, key, verbose, options, list, format, policy, report,
+    )
+}
+
+fn run_inspect(sub: &ArgMatches, report: &mut Report) -> Result<(), AppError> {
+    let image = PathBuf::from(sub.get_one::<String>("image").unwrap());
+    let method = sub.get_one::<String>("method");
+    let key = sub.get_one::<String>("key");
+    let options = get_options(sub);
+    let format = get_format(sub)?;
+
+    report.image = Some(image.display().to_string());
+    report.method = method.cloned();
+    report.options = options.iter().map(|o| o.to_string()).collect();
+
+    inspect(&image, method, key, options, format, report)
+}
+
+fn run_fingerprint(sub: &ArgMatches, report: &mut Report) -> Result<(), AppError> {
+    let cover = PathBuf::from(sub.get_one::<String>("cover").unwrap());
+    let output = PathBuf::from(sub.get_one::<String>("output").unwrap());
+    let recipients = PathBuf::from(sub.get_one::<String>("recipients").unwrap());
+    let options = get_options(sub);
+
+    report.image = Some(cover.display().to_string());
+    report.output = Some(output.display().to_string());
+    report.options = options.iter().map(|o| o.to_string()).collect();
+
+    let opts = BatchOptions {
+        method: None,
+        key: sub.get_one::<String>("key"),
+        verbose: sub.get_flag("verbose"),
+        options,
+        format: get_format(sub)?,
+        template: sub.get_one::<String>("template"),
+        jobs: sub.get_one::<usize>("jobs").copied().unwrap_or_default(),
+    };
+    fingerprint(
+        &cover,
+        &output,
+        &recipients,
+        !sub.get_flag("no-verify"),
+        opts,
+        report,
+    )
+}
+
+fn run_trace(sub: &ArgMatches, report: &mut Report) -> Result<(), AppError> {
+    let image = PathBuf::from(sub.get_one::<String>("image").unwrap());
+    let recipients = PathBuf::from(sub.get_one::<String>("recipients").unwrap());
+    let key = sub.get_one::<String>("key");
+    let options = get_options(sub);
+    let format = get_format(sub)?;
+
+    report.image = Some(image.display().to_string());
+    report.options = options.iter().map(|o| o.to_string()).collect();
+
+    trace(
+        &image,
+        &recipients,
+        key,
+        sub.get_flag("verbose"),
+        options,
+        format,
+        report,
+    )
+}
+
+fn run_rank(sub: &ArgMatches, report: &mut Report) -> Result<(), AppError> {
+    let covers = sub.get_one::<String>("covers").unwrap();
+    let output = sub.get_one::<String>("embed").map(PathBuf::from);
+    let fd_secrets = sub
+        .get_many::<String>("secret")
+        .map(|v| v.map(|s| s.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let options = get_options(sub);
+
+    report.image = Some(covers.clone());
+    report.method = Some("LSB".to_string());
+    report.options = options.iter().map(|o| o.to_string()).collect();
+
+    let start = Instant::now();
+    let secret = load_secret(&fd_secrets, false, report)?;
+    report.time("read_secret", start);
+
+    let opts = BatchOptions {
+        method: sub.get_one::<String>("method"),
+        key: sub.get_one::<String>("key"),
+        verbose: sub.get_flag("verbose"),
+        options,
+        format: get_format(sub)?,
+        template: None,
+        jobs: sub.get_one::<usize>("jobs").copied().unwrap_or_default(),
+    };
+    rank(
+        covers,
+        &secret,
+        output.as_deref(),
+        !sub.get_flag("no-verify"),
+        sub.get_flag("strip-metadata"),
+        opts,
+        report,
+    )
+}
+
+/// Prints the outcome of the command and exits with the matching code.
+fn finish(mut report: Report, result: Result<(), AppError>, stdout_used: bool) -> ! {
+    let code = match &result {
+        Ok(()) => 0,
+        Err(err) => err.exit_code(),
+    };
+
+    if report.json {
+        match &result {
+            Ok(()) => report.success = true,
+            Err(err) => report.error(err),
+        }
+        report.print(stdout_used);
+    } else if let Err(err) = result {
+        eprintln!("{}", err);
+    }
+    exit(code);
 }
 
 fn main() {
@@ -28,6 +343,13 @@ fn main() {
         .version("0.1.0")
         .about("Steganography tool to embed into and retrieve data from image files.")
         .author("Ramsteak")
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help(help_text::JSON),
+        )
         .subcommand(
             Command::new("embed")
                 .arg(
@@ -42,7 +364,12 @@ fn main() {
                         .index(2)
                         .help(help_text::EMBED_OUTPUT),
                 )
-                .arg(Arg::new("secret").index(3).help(help_text::EMBED_SECRET))
+                .arg(
+                    Arg::new("secret")
+                        .index(3)
+                        .num_args(1..)
+                        .help(help_text::EMBED_SECRET),
+                )
                 .arg(
                     Arg::new("method")
                         .short('m')
@@ -63,6 +390,48 @@ fn main() {
                         .long("options")
                         .num_args(1..)
                         .help(help_text::OPTIONS),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help(help_text::FORMAT),
+                )
+                .arg(
+                    Arg::new("manifest")
+                        .long("manifest")
+                        .help(help_text::MANIFEST),
+                )
+                .arg(
+                    Arg::new("template")
+                        .short('t')
+                        .long("template")
+                        .help(help_text::TEMPLATE),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(clap::value_parser!(usize))
+                        .help(help_text::JOBS),
+                )
+                .arg(
+                    Arg::new("metadata")
+                        .long("metadata")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::METADATA),
+                )
+                .arg(
+                    Arg::new("no-verify")
+                        .long("no-verify")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::NO_VERIFY),
+                )
+                .arg(
+                    Arg::new("strip-metadata")
+                        .long("strip-metadata")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::STRIP_METADATA),
                 ),
         )
         .subcommand(
@@ -94,6 +463,240 @@ fn main() {
                         .long("options")
                         .num_args(1..)
                         .help(help_text::OPTIONS),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help(help_text::FORMAT),
+                )
+                .arg(
+                    Arg::new("template")
+                        .short('t')
+                        .long("template")
+                        .help(help_text::TEMPLATE),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(clap::value_parser!(usize))
+                        .help(help_text::JOBS),
+                )
+                .arg(
+                    Arg::new("list")
+                        .short('l')
+                        .long("list")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::LIST),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("no")
+                        .help(help_text::YES),
+                )
+                .arg(
+                    Arg::new("no")
+                        .long("no")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::NO),
+                )
+                .arg(Arg::new("limit").long("limit").help(help_text::LIMIT)),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .arg(
+                    Arg::new("image")
+                        .required(true)
+                        .index(1)
+                        .help(help_text::INSPECT_IMAGE),
+                )
+                .arg(
+                    Arg::new("method")
+                        .short('m')
+                        .long("method")
+                        .help(help_text::METHOD),
+                )
+                .arg(Arg::new("key").short('k').long("key").help(help_text::KEY))
+                .arg(
+                    Arg::new("options")
+                        .short('o')
+                        .long("options")
+                        .num_args(1..)
+                        .help(help_text::OPTIONS),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help(help_text::FORMAT),
+                ),
+        )
+        .subcommand(
+            Command::new("fingerprint")
+                .arg(
+                    Arg::new("cover")
+                        .required(true)
+                        .index(1)
+                        .help(help_text::FINGERPRINT_COVER),
+                )
+                .arg(
+                    Arg::new("output")
+                        .required(true)
+                        .index(2)
+                        .help(help_text::FINGERPRINT_OUTPUT),
+                )
+                .arg(
+                    Arg::new("recipients")
+                        .required(true)
+                        .index(3)
+                        .help(help_text::RECIPIENTS),
+                )
+                .arg(Arg::new("key").short('k').long("key").help(help_text::KEY))
+                .arg(
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::VERBOSE),
+                )
+                .arg(
+                    Arg::new("options")
+                        .short('o')
+                        .long("options")
+                        .num_args(1..)
+                        .help(help_text::OPTIONS),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help(help_text::FORMAT),
+                )
+                .arg(
+                    Arg::new("template")
+                        .short('t')
+                        .long("template")
+                        .help(help_text::FINGERPRINT_TEMPLATE),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(clap::value_parser!(usize))
+                        .help(help_text::JOBS),
+                )
+                .arg(
+                    Arg::new("no-verify")
+                        .long("no-verify")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::NO_VERIFY),
+                ),
+        )
+        .subcommand(
+            Command::new("trace")
+                .arg(
+                    Arg::new("image")
+                        .required(true)
+                        .index(1)
+                        .help(help_text::TRACE_IMAGE),
+                )
+                .arg(
+                    Arg::new("recipients")
+                        .required(true)
+                        .index(2)
+                        .help(help_text::RECIPIENTS),
+                )
+                .arg(Arg::new("key").short('k').long("key").help(help_text::KEY))
+                .arg(
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::VERBOSE),
+                )
+                .arg(
+                    Arg::new("options")
+                        .short('o')
+                        .long("options")
+                        .num_args(1..)
+                        .help(help_text::OPTIONS),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help(help_text::FORMAT),
+                ),
+        )
+        .subcommand(
+            Command::new("rank")
+                .arg(
+                    Arg::new("covers")
+                        .required(true)
+                        .index(1)
+                        .help(help_text::RANK_COVERS),
+                )
+                .arg(
+                    Arg::new("secret")
+                        .index(2)
+                        .num_args(1..)
+                        .help(help_text::EMBED_SECRET),
+                )
+                .arg(
+                    Arg::new("method")
+                        .short('m')
+                        .long("method")
+                        .help(help_text::METHOD),
+                )
+                .arg(Arg::new("key").short('k').long("key").help(help_text::KEY))
+                .arg(
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::VERBOSE),
+                )
+                .arg(
+                    Arg::new("options")
+                        .short('o')
+                        .long("options")
+                        .num_args(1..)
+                        .help(help_text::OPTIONS),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .help(help_text::FORMAT),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(clap::value_parser!(usize))
+                        .help(help_text::JOBS),
+                )
+                .arg(
+                    Arg::new("embed")
+                        .short('e')
+                        .long("embed")
+                        .help(help_text::RANK_EMBED),
+                )
+                .arg(
+                    Arg::new("no-verify")
+                        .long("no-verify")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::NO_VERIFY),
+                )
+                .arg(
+                    Arg::new("strip-metadata")
+                        .long("strip-metadata")
+                        .action(ArgAction::SetTrue)
+                        .help(help_text::STRIP_METADATA),
                 ),
         )
         .after_help(help_text::AFTER_HELP)
@@ -101,94 +704,49 @@ fn main() {
 
     match cmd.subcommand() {
         Some(("embed", sub)) => {
-            let image = PathBuf::from(sub.get_one::<String>("image").unwrap());
-            let output = PathBuf::from(sub.get_one::<String>("output").unwrap());
-
-            let method = sub.get_one::<String>("method");
-            let key = sub.get_one::<String>("key");
-
-            let verbose = sub.get_flag("verbose");
-
-            let fd_secret = sub.get_one::<String>("secret").unwrap();
-
-            let secret = match get_secret(fd_secret) {
-                Ok(secret) => secret,
-                Err(err) => {
-                    eprintln!("Error in reading secret file {}: {}", fd_secret, err);
-                    exit(-1);
-                }
-            };
-
-            let options = sub
-                .get_many::<String>("options")
-                .map(|v| v.collect::<Vec<_>>())
-                .unwrap_or_default();
-
-            if verbose {
-                println!("Kiki embed");
-                println!("Image:        {}", image.to_str().unwrap());
-                println!("Output:       {}", output.to_str().unwrap());
-                println!("Secret:       {} bytes", secret.len());
-                match method {
-                    Some(method) => println!("Method:       {}", method),
-                    None => println!("Method will be determined by filetype."),
-                }
-                match key {
-                    Some(key) => println!("Key:          {}", key),
-                    None => println!("Key not specified"),
-                }
-                println!("Options:      {:?}", options);
-            }
+            let mut report = Report::new("embed");
+            report.json = sub.get_flag("json");
+            let result = run_embed(sub, &mut report);
 
-            if let Err(err) = embed(&image, &output, &secret, method, key, verbose, options) {
-                eprintln!("{}", err);
-                exit(-1);
-            }
+            let stdout_used = sub.get_one::<String>("output").is_some_and(|o| o == "-");
+            finish(report, result, stdout_used);
         }
         Some(("extract", sub)) => {
-            let image = PathBuf::from(sub.get_one::<String>("image").unwrap());
-
-            let output = sub
-                .get_one::<String>("output")
-                .filter(|&s| s != "-")
-                .map(|s| PathBuf::from(s));
-
-            let method = sub.get_one::<String>("method");
-            let key = sub.get_one::<String>("key");
-
-            let verbose = sub.get_flag("verbose");
-
-            let options = sub
-                .get_many::<String>("options")
-                .map(|v| v.collect::<Vec<_>>())
-                .unwrap_or_default();
-
-            if verbose {
-                println!("Kiki extract");
-                println!("Image:        {}", image.to_str().unwrap());
-                match &output {
-                    Some(output) => println!("Output:       {}", output.to_str().unwrap()),
-                    None => println!("Output to stdout"),
-                }
-                match method {
-                    Some(method) => println!("Method:       {}", method),
-                    None => println!("Method will be inferred"),
-                }
-                match key {
-                    Some(key) => println!("Key:          {}", key),
-                    None => println!("Key not specified"),
-                }
-                println!("Options:      {:?}", options);
-            }
-            let output_ref = output.as_ref();
-            if let Err(err) = extract(&image, output_ref, method, key, verbose, options) {
-                eprintln!("{}", err);
-                exit(-1);
-            };
+            let mut report = Report::new("extract");
+            report.json = sub.get_flag("json");
+            let result = run_extract(sub, &mut report);
+
+            let stdout_used =
+                !sub.get_flag("list") && sub.get_one::<String>("output").is_none_or(|o| o == "-");
+            finish(report, result, stdout_used);
+        }
+        Some(("inspect", sub)) => {
+            let mut report = Report::new("inspect");
+            report.json = sub.get_flag("json");
+            let result = run_inspect(sub, &mut report);
+            finish(report, result, false);
+        }
+        Some(("fingerprint", sub)) => {
+            let mut report = Report::new("fingerprint");
+            report.json = sub.get_flag("json");
+            let result = run_fingerprint(sub, &mut report);
+            finish(report, result, false);
+        }
+        Some(("trace", sub)) => {
+            let mut report = Report::new("trace");
+            report.json = sub.get_flag("json");
+            let result = run_trace(sub, &mut report);
+            finish(report, result, false);
+        }
+        Some(("rank", sub)) => {
+            let mut report = Report::new("rank");
+            report.json = sub.get_flag("json");
+            let result = run_rank(sub, &mut report);
+            finish(report, result, false);
         }
         _ => {
-            eprintln!("No subcommand used. Specify either 'embed' or 'extract'.");
-            exit(-1);
+            eprintln!("No subcommand used. Specify 'embed', 'extract', 'inspect', 'fingerprint', 'trace' or 'rank'.");
+            exit(2);
         }
     }
 }
@@ -0,0 +1,410 @@
+use crate::chunks::{self, Chunk};
+use crate::errors::{AppError, Context, Stage};
+use crate::methods::chunk;
+use image::{ImageDecoder, ImageReader};
+use std::io::Cursor;
+use std::path::Path;
+
+// Encoding the pixels anew drops everything else the cover held: its color
+// profile, EXIF and XMP data, text and physical size. They are read from the
+// cover before embedding and written back into the output file:
+// - from a PNG cover into a PNG output, every ancillary chunk that still holds
+//   for the new image, on the same side of the image data;
+// - otherwise the ICC profile, EXIF and XMP data, into PNG, WebP and JPEG
+//   outputs.
+// A chunk that may hold a CHUNK payload is never carried: the output would
+// still give away the old payload.
+// Chunks the output already has are left alone, as the methods that rewrite
+// the cover file itself (PAL, CHUNK, animations) keep them.
+
+/// PNG chunks that must come before the palette.
+const BEFORE_PALETTE: [&[u8; 4]; 8] = [
+    b"iCCP", b"sRGB", b"gAMA", b"cHRM", b"cICP", b"mDCV", b"cLLI", b"sBIT",
+];
+
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+/// The most data a JPEG segment holds, besides its length.
+const SEGMENT_MAX: usize = 0xFFFF - 2;
+
+/// The metadata of a cover.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// The header of a PNG cover.
+    header: Option<Vec<u8>>,
+    /// The ancillary chunks of a PNG cover, each with whether it came before
+    /// the image data.
+    chunks: Vec<(Chunk, bool)>,
+    icc: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the metadata of the cover file. What cannot be read is left out:
+    /// the method reports covers it cannot decode.
+    pub fn read(path: &Path) -> Result<Self, AppError> {
+        let data = std::fs::read(path).context(Stage::Read, path)?;
+        let mut meta = Metadata::default();
+
+        if let Ok(all) = chunks::split(&data) {
+            let mut before = true;
+            for chunk in &all {
+                before &= !chunk.is(b"IDAT");
+                if chunk.kind[0].is_ascii_lowercase() {
+                    meta.chunks.push((chunk.clone(), before));
+                }
+            }
+            meta.header = all.first().map(|header| header.data.clone());
+        }
+
+        if let Ok(mut decoder) = ImageReader::new(Cursor::new(&data))
+            .with_guessed_format()
+            .map_err(image::ImageError::IoError)
+            .and_then(|reader| reader.into_decoder())
+        {
+            meta.icc = decoder.icc_profile().ok().flatten();
+            meta.exif = decoder.exif_metadata().ok().flatten().map(|exif| {
+                match exif.strip_prefix(b"Exif\0\0") {
+                    Some(tiff) => tiff.to_vec(),
+                    None => exif,
+                }
+            });
+            meta.xmp = decoder.xmp_metadata().ok().flatten();
+        }
+        Ok(meta)
+    }
+
+    /// Writes the metadata into the output file, and returns how many chunks
+    /// or segments it added.
+    pub fn restore(&self, path: &Path) -> Result<usize, AppError> {
+        let data = std::fs::read(path).context(Stage::Save, path)?;
+        let restored = if chunks::is_png(&data) {
+            self.restore_png(&data)?
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            self.restore_webp(&data)
+        } else if data.starts_with(&[0xFF, 0xD8]) {
+            self.restore_jpeg(&data)
+        } else {
+            None
+        };
+
+        match restored {
+            Some((data, count)) => {
+                std::fs::write(path, data).context(Stage::Save, path)?;
+                Ok(count)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// The chunks to add to a PNG output of the given header.
+    fn png_chunks(&self, header: &[u8]) -> Vec<(Chunk, bool)> {
+        if let Some(cover) = &self.header {
+            return self
+                .chunks
+                .iter()
+                .filter(|(chunk, _)| carries(chunk, cover, header))
+                .cloned()
+                .collect();
+        }
+
+        let mut chunks = Vec::new();
+        if let Some(icc) = &self.icc {
+            let mut data = b"ICC profile\0\0".to_vec();
+            data.extend(zlib_stored(icc));
+            chunks.push((Chunk::new(b"iCCP", data), true));
+        }
+        if let Some(exif) = &self.exif {
+            chunks.push((Chunk::new(b"eXIf", exif.clone()), true));
+        }
+        if let Some(xmp) = &self.xmp {
+            // Uncompressed, with neither language nor translated keyword.
+            let mut data = XMP_KEYWORD.to_vec();
+            data.extend([0, 0, 0, 0, 0]);
+            data.extend_from_slice(xmp);
+            chunks.push((Chunk::new(b"iTXt", data), false));
+        }
+        chunks
+    }
+
+    fn restore_png(&self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, AppError> {
+        let mut out = chunks::split(data)?;
+        let present = out.iter().map(|c| c.kind).collect::<Vec<_>>();
+        let carried = self
+            .png_chunks(&out[0].data)
+            .into_iter()
+            .filter(|(chunk, _)| !present.contains(&chunk.kind))
+            .collect::<Vec<_>>();
+        if carried.is_empty() {
+            return Ok(None);
+        }
+
+        let count = carried.len();
+        for (chunk, before) in carried {
+            let at = if before {
+                let palette = BEFORE_PALETTE.contains(&&chunk.kind);
+                out.iter()
+                    .position(|c| c.is(b"IDAT") || c.is(b"fcTL") || (palette && c.is(b"PLTE")))
+            } else {
+                out.iter().position(|c| c.is(b"IEND"))
+            };
+            out.insert(at.unwrap_or(out.len()), chunk);
+        }
+        Ok(Some((chunks::join(&out), count)))
+    }
+
+    /// Turns the simple lossless file the encoder writes into an extended
+    /// one, which holds metadata.
+    fn restore_webp(&self, data: &[u8]) -> Option<(Vec<u8>, usize)> {
+        if data.get(12..16) != Some(b"VP8L") || data.len() < 25 {
+            return None;
+        }
+        let extras = [
+            (b"ICCP", &self.icc, 0x20),
+            (b"EXIF", &self.exif, 0x08),
+            (b"XMP ", &self.xmp, 0x04),
+        ];
+        let count = extras.iter().filter(|(_, d, _)| d.is_some()).count();
+        if count == 0 {
+            return None;
+        }
+
+        let bits = u32::from_le_bytes(data[21..25].try_into().unwrap());
+        let (width, height) = (bits & 0x3FFF, bits >> 14 & 0x3FFF);
+        let alpha = if bits >> 28 & 1 == 1 { 0x10 } else { 0 };
+        let flags = extras
+            .iter()
+            .filter(|(_, d, _)| d.is_some())
+            .fold(alpha, |acc, (_, _, flag)| acc | flag);
+
+        let mut header = vec![flags, 0, 0, 0];
+        header.extend(&width.to_le_bytes()[..3]);
+        header.extend(&height.to_le_bytes()[..3]);
+
+        let mut body = b"WEBP".to_vec();
+        riff_chunk(&mut body, b"VP8X", &header);
+        if let Some(icc) = &self.icc {
+            riff_chunk(&mut body, b"ICCP", icc);
+        }
+        body.extend_from_slice(&data[12..]);
+        for (kind, extra, _) in &extras[1..] {
+            if let Some(extra) = extra {
+                riff_chunk(&mut body, kind, extra);
+            }
+        }
+
+        let mut out = b"RIFF".to_vec();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        Some((out, count))
+    }
+
+    fn restore_jpeg(&self, data: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let mut segments = Vec::new();
+        if let Some(exif) = self.exif.as_ref().filter(|e| e.len() + 6 <= SEGMENT_MAX) {
+            segments.push((0xE1, [b"Exif\0\0".as_slice(), exif].concat()));
+        }
+        if let Some(xmp) = self
+            .xmp
+            .as_ref()
+            .filter(|x| x.len() + XMP_NAMESPACE.len() <= SEGMENT_MAX)
+        {
+            segments.push((0xE1, [XMP_NAMESPACE, xmp].concat()));
+        }
+        if let Some(icc) = &self.icc {
+            // Split over numbered segments, at most 255 of them.
+            let parts = icc.chunks(SEGMENT_MAX - ICC_MARKER.len() - 2);
+            let total = parts.len();
+            if total <= 255 {
+                for (idx, part) in parts.enumerate() {
+                    let mut segment = ICC_MARKER.to_vec();
+                    segment.extend([idx as u8 + 1, total as u8]);
+                    segment.extend_from_slice(part);
+                    segments.push((0xE2, segment));
+                }
+            }
+        }
+        if segments.is_empty() {
+            return None;
+        }
+
+        // After the JFIF segment the encoder writes, if any.
+        let mut at = 2;
+        if data.get(2..4) == Some(&[0xFF, 0xE0]) {
+            at += 2 + u16::from_be_bytes(data.get(4..6)?.try_into().unwrap()) as usize;
+        }
+        let count = segments.len();
+        let mut out = data[..at].to_vec();
+        for (marker, segment) in segments {
+            out.extend([0xFF, marker]);
+            out.extend((segment.len() as u16 + 2).to_be_bytes());
+            out.extend(segment);
+        }
+        out.extend_from_slice(&data[at..]);
+        Some((out, count))
+    }
+}
+
+/// Returns true if the ancillary chunk of the cover still holds for the output
+/// of the given header. Chunks about the pixel format need the same one, and
+/// unknown chunks must be safe to copy over changed pixels. Payload chunks
+/// never hold.
+fn carries(chunk: &Chunk, cover: &[u8], output: &[u8]) -> bool {
+    match &chunk.kind {
+        _ if chunk::is_payload(chunk) => false,
+        b"acTL" | b"fcTL" | b"fdAT" => false,
+        b"sBIT" | b"bKGD" | b"tRNS" | b"hIST" | b"sPLT" => cover.get(8..10) == output.get(8..10),
+        b"iCCP" => cover.get(9).map(|c| c & 2) == output.get(9).map(|c| c & 2),
+        kind if kind[1].is_ascii_lowercase() => kind[3].is_ascii_lowercase(),
+        _ => true,
+    }
+}
+
+/// Returns true if the PNG chunk makes up the image: the critical chunks, the
+/// transparency and the animation.
+pub fn is_image_chunk(chunk: &Chunk) -> bool {
+    chunk.kind[0].is_ascii_uppercase()
+        || [b"tRNS", b"acTL", b"fcTL", b"fdAT"].contains(&&chunk.kind)
+}
+
+/// Removes the ancillary chunks of a PNG output but those that make up the
+/// image, and returns how many it removed. The other formats are written
+/// without metadata.
+pub fn strip(path: &Path) -> Result<usize, AppError> {
+    let data = std::fs::read(path).context(Stage::Save, path)?;
+    if !chunks::is_png(&data) {
+        return Ok(0);
+    }
+    let all = chunks::split(&data)?;
+    let kept = all
+        .iter()
+        .filter(|c| is_image_chunk(c))
+        .cloned()
+        .collect::<Vec<_>>();
+    let count = all.len() - kept.len();
+    if count > 0 {
+        std::fs::write(path, chunks::join(&kept)).context(Stage::Save, path)?;
+    }
+    Ok(count)
+}
+
+fn riff_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Wraps the data in a zlib stream of stored blocks, which PNG readers
+/// inflate like any other.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if data.is_empty() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(blocks.peek().is_none() as u8);
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    out.extend((b << 16 | a).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::embed;
+    use crate::methods::data::LengthPolicy;
+    use crate::methods::lsb;
+    use crate::report::Report;
+    use image::RgbImage;
+
+    #[test]
+    fn payload_chunks_are_not_carried() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_carry.png", std::process::id()));
+        let first = cover.with_extension("chunk.png");
+        let second = cover.with_extension("lsb.png");
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 90]));
+        img.save(&cover).unwrap();
+
+        let key = "key".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        let (chunk, lsb) = ("CHUNK".to_string(), "LSB".to_string());
+        let (text, private) = ("TEXT".to_string(), "CHUNK=abCd".to_string());
+        for options in [vec![], vec![&text], vec![&private]] {
+            embed(
+                &cover,
+                &first,
+                b"old secret",
+                Some(&chunk),
+                Some(&key),
+                false,
+                options.clone(),
+                None,
+                true,
+                false,
+                &mut Report::default(),
+            )
+            .unwrap();
+            embed(
+                &first,
+                &second,
+                b"new secret",
+                Some(&lsb),
+                Some(&key),
+                false,
+                vec![],
+                None,
+                true,
+                false,
+                &mut Report::default(),
+            )
+            .unwrap();
+
+            let data = std::fs::read(&second).unwrap();
+            assert!(!chunks::split(&data).unwrap().iter().any(chunk::is_payload));
+            let found = chunk::extract(
+                &second,
+                Some(&key),
+                false,
+                options,
+                &policy,
+                &mut Report::default(),
+            );
+            assert_eq!(
+                found.unwrap_err().kind,
+                crate::errors::AppErrorKind::NoPayload
+            );
+            let data = lsb::extract(
+                &second,
+                Some(&key),
+                false,
+                vec![],
+                None,
+                &policy,
+                &mut Report::default(),
+            );
+            assert_eq!(data.unwrap(), b"new secret");
+        }
+        for path in [cover, first, second] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use crate::errors::{AppError, AppErrorKind};
+use crate::imageio::save_image;
+use crate::report::{BlockReport, Report};
+use image::{DynamicImage, ImageFormat};
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{
+    check_length, package_data, pad_data, padded_len, read_bytes, slot_order, unpackage_data,
+    BitIterator, LengthPolicy,
+};
+
+use super::lsb::{load_lossless, samples_8bit, Layout};
+
+// Bit-plane complexity segmentation (Kawaguchi and Eason): every bit plane of
+// every color channel, Gray coded, is cut into 8x8 blocks, and the blocks that
+// look like noise are replaced by data. The complexity of a block is its
+// number of borders between 0 and 1 bits over the 112 possible. A data block
+// simpler than the threshold is conjugated, xored with a checkerboard, which
+// makes it as complex as the original was simple. Its first bit records
+// that, so a block holds 63 bits of data. As embedded blocks stay complex and
+// the others are untouched, extraction finds the same blocks.
+
+const BLOCK: usize = 8;
+const PLANES: usize = 8;
+/// Data bits of a block, the first bit being the conjugation flag.
+const BLOCK_BITS: usize = BLOCK * BLOCK - 1;
+const MAX_BORDERS: u32 = 2 * BLOCK as u32 * (BLOCK as u32 - 1);
+const THRESHOLD: f64 = 0.3;
+
+/// The block bits are stored in a u64, the top left pixel in the highest bit.
+const FLAG: u64 = 1 << 63;
+
+/// The checkerboard block, with the top left pixel set.
+const CHECKER: u64 = {
+    let mut checker = 0;
+    let mut idx = 0;
+    while idx < 64 {
+        if (idx / BLOCK + idx % BLOCK).is_multiple_of(2) {
+            checker |= 1 << (63 - idx);
+        }
+        idx += 1;
+    }
+    checker
+};
+
+/// The number of borders between neighbouring bits of the block.
+fn borders(block: u64) -> u32 {
+    let horizontal = (block ^ (block >> 1)) & !0x8080_8080_8080_8080;
+    let vertical = (block ^ (block >> BLOCK)) & (u64::MAX >> BLOCK);
+    horizontal.count_ones() + vertical.count_ones()
+}
+
+/// The least number of borders of a block that carries data.
+fn min_borders(options: &[&String]) -> Result<u32, AppError> {
+    let threshold = match options.iter().find_map(|o| o.strip_prefix("THRESHOLD")) {
+        None => THRESHOLD,
+        Some(value) => value
+            .strip_prefix('=')
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|v| *v > 0.0 && *v <= 0.5)
+            .ok_or_else(|| {
+                AppError::new(
+                    AppErrorKind::InvalidOption,
+                    format!(
+                        "THRESHOLD{} is not a valid threshold, it must be above 0 and at most 0.5.",
+                        value
+                    ),
+                )
+            })?,
+    };
+    Ok((threshold * MAX_BORDERS as f64).ceil() as u32)
+}
+
+/// Replaces the samples by their Gray code, in which neighbouring values
+/// differ by one bit.
+fn to_gray(buf: &mut [u8]) {
+    buf.iter_mut().for_each(|s| *s ^= *s >> 1);
+}
+
+fn from_gray(buf: &mut [u8]) {
+    buf.iter_mut().for_each(|s| {
+        *s ^= *s >> 1;
+        *s ^= *s >> 2;
+        *s ^= *s >> 4;
+    });
+}
+
+/// The 8x8 blocks of an image, one per bit plane of each color channel.
+struct Blocks {
+    width: usize,
+    layout: Layout,
+    columns: usize,
+    count: u64,
+}
+
+impl Blocks {
+    fn new(img: &DynamicImage) -> Self {
+        let color = img.color();
+        let layout = Layout::with_alpha(color.channel_count() as usize, color.has_alpha());
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let columns = width / BLOCK;
+        Blocks {
+            width,
+            layout,
+            columns,
+            count: (columns * (height / BLOCK) * layout.channels * PLANES) as u64,
+        }
+    }
+
+    /// The index of the first sample of the block and its bit plane.
+    fn locate(&self, block: u64) -> (usize, usize) {
+        let block = block as usize;
+        let plane = block % PLANES;
+        let channel = block / PLANES % self.layout.channels;
+        let tile = block / PLANES / self.layout.channels;
+        let (row, column) = (tile / self.columns * BLOCK, tile % self.columns * BLOCK);
+        (
+            (row * self.width + column) * self.layout.stride + channel,
+            plane,
+        )
+    }
+
+    fn sample(&self, first: usize, idx: usize) -> usize {
+        first + (idx / BLOCK * self.width + idx % BLOCK) * self.layout.stride
+    }
+
+    fn read(&self, buf: &[u8], block: u64) -> u64 {
+        let (first, plane) = self.locate(block);
+        (0..BLOCK * BLOCK).fold(0, |acc, idx| {
+            acc << 1 | (buf[self.sample(first, idx)] >> plane & 1) as u64
+        })
+    }
+
+    fn write(&self, buf: &mut [u8], block: u64, bits: u64) {
+        let (first, plane) = self.locate(block);
+        for idx in 0..BLOCK * BLOCK {
+            let sample = &mut buf[self.sample(first, idx)];
+            let bit = (bits >> (63 - idx) & 1) as u8;
+            *sample = *sample & !(1 << plane) | bit << plane;
+        }
+    }
+
+    /// Counts the blocks complex enough to carry data.
+    fn report(&self, buf: &[u8], min_borders: u32) -> BlockReport {
+        BlockReport {
+            qualified: (0..self.count)
+                .filter(|&block| borders(self.read(buf, block)) >= min_borders)
+                .count(),
+            total: self.count as usize,
+        }
+    }
+}
+
+/// Returns the number of bits the image can hold, framing included, and how
+/// many of its blocks qualify.
+pub fn capacity(
+    image_path: &Path,
+    format: Option<ImageFormat>,
+    options: &[&String],
+) -> Result<(usize, BlockReport), AppError> {
+    let min_borders = min_borders(options)?;
+    let mut img = load_lossless(image_path, format)?;
+    let blocks = Blocks::new(&img);
+    let buf = samples_8bit(&mut img, "BPCS")?;
+    to_gray(buf);
+    let report = blocks.report(buf, min_borders);
+    Ok((report.qualified * BLOCK_BITS, report))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn embed(
+    image_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let min_borders = min_borders(&options)?;
+    let start = Instant::now();
+    let mut img = load_lossless(image_path, None)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+
+    let blocks = Blocks::new(&img);
+    let buf = samples_8bit(&mut img, "BPCS")?;
+    to_gray(buf);
+    let qualified = blocks.report(buf, min_borders);
+    let capacity_bits = qualified.qualified * BLOCK_BITS;
+    if verbose {
+        eprintln!(
+            "Image: {} of {} blocks complex enough, {} bits",
+            qualified.qualified, qualified.total, capacity_bits
+        );
+    }
+    report.blocks = Some(qualified);
+
+    let mut data = package_data(secret_data);
+    if data.len() * 8 > capacity_bits {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
+        ));
+    };
+
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits / 8)? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
+        }
+        pad_data(&mut data, len);
+    }
+    report.capacity(data.len() * 8, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let order = slot_order(&options, key, blocks.count);
+    let mut bits = BitIterator::new(&data).peekable();
+    let mut conjugated = 0;
+    for block in (0..blocks.count).map(|idx| order.get(idx)) {
+        if bits.peek().is_none() {
+            break;
+        }
+        if borders(blocks.read(buf, block)) < min_borders {
+            continue;
+        }
+        // The last block may be only partly filled by the data.
+        let mut bits =
+            (0..BLOCK_BITS).fold(0u64, |acc, _| acc << 1 | bits.next().unwrap_or(0) as u64);
+        if borders(bits) < min_borders {
+            bits ^= CHECKER;
+            conjugated += 1;
+        }
+        blocks.write(buf, block, bits);
+    }
+    from_gray(buf);
+    report.time("embed", start);
+    if verbose {
+        eprintln!("{} data blocks conjugated", conjugated);
+    }
+
+    let start = Instant::now();
+    save_image(&img, output_path, format)?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Image saved");
+    }
+
+    Ok(())
+}
+
+pub fn extract(
+    image_path: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let min_borders = min_borders(&options)?;
+    let start = Instant::now();
+    let mut img = load_lossless(image_path, format)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+
+    let start = Instant::now();
+    let blocks = Blocks::new(&img);
+    let buf = samples_8bit(&mut img, "BPCS")?;
+    to_gray(buf);
+    let buf: &[u8] = buf;
+    let qualified = blocks.report(buf, min_borders);
+    let capacity_bits = qualified.qualified * BLOCK_BITS;
+    if verbose {
+        eprintln!(
+            "Image: {} of {} blocks complex enough, {} bits",
+            qualified.qualified, qualified.total, capacity_bits
+        );
+    }
+    report.blocks = Some(qualified);
+
+    let order = slot_order(&options, key, blocks.count);
+    let mut bits = (0..blocks.count)
+        .map(|idx| blocks.read(buf, order.get(idx)))
+        .filter(|&block| borders(block) >= min_borders)
+        .flat_map(|block| {
+            let block = if block & FLAG != 0 {
+                block ^ CHECKER
+            } else {
+                block
+            };
+            (0..BLOCK_BITS)
+                .rev()
+                .map(move |bit| (block >> bit) as u8 & 1)
+        });
+
+    let message_len = match read_bytes(&mut bits, 4).try_into() {
+        Ok(len) => u32::from_be_bytes(len) as usize,
+        Err(_) => 0,
+    };
+    if verbose {
+        eprintln!("Detected message length: {}", message_len)
+    };
+
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let secret = read_bytes(&mut bits, message_len + 4);
+    report.time("extract", start);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn conjugation_mirrors_the_complexity() {
+        assert_eq!(borders(0), 0);
+        assert_eq!(borders(CHECKER), MAX_BORDERS);
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let block = rng.gen::<u64>();
+            assert_eq!(borders(block ^ CHECKER), MAX_BORDERS - borders(block));
+        }
+        let threshold = "THRESHOLD=0.5".to_string();
+        assert_eq!(min_borders(&[&threshold]).unwrap(), MAX_BORDERS / 2);
+        for value in ["THRESHOLD=0", "THRESHOLD=0.6", "THRESHOLD"] {
+            assert!(min_borders(&[&value.to_string()]).is_err());
+        }
+    }
+
+    #[test]
+    fn gray_code_round_trip() {
+        let mut buf: Vec<u8> = (0..=255).collect();
+        to_gray(&mut buf);
+        assert!(buf.windows(2).all(|w| (w[0] ^ w[1]).count_ones() == 1));
+        from_gray(&mut buf);
+        assert!(buf.iter().enumerate().all(|(idx, &s)| s as usize == idx));
+    }
+
+    #[test]
+    fn round_trip_with_conjugated_blocks() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_bpcs.png", std::process::id()));
+        let output = cover.with_extension("out.png");
+        let mut rng = StdRng::seed_from_u64(2);
+        RgbImage::from_fn(64, 64, |_, _| image::Rgb(rng.gen()))
+            .save(&cover)
+            .unwrap();
+
+        let key = "key".to_string();
+        let threshold = "THRESHOLD=0.4".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        // Runs of zeros make blocks too simple to pass for noise.
+        let secret = [vec![0u8; 400], b"end".to_vec()].concat();
+        embed(
+            &cover,
+            &output,
+            &secret,
+            Some(&key),
+            false,
+            vec![&threshold],
+            None,
+            &mut Report::default(),
+        )
+        .unwrap();
+
+        let mut img = load_lossless(&output, None).unwrap();
+        let blocks = Blocks::new(&img);
+        let buf = samples_8bit(&mut img, "BPCS").unwrap();
+        to_gray(buf);
+        let min = min_borders(&[&threshold]).unwrap();
+        // All the data blocks but those holding the length, CRC or the end.
+        let order = slot_order(&[], Some(&key), blocks.count);
+        let used = (secret.len() + 8) * 8 / BLOCK_BITS;
+        let conjugated = (0..blocks.count)
+            .map(|idx| blocks.read(buf, order.get(idx)))
+            .filter(|&block| borders(block) >= min)
+            .take(used)
+            .filter(|&block| block & FLAG != 0)
+            .count();
+        assert!(conjugated >= used - 3, "{} of {}", conjugated, used);
+
+        let data = extract(
+            &output,
+            Some(&key),
+            false,
+            vec![&threshold],
+            None,
+            &policy,
+            &mut Report::default(),
+        );
+        assert_eq!(data.unwrap(), secret);
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+use crate::chunks::{self, Chunk};
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::{read_input, write_output};
+use crate::metadata;
+use crate::report::Report;
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{
+    check_length, package_data, pad_data, padded_len, unpackage_data, LengthPolicy,
+};
+
+// The payload is kept out of the pixels altogether: it goes into a chunk of
+// the PNG, encrypted with AES-128 in counter mode under a key derived from the
+// kiki key, so that it reads as random bytes. By default the chunk is a
+// private ancillary one, which decoders skip. With TEXT it is an iTXt chunk
+// holding the payload in base64, like a comment of some editor. Every other
+// chunk is written back as it was read. Such a chunk is easy to find for
+// anyone who lists the chunks, but no pixel changes.
+
+/// Type of the private chunk: ancillary, private and safe to copy.
+const PRIVATE_CHUNK: &[u8; 4] = b"prVt";
+const TEXT_CHUNK: &[u8; 4] = b"iTXt";
+const KEYWORD: &str = "Comment";
+const NONCE_LEN: usize = 8;
+/// Largest chunk PNG allows.
+const MAX_CHUNK: usize = (1 << 31) - 1;
+
+/// Where the payload is stored, chosen by the options.
+enum Place {
+    Private([u8; 4]),
+    Text(String),
+}
+
+impl Place {
+    fn new(options: &[&String]) -> Result<Self, AppError> {
+        if let Some(keyword) = options.iter().find_map(|o| o.strip_prefix("TEXT")) {
+            let keyword = match keyword.strip_prefix('=') {
+                Some(keyword) => keyword,
+                None if keyword.is_empty() => KEYWORD,
+                None => keyword,
+            };
+            if keyword.is_empty() || keyword.len() > 79 || !keyword.is_ascii() {
+                return Err(AppError::new(
+                    AppErrorKind::InvalidOption,
+                    format!(
+                        "{} is not a valid keyword, PNG allows 1 to 79 letters.",
+                        keyword
+                    ),
+                ));
+            }
+            return Ok(Place::Text(keyword.to_string()));
+        }
+
+        match options.iter().find_map(|o| o.strip_prefix("CHUNK=")) {
+            None => Ok(Place::Private(*PRIVATE_CHUNK)),
+            Some(kind) => match kind.as_bytes() {
+                &[a, b, c, d] if is_private(&[a, b, c, d]) => Ok(Place::Private([a, b, c, d])),
+                _ => Err(AppError::new(
+                    AppErrorKind::InvalidOption,
+                    format!(
+                        "CHUNK={} is not a private ancillary chunk type, such as {}.",
+                        kind,
+                        String::from_utf8_lossy(PRIVATE_CHUNK)
+                    ),
+                )),
+            },
+        }
+    }
+
+    /// Returns true if the chunk is where this place stores the payload.
+    fn holds(&self, chunk: &Chunk) -> bool {
+        match self {
+            Place::Private(kind) => chunk.is(kind),
+            Place::Text(keyword) => {
+                chunk.is(TEXT_CHUNK)
+                    && chunk.data.split(|&b| b == 0).next() == Some(keyword.as_bytes())
+            }
+        }
+    }
+
+    fn chunk(&self, payload: &[u8]) -> Chunk {
+        match self {
+            Place::Private(kind) => Chunk::new(kind, payload.to_vec()),
+            Place::Text(keyword) => {
+                // Uncompressed, with no language tag nor translated keyword.
+                let mut data = keyword.as_bytes().to_vec();
+                data.extend([0, 0, 0, 0, 0]);
+                data.extend(base64_encode(payload).into_bytes());
+                Chunk::new(TEXT_CHUNK, data)
+            }
+        }
+    }
+
+    fn payload(&self, chunk: &Chunk) -> Option<Vec<u8>> {
+        match self {
+            Place::Private(_) => Some(chunk.data.clone()),
+            Place::Text(keyword) => {
+                let text = chunk.data.get(keyword.len() + 1..)?;
+                // Skip the compression flag and method, the language tag and
+                // the translated keyword.
+                if text.first() != Some(&0) {
+                    return None;
+                }
+                let mut fields = text.get(2..)?.splitn(3, |&b| b == 0);
+                let (_, _, text) = (fields.next()?, fields.next()?, fields.next()?);
+                base64_decode(text)
+            }
+        }
+    }
+
+    /// The payload of the chunk, if this place holds it and it is long
+    /// enough for a nonce and the framing.
+    fn frame(&self, chunk: &Chunk) -> Option<Vec<u8>> {
+        self.holds(chunk)
+            .then(|| self.payload(chunk))?
+            .filter(|p| p.len() >= NONCE_LEN + 8)
+    }
+}
+
+/// Returns true if the chunk type is one CHUNK= accepts: lowercase first and
+/// second letters make it ancillary and private, the third one must be
+/// uppercase.
+fn is_private(kind: &[u8; 4]) -> bool {
+    kind[0].is_ascii_lowercase()
+        && kind[1].is_ascii_lowercase()
+        && kind[2].is_ascii_uppercase()
+        && kind[3].is_ascii_alphabetic()
+}
+
+/// The places extract looks into: the one the options give, or else both
+/// default ones.
+fn places(options: &[&String]) -> Result<Vec<Place>, AppError> {
+    if options
+        .iter()
+        .any(|o| o.starts_with("TEXT") || o.starts_with("CHUNK="))
+    {
+        Ok(vec![Place::new(options)?])
+    } else {
+        Ok(vec![
+            Place::Private(*PRIVATE_CHUNK),
+            Place::Text(KEYWORD.to_string()),
+        ])
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let word = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (idx, &b)| acc | (b as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= group.len() {
+                out.push(BASE64[(word >> (18 - 6 * idx) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &[u8]) -> Option<Vec<u8>> {
+    let text: Vec<u8> = text
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for group in text.chunks(4) {
+        let digits = group.iter().take_while(|&&b| b != b'=').count();
+        if digits < 2 || group[digits..].iter().any(|&b| b != b'=') {
+            return None;
+        }
+        let mut word = 0u32;
+        for (idx, b) in group[..digits].iter().enumerate() {
+            let value = BASE64.iter().position(|c| c == b)? as u32;
+            word |= value << (18 - 6 * idx);
+        }
+        out.extend(&word.to_be_bytes()[1..digits]);
+    }
+    Some(out)
+}
+
+/// Xors the data with the AES-128 keystream of the key and nonce.
+fn apply_keystream(data: &mut [u8], key: Option<&String>, nonce: &[u8]) {
+    let mut hasher = Sha256::new();
+    hasher.update(b"kiki chunk\0");
+    hasher.update(key.map(String::as_str).unwrap_or_default());
+    let cipher = Aes128::new(GenericArray::from_slice(&hasher.finalize()[..16]));
+
+    for (counter, block) in data.chunks_mut(16).enumerate() {
+        let mut stream =
+            GenericArray::clone_from_slice(&[nonce, &(counter as u64).to_be_bytes()].concat());
+        cipher.encrypt_block(&mut stream);
+        block.iter_mut().zip(stream).for_each(|(b, s)| *b ^= s);
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Chunk>, AppError> {
+    let data = read_input(path)?;
+    if !chunks::is_png(&data) {
+        return Err(AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            "CHUNK only stores data into PNG images.",
+        )
+        .with_stage(Stage::Decode)
+        .with_path(path));
+    }
+    chunks::split(&data).context(Stage::Decode, path)
+}
+
+/// Returns the number of bits a chunk can hold, framing included.
+pub fn capacity() -> usize {
+    (MAX_CHUNK - NONCE_LEN) / 4 * 3 * 8
+}
+
+/// Returns true if the PNG holds a chunk where the options would store a
+/// payload, so that extract needs no method.
+pub fn holds_payload(data: &[u8], options: &[&String]) -> bool {
+    let Ok(places) = places(options) else {
+        return false;
+    };
+    chunks::is_png(data)
+        && chunks::split(data).is_ok_and(|chunks| {
+            places
+                .iter()
+                .any(|place| chunks.iter().any(|c| place.frame(c).is_some()))
+        })
+}
+
+/// Returns true if the chunk may hold a payload of this method: a private
+/// chunk of a type CHUNK= accepts, or an iTXt chunk whose text decodes to a
+/// frame, whatever its keyword.
+pub fn is_payload(chunk: &Chunk) -> bool {
+    if is_private(&chunk.kind) {
+        return true;
+    }
+    if !chunk.is(TEXT_CHUNK) {
+        return false;
+    }
+    let keyword = chunk.data.split(|&b| b == 0).next().unwrap_or_default();
+    let place = Place::Text(String::from_utf8_lossy(keyword).into_owned());
+    place.frame(chunk).is_some()
+}
+
+/// Embeds the payload into a chunk of the cover. With `strip_metadata`, the
+/// other ancillary chunks of the cover are dropped, as with the other methods.
+#[allow(clippy::too_many_arguments)]
+pub fn embed(
+    image_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    strip_metadata: bool,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let place = Place::new(&options)?;
+    let start = Instant::now();
+    let mut chunks = load(image_path)?;
+    report.time("load", start);
+    if verbose {
+        eprintln!("Image: {} chunks", chunks.len());
+    }
+
+    let mut data = package_data(secret_data);
+    let capacity_bits = capacity();
+    if data.len() * 8 > capacity_bits {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
+        ));
+    };
+
+    if options.iter().any(|o| *o == "PAD") {
+        return Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            "A chunk has no fixed capacity to fill, use PAD=N.",
+        ));
+    }
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits / 8)? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
+        }
+        pad_data(&mut data, len);
+    }
+    report.capacity(data.len() * 8, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    apply_keystream(&mut data, key, &nonce);
+    let payload = [&nonce[..], &data].concat();
+
+    // A payload embedded earlier is replaced, text chunks that hold none are
+    // kept.
+    chunks.retain(|c| !place.holds(c) || place.payload(c).is_none());
+    if strip_metadata {
+        let count = chunks.len();
+        chunks.retain(metadata::is_image_chunk);
+        if verbose {
+            eprintln!("Metadata: {} chunks stripped", count - chunks.len());
+        }
+    }
+    let end = chunks
+        .iter()
+        .position(|c| c.is(b"IEND"))
+        .unwrap_or(chunks.len());
+    chunks.insert(end, place.chunk(&payload));
+    report.time("embed", start);
+
+    let start = Instant::now();
+    write_output(output_path, &chunks::join(&chunks))?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Image saved");
+    }
+
+    Ok(())
+}
+
+pub fn extract(
+    image_path: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let places = places(&options)?;
+    let start = Instant::now();
+    let chunks = load(image_path)?;
+    report.time("load", start);
+
+    let start = Instant::now();
+    let mut payload = places
+        .iter()
+        .find_map(|place| chunks.iter().find_map(|c| place.frame(c)))
+        .ok_or_else(|| {
+            AppError::new(
+                AppErrorKind::NoPayload,
+                "The image has no chunk holding a payload: wrong options or method, or no data is present.",
+            )
+        })?;
+    let mut data = payload.split_off(NONCE_LEN);
+    apply_keystream(&mut data, key, &payload);
+    let capacity_bits = data.len() * 8;
+
+    let message_len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    if verbose {
+        eprintln!("Detected message length: {}", message_len)
+    };
+
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let secret = data[4..message_len + 8].to_vec();
+    report.time("extract", start);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(text: &[u8]) -> Vec<u8> {
+        let mut data = b"Comment\0\0\0\0\0".to_vec();
+        data.extend_from_slice(text);
+        chunks::join(&[
+            Chunk::new(b"IHDR", vec![0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]),
+            Chunk::new(TEXT_CHUNK, data),
+            Chunk::new(b"IEND", vec![]),
+        ])
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for len in 0..10 {
+            let data = (0..len).map(|i| i as u8 * 29).collect::<Vec<_>>();
+            assert_eq!(
+                base64_decode(base64_encode(&data).as_bytes()).unwrap(),
+                data
+            );
+        }
+        assert_eq!(base64_encode(b"Made with GIMP"), "TWFkZSB3aXRoIEdJTVA=");
+        assert!(base64_decode(b"TWFkZS").is_none());
+    }
+
+    #[test]
+    fn short_comments_hold_no_payload() {
+        // Too short for a nonce and the framing.
+        let png = comment(b"TWFkZSB3aXRoIEdJTVA=");
+        assert!(!holds_payload(&png, &[]));
+        let all = chunks::split(&png).unwrap();
+        assert!(!all.iter().any(is_payload));
+
+        let png = comment(base64_encode(&[7; NONCE_LEN + 8]).as_bytes());
+        assert!(holds_payload(&png, &[]));
+        // Only where the options look.
+        let private = "CHUNK=abCd".to_string();
+        assert!(!holds_payload(&png, &[&private]));
+    }
+}
@@ -1,4 +1,10 @@
+use crate::errors::{AppError, AppErrorKind};
+use crate::report::Report;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, IsTerminal};
+
+use super::pixel::{KeyedPermutation, PixelOrder};
 
 pub fn hash_key(key: Option<&String>) -> u64 {
     let key = match key {
@@ -12,6 +18,17 @@ pub fn hash_key(key: Option<&String>) -> u64 {
     u64::from_be_bytes(res[0..8].try_into().unwrap())
 }
 
+/// The order of the slots of a carrier: consecutive ones with SEQ, otherwise
+/// a permutation of them given by the key.
+pub fn slot_order(options: &[&String], key: Option<&String>, slots: u64) -> PixelOrder {
+    if options.iter().any(|o| *o == "SEQ") {
+        PixelOrder::Sequential
+    } else {
+        let mut rng = StdRng::seed_from_u64(hash_key(key));
+        PixelOrder::Keyed(KeyedPermutation::new(slots, &mut rng))
+    }
+}
+
 pub fn package_data(secret_data: &[u8]) -> Vec<u8> {
     let len_bytes = (secret_data.len() as u32).to_be_bytes();
     let crc_bytes = crc32fast::hash(secret_data).to_be_bytes();
@@ -26,6 +43,178 @@ pub fn package_data(secret_data: &[u8]) -> Vec<u8> {
     data
 }
 
+/// Splits the CRC off the data read after the length, `message_len` bytes of
+/// message and 4 of CRC, and returns the message if they match.
+pub fn unpackage_data(
+    mut data: Vec<u8>,
+    message_len: usize,
+    verbose: bool,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let crc_read = u32::from_be_bytes(data.split_off(message_len).try_into().unwrap());
+    let crc_calc = crc32fast::hash(&data);
+
+    if verbose {
+        eprintln!("CRC32 in file    :    {}", crc_read);
+        eprintln!("Calculated CRC32 :    {}", crc_calc);
+    }
+    report.crc_stored = Some(crc_read);
+    report.crc_calculated = Some(crc_calc);
+
+    if crc_read == crc_calc {
+        Ok(data)
+    } else {
+        Err(AppError::new(
+            AppErrorKind::CRCMismatch,
+            "CRC32 mismatch: invalid data",
+        ))
+    }
+}
+
+pub fn parse_size(size: &str) -> Option<usize> {
+    let (digits, multiplier) = match size.to_ascii_uppercase().chars().last()? {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// Reads the PAD option and returns the total number of bytes that should be
+/// written into the carrier, or None if no padding was requested.
+///
+/// `PAD` fills the whole capacity, `PAD=<size>` rounds the packaged data up to
+/// the next multiple of size (e.g. `PAD=4K`), capped at the capacity.
+pub fn padded_len(
+    options: &[&String],
+    packaged_len: usize,
+    capacity: usize,
+) -> Result<Option<usize>, AppError> {
+    let option = match options.iter().find(|o| o.starts_with("PAD")) {
+        Some(option) => option.as_str(),
+        None => return Ok(None),
+    };
+
+    let bucket = match option.strip_prefix("PAD") {
+        Some("") => return Ok(Some(capacity)),
+        Some(size) => size
+            .strip_prefix('=')
+            .and_then(parse_size)
+            .filter(|&size| size > 0),
+        None => None,
+    };
+
+    match bucket {
+        Some(bucket) => Ok(Some(
+            packaged_len
+                .div_ceil(bucket)
+                .saturating_mul(bucket)
+                .min(capacity),
+        )),
+        None => Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            format!("{} is not a valid padding option.", option),
+        )),
+    }
+}
+
+/// Extends the packaged data with random bytes up to `len`. The length field
+/// of the package is untouched, so the padding is ignored on extraction.
+pub fn pad_data(data: &mut Vec<u8>, len: usize) {
+    if data.len() >= len {
+        return;
+    }
+    let start = data.len();
+    data.resize(len, 0);
+    rand::thread_rng().fill_bytes(&mut data[start..]);
+}
+
+/// Decides what happens when the detected message is larger than `limit`.
+#[derive(Clone)]
+pub struct LengthPolicy {
+    pub limit: usize,
+    /// Some(true) always continues, Some(false) always stops, None asks the
+    /// user if stdin is a terminal and stops otherwise.
+    pub answer: Option<bool>,
+    /// Whether stdin is free to be used for the prompt.
+    pub stdin_free: bool,
+}
+
+impl Default for LengthPolicy {
+    fn default() -> Self {
+        LengthPolicy {
+            limit: 1 << 20,
+            answer: None,
+            stdin_free: true,
+        }
+    }
+}
+
+/// Checks the length read from the carrier before extracting the data.
+///
+/// A length that does not fit in the carrier can only come from a wrong key or
+/// method, so it fails immediately. Large but plausible lengths are subject to
+/// the policy.
+pub fn check_length(
+    message_len: usize,
+    capacity_bits: usize,
+    policy: &LengthPolicy,
+) -> Result<(), AppError> {
+    let packaged_bits = (message_len as u64 + 8) * 8;
+    if packaged_bits > capacity_bits as u64 {
+        return Err(AppError::new(
+            AppErrorKind::NoPayload,
+            format!(
+                "The detected message length ({} bytes) exceeds the capacity of the carrier \
+                ({} bytes): wrong key or method, or no data is present.",
+                message_len,
+                (capacity_bits / 8).saturating_sub(8)
+            ),
+        ));
+    }
+
+    if message_len < policy.limit {
+        return Ok(());
+    }
+
+    let stopped = || {
+        AppError::new(
+            AppErrorKind::UserStopped,
+            format!(
+                "Operation stopped: the detected message length ({} bytes) is over the limit of {} bytes",
+                message_len, policy.limit
+            ),
+        )
+    };
+
+    match policy.answer {
+        Some(true) => return Ok(()),
+        Some(false) => return Err(stopped()),
+        None => {}
+    }
+
+    let stdin = io::stdin();
+    if !policy.stdin_free || !stdin.is_terminal() {
+        return Err(stopped());
+    }
+
+    eprintln!(
+        "The detected message length is {:.1} MB. Do you want to continue? (y/n)",
+        (message_len as f32) / 1048576.0
+    );
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+    let line = line.trim().to_lowercase();
+    match line.chars().next() {
+        Some('y') => Ok(()),
+        Some(_) | None => Err(AppError::new(
+            AppErrorKind::UserStopped,
+            "Operation stopped by user",
+        )),
+    }
+}
+
 pub struct BitIterator<'a> {
     data: &'a [u8],
     byteidx: usize,
@@ -60,6 +249,13 @@ impl<'a> Iterator for BitIterator<'a> {
     }
 }
 
+/// Reads `len` bytes from the bits, the most significant bit of each first.
+pub fn read_bytes(bits: &mut impl Iterator<Item = u8>, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|_| bits.by_ref().take(8).fold(0u8, |acc, bit| acc << 1 | bit))
+        .collect()
+}
+
 pub struct BatchIterator<I> {
     iter: I,
     size: usize,
@@ -87,7 +283,7 @@ where
             .take(self.size)
             .map(Some)
             .collect::<Vec<_>>();
-        if batch.len() == 0 {
+        if batch.is_empty() {
             None
         } else if batch.len() == self.size {
             Some(batch)
@@ -123,7 +319,93 @@ impl FromBits for u8 {
             .take(8)
             .rev()
             .enumerate()
-            .map(|(i, b)| (*b as u8) << i)
+            .map(|(i, b)| *b << i)
             .sum()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(options: &[&str]) -> Vec<String> {
+        options.iter().map(|o| o.to_string()).collect()
+    }
+
+    #[test]
+    fn sizes_parse_with_units() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("2m"), Some(2 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("4T"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn padded_len_follows_the_option() {
+        let padded = |options: &[&str], len, capacity| {
+            let options = opts(options);
+            padded_len(&options.iter().collect::<Vec<_>>(), len, capacity)
+        };
+        assert_eq!(padded(&[], 100, 1000).unwrap(), None);
+        assert_eq!(padded(&["SEQ"], 100, 1000).unwrap(), None);
+        assert_eq!(padded(&["PAD"], 100, 1000).unwrap(), Some(1000));
+        assert_eq!(padded(&["PAD=64"], 100, 1000).unwrap(), Some(128));
+        assert_eq!(padded(&["PAD=64"], 128, 1000).unwrap(), Some(128));
+        assert_eq!(padded(&["PAD=1K"], 100, 1000).unwrap(), Some(1000));
+        for option in ["PAD=0", "PAD=", "PAD=x", "PADDING"] {
+            let err = padded(&[option], 100, 1000).unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::InvalidOption);
+        }
+    }
+
+    #[test]
+    fn padding_is_ignored_on_extraction() {
+        let mut data = package_data(b"secret");
+        assert_eq!(data.len(), 6 + 8);
+        pad_data(&mut data, 64);
+        assert_eq!(data.len(), 64);
+        pad_data(&mut data, 10);
+        assert_eq!(data.len(), 64);
+
+        let message_len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let body = data[4..message_len + 8].to_vec();
+        let mut report = Report::default();
+        assert_eq!(
+            unpackage_data(body, message_len, false, &mut report).unwrap(),
+            b"secret"
+        );
+        assert_eq!(report.crc_stored, report.crc_calculated);
+
+        let mut body = data[4..message_len + 8].to_vec();
+        body[0] ^= 1;
+        let err = unpackage_data(body, message_len, false, &mut report).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::CRCMismatch);
+    }
+
+    #[test]
+    fn check_length_follows_the_policy() {
+        let policy = |answer| LengthPolicy {
+            limit: 100,
+            answer,
+            stdin_free: false,
+        };
+        let capacity = 1000 * 8;
+        // A length past the capacity is never a message.
+        let err = check_length(993, capacity, &policy(Some(true))).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::NoPayload);
+        assert!(check_length(992, capacity, &policy(Some(true))).is_ok());
+
+        assert!(check_length(99, capacity, &policy(None)).is_ok());
+        assert!(check_length(100, capacity, &policy(Some(true))).is_ok());
+        for answer in [Some(false), None] {
+            let err = check_length(100, capacity, &policy(answer)).unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::UserStopped);
+        }
+
+        let default = LengthPolicy::default();
+        assert_eq!((default.limit, default.answer), (1 << 20, None));
+    }
+}
@@ -1,134 +1,1024 @@
+use crate::apng::Png;
+use crate::chunks;
 use crate::errors::{AppError, AppErrorKind};
+use crate::errors::{Context, Stage};
+use crate::imageio::{self, decode_image, read_input, save_image, write_output};
+use crate::pages::{self, PageData, Tiff};
+use crate::report::Report;
+use image::{DynamicImage, ImageFormat};
 use rand::{rngs::StdRng, SeedableRng};
-use std::{iter::zip, path::PathBuf};
+use rayon::prelude::*;
+use std::{path::Path, time::Instant};
 
-use crate::methods::data::FromBits;
-use crate::methods::data::{hash_key, package_data, BatchIterator, BitIterator};
+use crate::methods::data::{
+    check_length, hash_key, package_data, pad_data, padded_len, unpackage_data, LengthPolicy,
+};
 
-use super::pixel::{PixelIterator, RandomPixelIterator, SequentialPixelIterator};
+use super::mask::allowed_pixels;
+use super::pixel::{KeyedPermutation, PixelOrder, RandomPixelIterator};
 
+/// A sample of the image, whose least significant bit can carry data.
+pub trait Sample: Copy + Send + Sync {
+    fn lsb(self) -> u8;
+    fn with_lsb(self, bit: u8) -> Self;
+}
+
+impl Sample for u8 {
+    fn lsb(self) -> u8 {
+        self & 1
+    }
+    fn with_lsb(self, bit: u8) -> Self {
+        self & !1 | bit
+    }
+}
+
+impl Sample for u16 {
+    fn lsb(self) -> u8 {
+        (self & 1) as u8
+    }
+    fn with_lsb(self, bit: u8) -> Self {
+        self & !1 | bit as u16
+    }
+}
+
+/// How the samples of the pixels are laid out: `stride` samples per pixel,
+/// of which the first `channels` carry one bit each. Alpha never does.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub stride: usize,
+    pub channels: usize,
+}
+
+impl Layout {
+    pub const RGB: Layout = Layout::new(3, 3);
+    pub const RGBA: Layout = Layout::new(4, 3);
+
+    pub const fn new(stride: usize, channels: usize) -> Self {
+        Layout { stride, channels }
+    }
+
+    /// The layout of a pixel with `samples` samples, the last of which is
+    /// alpha if `alpha` is set.
+    pub fn with_alpha(samples: usize, alpha: bool) -> Self {
+        Layout::new(samples, if alpha { samples - 1 } else { samples })
+    }
+}
+
+// The bitstream is processed in groups of 8 pixels, which hold exactly as many
+// bytes as there are channels carrying data.
+const GROUP_PIXELS: usize = 8;
+
+// Number of pixel positions computed at once when writing, which bounds the
+// memory used on top of the image.
+const CHUNK_PIXELS: usize = 1 << 16;
+
+/// Chooses the pixel order from the options. `pixels` is the number of pixels
+/// that will be used, only needed by the legacy order. With a mask, only the
+/// allowed pixels are ordered.
+pub fn pixel_order(
+    options: &[&String],
+    key: Option<&String>,
+    (width, height): (u32, u32),
+    pixels: usize,
+    allowed: Option<Vec<u64>>,
+) -> Result<PixelOrder, AppError> {
+    let mut rng = StdRng::seed_from_u64(hash_key(key));
+    let total = match &allowed {
+        Some(allowed) => allowed.len() as u64,
+        None => width as u64 * height as u64,
+    };
+
+    let order = if options.iter().any(|o| *o == "SEQ") {
+        PixelOrder::Sequential
+    } else if options.iter().any(|o| *o == "LEGACY") {
+        if allowed.is_some() {
+            return Err(AppError::new(
+                AppErrorKind::InvalidOption,
+                "LEGACY reads images of earlier versions, which had no masks nor CHROMA.",
+            ));
+        }
+        // Whole groups are always read, so the table covers them too.
+        let table = RandomPixelIterator::new((width, height), rng)
+            .take(pixels.next_multiple_of(GROUP_PIXELS))
+            .map(|(x, y)| y as u64 * width as u64 + x as u64)
+            .collect();
+        PixelOrder::Table(table)
+    } else {
+        PixelOrder::Keyed(KeyedPermutation::new(total, &mut rng))
+    };
+
+    Ok(match allowed {
+        Some(allowed) => PixelOrder::Masked(Box::new(order), allowed),
+        None => order,
+    })
+}
+
+/// Writes the bits of `data` into the LSBs of the channels of the pixels,
+/// taken in the given order.
+pub fn write_bits<T: Sample>(buf: &mut [T], layout: Layout, order: &PixelOrder, data: &[u8]) {
+    let Layout { stride, channels } = layout;
+    let slots = order.limit().unwrap_or(buf.len() / stride);
+    let total_bits = (data.len() * 8).min(slots * channels);
+    let total_pixels = total_bits.div_ceil(channels);
+    let mut positions = Vec::with_capacity(CHUNK_PIXELS.min(total_pixels));
+
+    for chunk_start in (0..total_pixels).step_by(CHUNK_PIXELS) {
+        let chunk_end = (chunk_start + CHUNK_PIXELS).min(total_pixels);
+        (chunk_start..chunk_end)
+            .into_par_iter()
+            .map(|idx| order.get(idx as u64) as usize * stride)
+            .collect_into_vec(&mut positions);
+
+        for (group_idx, group) in positions.chunks(GROUP_PIXELS).enumerate() {
+            let group_start = (chunk_start / GROUP_PIXELS + group_idx) * channels;
+            let mut bytes = [0u8; 4];
+            let available = data.len().saturating_sub(group_start).min(channels);
+            bytes[..available].copy_from_slice(&data[group_start..group_start + available]);
+            let word = u32::from_be_bytes(bytes);
+
+            let first_bit = group_start * 8;
+            for (slot, &pos) in group.iter().enumerate() {
+                for channel in 0..channels {
+                    let bit_idx = slot * channels + channel;
+                    if first_bit + bit_idx >= total_bits {
+                        return;
+                    }
+                    let bit = (word >> (31 - bit_idx)) as u8 & 1;
+                    buf[pos + channel] = buf[pos + channel].with_lsb(bit);
+                }
+            }
+        }
+    }
+}
+
+/// Reads `len` bytes of the bitstream starting at byte `start`, from the LSBs
+/// of the pixels taken in the given order. Groups of pixels are decoded in
+/// parallel straight into the output buffer.
+pub fn read_bytes<T: Sample>(
+    buf: &[T],
+    layout: Layout,
+    order: &PixelOrder,
+    start: usize,
+    len: usize,
+) -> Vec<u8> {
+    let Layout { stride, channels } = layout;
+    let total_pixels = order.limit().unwrap_or(buf.len() / stride);
+    let first_group = start / channels;
+    let last_group = (start + len).div_ceil(channels);
+
+    let mut out = vec![0u8; (last_group - first_group) * channels];
+    out.par_chunks_mut(channels)
+        .enumerate()
+        .for_each(|(idx, chunk)| {
+            let first_pixel = (first_group + idx) * GROUP_PIXELS;
+            let mut word = 0u32;
+            for slot in 0..GROUP_PIXELS {
+                if first_pixel + slot >= total_pixels {
+                    break;
+                }
+                let pos = order.get((first_pixel + slot) as u64) as usize * stride;
+                for channel in 0..channels {
+                    let bit = buf[pos + channel].lsb() as u32;
+                    word |= bit << (31 - slot * channels - channel);
+                }
+            }
+            chunk.copy_from_slice(&word.to_be_bytes()[..channels]);
+        });
+
+    let offset = start - first_group * channels;
+    out.drain(..offset);
+    out.truncate(len);
+    out
+}
+
+/// The samples of the pixels, of the image or of all its frames or pages.
+enum Samples<'a> {
+    U8(&'a mut [u8]),
+    U16(&'a mut [u16]),
+}
+
+/// The pixels data is embedded into: a still image, or all the frames of an
+/// animated PNG or the pages of a TIFF, taken one after the other.
+enum Carrier {
+    Image(DynamicImage),
+    Animation { png: Png, samples: Vec<u8> },
+    Pages { tiff: Tiff, samples: PageData },
+}
+
+fn lossy_error(format: &str, encoding: &str) -> AppError {
+    AppError::new(
+        AppErrorKind::UnsupportedExtension,
+        format!(
+            "The {} uses {} compression, which is lossy and cannot hold a payload. \
+            Convert it to a lossless format first.",
+            format, encoding
+        ),
+    )
+}
+
+/// Refuses lossy encodings of formats that are otherwise lossless.
+pub(crate) fn check_lossless(data: &[u8]) -> Result<(), AppError> {
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        let mut idx = 12;
+        while let Some(header) = data.get(idx..idx + 8) {
+            if &header[..4] == b"VP8 " {
+                return Err(lossy_error("WebP image", "VP8"));
+            }
+            let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+            idx += 8 + size + size % 2;
+        }
+    } else if pages::is_tiff(data) {
+        if let Some(encoding) = pages::lossy_compression(data)? {
+            return Err(lossy_error("TIFF image", encoding));
+        }
+    }
+    Ok(())
+}
+
+/// Loads an image, refusing the lossy encodings of lossless formats.
+pub(crate) fn load_lossless(
+    path: &Path,
+    format: Option<ImageFormat>,
+) -> Result<DynamicImage, AppError> {
+    let data = read_input(path)?;
+    check_lossless(&data).context(Stage::Decode, path)?;
+    decode_image(data, path, format)
+}
+
+/// The samples of an 8 bit image, which `method` needs.
+pub(crate) fn samples_8bit<'a>(
+    img: &'a mut DynamicImage,
+    method: &str,
+) -> Result<&'a mut [u8], AppError> {
+    match img {
+        DynamicImage::ImageLuma8(buf) => Ok(buf),
+        DynamicImage::ImageLumaA8(buf) => Ok(buf),
+        DynamicImage::ImageRgb8(buf) => Ok(buf),
+        DynamicImage::ImageRgba8(buf) => Ok(buf),
+        _ => Err(AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            format!("{} works on 8 bit images, use LSB for 16 bit ones.", method),
+        )),
+    }
+}
+
+impl Carrier {
+    /// Loads the image, keeping 8 and 16 bit gray, RGB and RGBA images as
+    /// they are, and converting everything else to RGBA.
+    ///
+    /// `output` is the extension of the image that will be written: animated
+    /// PNGs are only kept whole when writing a PNG, and multi-page TIFFs when
+    /// writing a TIFF, otherwise only their first frame or page is used. Both
+    /// are kept whole when extracting, with no output.
+    fn load(
+        path: &Path,
+        format: Option<ImageFormat>,
+        output: Option<&str>,
+    ) -> Result<Self, AppError> {
+        let data = read_input(path)?;
+        check_lossless(&data).context(Stage::Decode, path)?;
+
+        if output.is_none_or(|e| e == "png") && chunks::is_png(&data) {
+            let png = Png::decode(data.clone()).context(Stage::Decode, path)?;
+            if png.is_animated() {
+                let supported = png.info.bit_depth == png::BitDepth::Eight
+                    && png.info.color_type != png::ColorType::Indexed;
+                if !supported {
+                    return Err(AppError::new(
+                        AppErrorKind::UnsupportedExtension,
+                        "Only 8 bit animated PNGs are supported, use PAL for indexed ones.",
+                    )
+                    .with_stage(Stage::Decode)
+                    .with_path(path));
+                }
+                let samples = png.frames.iter().flat_map(|f| f.data.clone()).collect();
+                return Ok(Carrier::Animation { png, samples });
+            }
+        }
+
+        if output.is_none_or(|e| e == "tif" || e == "tiff") && pages::is_tiff(&data) {
+            let tiff = Tiff::decode(&data).context(Stage::Decode, path)?;
+            if tiff.pages.len() > 1 {
+                let first = &tiff.pages[0];
+                if tiff.pages.iter().any(|p| p.color != first.color) {
+                    return Err(AppError::new(
+                        AppErrorKind::UnsupportedExtension,
+                        "All the pages of the TIFF must have the same color type.",
+                    )
+                    .with_stage(Stage::Decode)
+                    .with_path(path));
+                }
+                let samples = match first.data {
+                    PageData::U8(_) => PageData::U8(
+                        tiff.pages
+                            .iter()
+                            .flat_map(|p| match &p.data {
+                                PageData::U8(d) => d.clone(),
+                                PageData::U16(_) => unreachable!(),
+                            })
+                            .collect(),
+                    ),
+                    PageData::U16(_) => PageData::U16(
+                        tiff.pages
+                            .iter()
+                            .flat_map(|p| match &p.data {
+                                PageData::U16(d) => d.clone(),
+                                PageData::U8(_) => unreachable!(),
+                            })
+                            .collect(),
+                    ),
+                };
+                return Ok(Carrier::Pages { tiff, samples });
+            }
+        }
+
+        match decode_image(data, path, format)? {
+            img @ (DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+            | DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)) => Ok(Carrier::Image(img)),
+            img => Ok(Carrier::Image(DynamicImage::ImageRgba8(img.into_rgba8()))),
+        }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Carrier::Image(img) => (img.width(), img.height()),
+            Carrier::Animation { png, .. } => (png.info.width, png.info.height),
+            Carrier::Pages { tiff, .. } => (tiff.pages[0].width, tiff.pages[0].height),
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        match self {
+            Carrier::Image(img) => {
+                let color = img.color();
+                Layout::with_alpha(color.channel_count() as usize, color.has_alpha())
+            }
+            Carrier::Animation { png, .. } => {
+                let color = png.info.color_type;
+                let alpha = matches!(color, png::ColorType::GrayscaleAlpha | png::ColorType::Rgba);
+                Layout::with_alpha(color.samples(), alpha)
+            }
+            Carrier::Pages { tiff, .. } => match tiff.pages[0].color {
+                tiff::ColorType::RGBA(_) => Layout::RGBA,
+                tiff::ColorType::RGB(_) => Layout::RGB,
+                _ => Layout::new(1, 1),
+            },
+        }
+    }
+
+    fn samples(&mut self) -> Samples<'_> {
+        match self {
+            Carrier::Image(DynamicImage::ImageLuma8(buf)) => Samples::U8(buf),
+            Carrier::Image(DynamicImage::ImageLumaA8(buf)) => Samples::U8(buf),
+            Carrier::Image(DynamicImage::ImageRgb8(buf)) => Samples::U8(buf),
+            Carrier::Image(DynamicImage::ImageRgba8(buf)) => Samples::U8(buf),
+            Carrier::Image(DynamicImage::ImageLuma16(buf)) => Samples::U16(buf),
+            Carrier::Image(DynamicImage::ImageLumaA16(buf)) => Samples::U16(buf),
+            Carrier::Image(DynamicImage::ImageRgb16(buf)) => Samples::U16(buf),
+            Carrier::Image(DynamicImage::ImageRgba16(buf)) => Samples::U16(buf),
+            Carrier::Image(_) => unreachable!(),
+            Carrier::Animation { samples, .. } => Samples::U8(samples),
+            Carrier::Pages {
+                samples: PageData::U8(samples),
+                ..
+            } => Samples::U8(samples),
+            Carrier::Pages {
+                samples: PageData::U16(samples),
+                ..
+            } => Samples::U16(samples),
+        }
+    }
+
+    fn pixels(&mut self) -> usize {
+        let stride = self.layout().stride;
+        match self.samples() {
+            Samples::U8(buf) => buf.len() / stride,
+            Samples::U16(buf) => buf.len() / stride,
+        }
+    }
+
+    fn frame_count(&self) -> usize {
+        match self {
+            Carrier::Image(_) => 1,
+            Carrier::Animation { png, .. } => png.frames.len(),
+            Carrier::Pages { tiff, .. } => tiff.pages.len(),
+        }
+    }
+
+    /// The allowed pixels of the mask given by the options, if any.
+    fn allowed(&self, options: &[&String]) -> Result<Option<Vec<u64>>, AppError> {
+        allowed_pixels(options, self.dimensions(), self.frame_count())
+    }
+
+    /// Size of the order over the pixels: the image, or a single row holding
+    /// the pixels of all the frames.
+    fn order_dimensions(&mut self) -> (u32, u32) {
+        match self {
+            Carrier::Image(img) => (img.width(), img.height()),
+            _ => (self.pixels() as u32, 1),
+        }
+    }
+
+    fn write_bits(&mut self, order: &PixelOrder, data: &[u8]) {
+        let layout = self.layout();
+        match self.samples() {
+            Samples::U8(buf) => write_bits(buf, layout, order, data),
+            Samples::U16(buf) => write_bits(buf, layout, order, data),
+        }
+    }
+
+    fn read_bytes(&mut self, order: &PixelOrder, start: usize, len: usize) -> Vec<u8> {
+        let layout = self.layout();
+        match self.samples() {
+            Samples::U8(buf) => read_bytes(buf, layout, order, start, len),
+            Samples::U16(buf) => read_bytes(buf, layout, order, start, len),
+        }
+    }
+
+    fn save(self, path: &Path, format: Option<ImageFormat>) -> Result<(), AppError> {
+        match self {
+            Carrier::Image(img) => save_image(&img, path, format),
+            Carrier::Animation { mut png, samples } => {
+                let mut rest = samples.as_slice();
+                for frame in &mut png.frames {
+                    let (head, tail) = rest.split_at(frame.data.len());
+                    frame.data.copy_from_slice(head);
+                    rest = tail;
+                }
+                write_output(path, &png.encode().context(Stage::Encode, path)?)
+            }
+            Carrier::Pages { mut tiff, samples } => {
+                let mut offset = 0;
+                for page in &mut tiff.pages {
+                    match (&mut page.data, &samples) {
+                        (PageData::U8(d), PageData::U8(s)) => {
+                            let len = d.len();
+                            d.copy_from_slice(&s[offset..offset + len]);
+                            offset += len;
+                        }
+                        (PageData::U16(d), PageData::U16(s)) => {
+                            let len = d.len();
+                            d.copy_from_slice(&s[offset..offset + len]);
+                            offset += len;
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                write_output(path, &tiff.encode().context(Stage::Encode, path)?)
+            }
+        }
+    }
+}
+
+/// The reversible colour transform of JPEG 2000, from RGB to Y, Cb and Cr.
+fn rct(r: i32, g: i32, b: i32) -> (i32, i32, i32) {
+    ((r + 2 * g + b) >> 2, b - g, r - g)
+}
+
+fn inverse_rct(y: i32, cb: i32, cr: i32) -> (i32, i32, i32) {
+    let g = y - ((cb + cr) >> 2);
+    (cr + g, g, cb + g)
+}
+
+/// Offset of the chroma samples, which range from -255 to 255.
+const CHROMA_OFFSET: i32 = 255;
+
+/// The samples the bits are written to: those of the image, or with the
+/// CHROMA option its Cb and Cr planes, offset to be positive.
+enum Space {
+    Rgb,
+    Chroma { planes: Vec<u16>, usable: Vec<bool> },
+}
+
+impl Space {
+    fn new(options: &[&String], img: &mut Carrier) -> Result<Self, AppError> {
+        if !options.iter().any(|o| *o == "CHROMA") {
+            return Ok(Space::Rgb);
+        }
+        let Layout { stride, channels } = img.layout();
+        let buf = match img.samples() {
+            Samples::U8(buf) if channels == 3 => buf,
+            _ => {
+                return Err(AppError::new(
+                    AppErrorKind::InvalidOption,
+                    "CHROMA only applies to 8 bit RGB and RGBA images.",
+                ))
+            }
+        };
+
+        let mut planes = Vec::with_capacity(buf.len() / stride * 2);
+        let mut usable = Vec::with_capacity(buf.len() / stride);
+        for pixel in buf.chunks_exact(stride) {
+            let (y, cb, cr) = rct(pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+            let (cb, cr) = (cb + CHROMA_OFFSET, cr + CHROMA_OFFSET);
+            planes.extend([cb as u16, cr as u16]);
+            // Only the LSBs change, so this holds for the embedded pixel too.
+            usable.push((0..4).all(|bits| {
+                let (r, g, b) = inverse_rct(
+                    y,
+                    (cb & !1 | bits & 1) - CHROMA_OFFSET,
+                    (cr & !1 | bits >> 1) - CHROMA_OFFSET,
+                );
+                [r, g, b].iter().all(|s| (0..=255).contains(s))
+            }));
+        }
+        Ok(Space::Chroma { planes, usable })
+    }
+
+    fn layout(&self, img: &Carrier) -> Layout {
+        match self {
+            Space::Rgb => img.layout(),
+            Space::Chroma { .. } => Layout::new(2, 2),
+        }
+    }
+
+    /// Narrows the allowed pixels down to those whose chroma can change
+    /// without taking the color out of range.
+    fn restrict(&self, allowed: Option<Vec<u64>>) -> Option<Vec<u64>> {
+        match self {
+            Space::Rgb => allowed,
+            Space::Chroma { usable, .. } => Some(match allowed {
+                Some(allowed) => allowed
+                    .into_iter()
+                    .filter(|&p| usable[p as usize])
+                    .collect(),
+                None => (0..usable.len() as u64)
+                    .filter(|&p| usable[p as usize])
+                    .collect(),
+            }),
+        }
+    }
+
+    fn write_bits(&mut self, img: &mut Carrier, order: &PixelOrder, data: &[u8]) {
+        let Space::Chroma { planes, .. } = self else {
+            return img.write_bits(order, data);
+        };
+        write_bits(planes, Layout::new(2, 2), order, data);
+
+        let stride = img.layout().stride;
+        let Samples::U8(buf) = img.samples() else {
+            unreachable!()
+        };
+        for (pixel, chroma) in buf.chunks_exact_mut(stride).zip(planes.chunks_exact(2)) {
+            let (y, _, _) = rct(pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+            let (r, g, b) = inverse_rct(
+                y,
+                chroma[0] as i32 - CHROMA_OFFSET,
+                chroma[1] as i32 - CHROMA_OFFSET,
+            );
+            pixel[..3].copy_from_slice(&[r as u8, g as u8, b as u8]);
+        }
+    }
+
+    fn read_bytes(
+        &self,
+        img: &mut Carrier,
+        order: &PixelOrder,
+        start: usize,
+        len: usize,
+    ) -> Vec<u8> {
+        match self {
+            Space::Rgb => img.read_bytes(order, start, len),
+            Space::Chroma { planes, .. } => {
+                read_bytes(planes, Layout::new(2, 2), order, start, len)
+            }
+        }
+    }
+}
+
+/// Returns the number of bits the image can hold, framing included, in the
+/// pixels the options allow.
+pub fn capacity(
+    image_path: &Path,
+    format: Option<ImageFormat>,
+    options: &[&String],
+) -> Result<usize, AppError> {
+    let mut img = Carrier::load(image_path, format, None)?;
+    let space = Space::new(options, &mut img)?;
+    let pixels = match space.restrict(img.allowed(options)?) {
+        Some(allowed) => allowed.len(),
+        None => img.pixels(),
+    };
+    Ok(pixels * space.layout(&img).channels)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn embed(
-    image_path: &PathBuf,
-    output_path: &PathBuf,
+    image_path: &Path,
+    output_path: &Path,
     secret_data: &[u8],
     key: Option<&String>,
     verbose: bool,
     options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
 ) -> Result<(), AppError> {
-    let mut img = image::open(image_path)?.to_rgba8();
+    let start = Instant::now();
+    let output = imageio::extension(output_path, format).unwrap_or_default();
+    let mut img = Carrier::load(image_path, None, Some(&output))?;
+    report.time("load", start);
 
     let (width, height) = img.dimensions();
-    let imgsize = width * height;
+    let mut space = Space::new(&options, &mut img)?;
+    let layout = space.layout(&img);
+    let allowed = space.restrict(img.allowed(&options)?);
+    let imgsize = allowed.as_ref().map_or(img.pixels(), Vec::len);
     if verbose {
-        println!("Image size: {}x{}", width, height);
+        eprintln!("Image size: {}x{}", width, height);
+        if img.frame_count() > 1 {
+            eprintln!("Frames: {}, {} pixels", img.frame_count(), imgsize);
+        }
+        if matches!(space, Space::Chroma { .. }) {
+            eprintln!("Chroma: {} pixels usable", imgsize);
+        } else if allowed.is_some() {
+            eprintln!("Mask: {} pixels allowed", imgsize);
+        }
     }
+    report.width = Some(width);
+    report.height = Some(height);
+
+    let capacity_bits = imgsize * layout.channels;
 
-    let message_len = secret_data.len() as u32;
+    let mut data = package_data(secret_data);
 
-    if message_len * 8 > (imgsize * 3) {
+    if data.len() * 8 > capacity_bits {
         return Err(AppError::new(
-            crate::errors::AppErrorKind::DataOverflow,
-            "Data is too long",
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
         ));
     };
 
-    let data = package_data(secret_data);
-    let secret_bits = BitIterator::new(&data);
-    let bit_triplet = BatchIterator::new(secret_bits, 3);
-
-    let iterpix = if options.contains(&&"SEQ".to_string()) {
-        PixelIterator::Sequential(SequentialPixelIterator::new((width, height)))
-    } else {
-        let rng = StdRng::seed_from_u64(hash_key(key));
-        PixelIterator::Random(RandomPixelIterator::new((width, height), rng))
-    };
-    // let iterpix = pixel::RandomPixelIterator::new((width, height), rng);
-
-    for (trip, pix) in zip(bit_triplet, iterpix) {
-        for (idx, val) in trip.iter().enumerate() {
-            let pixel = &mut img[(pix.0, pix.1)];
-            pixel[idx] = pixel[idx] & !1 | val.unwrap_or_default();
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits.div_ceil(8))? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
         }
+        pad_data(&mut data, len);
     }
+    let used_bits = (data.len() * 8).min(capacity_bits);
+    report.capacity(used_bits, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let order = pixel_order(
+        &options,
+        key,
+        img.order_dimensions(),
+        used_bits.div_ceil(layout.channels),
+        allowed,
+    )?;
+    space.write_bits(&mut img, &order, &data);
+    report.time("embed", start);
 
-    img.save(output_path)?;
+    let start = Instant::now();
+    img.save(output_path, format)?;
+    report.time("save", start);
 
     if verbose {
-        println!("Image saved");
+        eprintln!("Image saved");
     }
 
     Ok(())
 }
 
 pub fn extract(
-    image_path: &PathBuf,
+    image_path: &Path,
     key: Option<&String>,
     verbose: bool,
     options: Vec<&String>,
+    format: Option<ImageFormat>,
+    policy: &LengthPolicy,
+    report: &mut Report,
 ) -> Result<Vec<u8>, AppError> {
-    let img = image::open(image_path)?.to_rgba8();
+    let start = Instant::now();
+    let mut img = Carrier::load(image_path, format, None)?;
+    report.time("load", start);
 
     let (width, height) = img.dimensions();
     if verbose {
-        println!("Image size: {}x{}", width, height);
+        eprintln!("Image size: {}x{}", width, height);
+        if img.frame_count() > 1 {
+            eprintln!("Frames: {}", img.frame_count());
+        }
     }
+    report.width = Some(width);
+    report.height = Some(height);
+    let start = Instant::now();
+
+    let space = Space::new(&options, &mut img)?;
+    let allowed = space.restrict(img.allowed(&options)?);
 
-    let mut iterpix = if options.contains(&&"SEQ".to_string()) {
-        PixelIterator::Sequential(SequentialPixelIterator::new((width, height)))
+    // Images of earlier versions were all embedded in the legacy order, which
+    // is tried when nothing is found in the keyed one.
+    let keyed = !options.iter().any(|o| *o == "SEQ" || *o == "LEGACY");
+    let fallback = keyed && allowed.is_none() && matches!(space, Space::Rgb);
+    let found = read_payload(
+        &mut img, &space, &options, key, verbose, allowed, policy, report,
+    );
+    let missing = found.as_ref().is_err_and(|err| {
+        matches!(
+            err.kind,
+            AppErrorKind::NoPayload | AppErrorKind::CRCMismatch
+        )
+    });
+    let secret = if fallback && missing {
+        if verbose {
+            eprintln!("Nothing found, trying the pixel order of earlier versions");
+        }
+        let legacy = "LEGACY".to_string();
+        let options = [options.as_slice(), &[&legacy]].concat();
+        let legacy = read_payload(
+            &mut img, &space, &options, key, verbose, None, policy, report,
+        );
+        // The error of the current order is the one that tells what is wrong.
+        legacy.or(found)?
     } else {
-        let rng = StdRng::seed_from_u64(hash_key(key));
-        PixelIterator::Random(RandomPixelIterator::new((width, height), rng))
+        found?
     };
+    report.time("extract", start);
+    Ok(secret)
+}
 
-    let mut bitstream = (&mut iterpix).flat_map(|px| {
-        let img = &img;
-        (0..3).map(move |i| img[(px.0, px.1)][i] & 1)
-    });
+/// Reads the framed payload from the pixels, in the order the options give.
+#[allow(clippy::too_many_arguments)]
+fn read_payload(
+    img: &mut Carrier,
+    space: &Space,
+    options: &[&String],
+    key: Option<&String>,
+    verbose: bool,
+    allowed: Option<Vec<u64>>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let layout = space.layout(img);
+    let capacity_bits = allowed.as_ref().map_or(img.pixels(), Vec::len) * layout.channels;
 
-    let message_len =
-        u32::from_bits((&mut bitstream).take(32).collect::<Vec<u8>>().as_slice()) as usize;
+    let header_pixels = (4 * 8usize).div_ceil(layout.channels);
+    let order = pixel_order(options, key, img.order_dimensions(), header_pixels, allowed)?;
+    let message_len = match space.read_bytes(img, &order, 0, 4).try_into() {
+        Ok(len) => u32::from_be_bytes(len) as usize,
+        Err(_) => 0,
+    };
     if verbose {
-        println!("Detected message length: {}", message_len)
+        eprintln!("Detected message length: {}", message_len)
     };
 
-    if message_len >= 1048576 {
-        println!(
-            "The detected message length is {:.1} MB. Do you want to continue? (y/n)",
-            (message_len as f32) / 1048576.0
-        );
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line).unwrap();
-        let line = line.trim().to_lowercase();
-        match line.chars().nth(0) {
-            Some('y') => Ok(()),
-            Some(_) | None => Err(AppError::new(
-                AppErrorKind::UserStopped,
-                "Operation stopped by user",
-            )),
-        }?;
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let order = match order {
+        PixelOrder::Table(_) => pixel_order(
+            options,
+            key,
+            img.order_dimensions(),
+            ((message_len + 8) * 8).div_ceil(layout.channels),
+            None,
+        )?,
+        order => order,
+    };
+    let secret = space.read_bytes(img, &order, 4, message_len + 4);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    fn keyed(n: u64) -> PixelOrder {
+        PixelOrder::Keyed(KeyedPermutation::new(n, &mut StdRng::seed_from_u64(n)))
     }
 
-    let mut secret = Vec::<u8>::with_capacity(message_len);
+    #[test]
+    fn bits_round_trip() {
+        for layout in [
+            Layout::RGB,
+            Layout::RGBA,
+            Layout::new(1, 1),
+            Layout::new(2, 2),
+        ] {
+            let pixels = 1000;
+            let orders = [
+                PixelOrder::Sequential,
+                keyed(pixels as u64),
+                PixelOrder::Masked(Box::new(keyed(300)), (0..300).map(|p| p * 3).collect()),
+            ];
+            for order in &orders {
+                let slots = order.limit().unwrap_or(pixels);
+                let data = data(slots * layout.channels / 8);
+                let mut buf = vec![0x55u8; pixels * layout.stride];
+                write_bits(&mut buf, layout, order, &data);
+                assert_eq!(read_bytes(&buf, layout, order, 0, data.len()), data);
+                assert_eq!(read_bytes(&buf, layout, order, 5, 9), data[5..14]);
+
+                let mut wide = vec![0xAAAAu16; pixels * layout.stride];
+                write_bits(&mut wide, layout, order, &data);
+                assert_eq!(read_bytes(&wide, layout, order, 3, 20), data[3..23]);
+            }
+        }
+    }
 
-    while secret.len() < message_len {
-        let byte = u8::from_bits((&mut bitstream).take(8).collect::<Vec<u8>>().as_slice());
-        secret.push(byte);
+    #[test]
+    fn bits_leave_the_rest_alone() {
+        let mut buf = vec![0xFEu8; 64 * 4];
+        write_bits(&mut buf, Layout::RGBA, &PixelOrder::Sequential, &[0xFF]);
+        // 8 bits over 3 channels, then the alpha of each pixel is untouched.
+        let changed = buf.iter().filter(|&&s| s == 0xFF).count();
+        assert_eq!(changed, 8);
+        assert!(buf.chunks(4).all(|p| p[3] == 0xFE));
     }
 
-    let crc_read = u32::from_bits((&mut bitstream).take(32).collect::<Vec<u8>>().as_slice());
-    let crc_calc = crc32fast::hash(&secret);
+    #[test]
+    fn extract_falls_back_to_the_legacy_order() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_legacy.png", std::process::id()));
+        let output = cover.with_extension("out.png");
+        let img = RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 90]));
+        img.save(&cover).unwrap();
 
-    if verbose {
-        println!("CRC32 in file    :    {}", crc_read);
-        println!("Calculated CRC32 :    {}", crc_calc);
+        let key = "key".to_string();
+        let legacy = "LEGACY".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        embed(
+            &cover,
+            &output,
+            b"old secret",
+            Some(&key),
+            false,
+            vec![&legacy],
+            None,
+            &mut Report::default(),
+        )
+        .unwrap();
+        let data = extract(
+            &output,
+            Some(&key),
+            false,
+            vec![],
+            None,
+            &policy,
+            &mut Report::default(),
+        );
+        assert_eq!(data.unwrap(), b"old secret");
+
+        let other = "other".to_string();
+        let data = extract(
+            &output,
+            Some(&other),
+            false,
+            vec![],
+            None,
+            &policy,
+            &mut Report::default(),
+        );
+        assert!(data.is_err());
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
     }
 
-    if crc_read == crc_calc {
-        Ok(secret)
-    } else {
-        Err(AppError::new(
-            AppErrorKind::CRCMismatch,
-            "CRC32 mismatch: invalid data",
-        ))
+    #[test]
+    fn padded_round_trip() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_pad.png", std::process::id()));
+        let output = cover.with_extension("out.png");
+        RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 90]))
+            .save(&cover)
+            .unwrap();
+
+        let key = "key".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        // 32x32 RGB pixels hold 384 bytes.
+        for (pad, padded) in [("PAD", 384), ("PAD=256", 256)] {
+            let pad = pad.to_string();
+            let mut report = Report::default();
+            embed(
+                &cover,
+                &output,
+                &data(100),
+                Some(&key),
+                false,
+                vec![&pad],
+                None,
+                &mut report,
+            )
+            .unwrap();
+            assert_eq!(report.used_bits, Some(padded * 8));
+            let found = extract(
+                &output,
+                Some(&key),
+                false,
+                vec![],
+                None,
+                &policy,
+                &mut Report::default(),
+            );
+            assert_eq!(found.unwrap(), data(100));
+        }
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn rct_is_reversible() {
+        for r in (0..256).step_by(5) {
+            for g in (0..256).step_by(3) {
+                for b in (0..256).step_by(7) {
+                    let (y, cb, cr) = rct(r, g, b);
+                    assert_eq!(inverse_rct(y, cb, cr), (r, g, b));
+                    assert!((0..=255).contains(&y));
+                    assert!((-255..=255).contains(&cb) && (-255..=255).contains(&cr));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chroma_round_trip_keeps_the_luma() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_chroma.png", std::process::id()));
+        let output = cover.with_extension("out.png");
+        // Every fourth column is magenta, whose chroma cannot grow.
+        let img = RgbImage::from_fn(48, 40, |x, y| match x % 4 {
+            0 => image::Rgb([255, 0, 255]),
+            _ => image::Rgb([x as u8 * 5, y as u8 * 6, 120]),
+        });
+        img.save(&cover).unwrap();
+
+        let key = "key".to_string();
+        let chroma = "CHROMA".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        embed(
+            &cover,
+            &output,
+            &data(150),
+            Some(&key),
+            false,
+            vec![&chroma],
+            None,
+            &mut Report::default(),
+        )
+        .unwrap();
+        let marked = image::open(&output).unwrap().to_rgb8();
+        let luma = |p: &image::Rgb<u8>| rct(p[0] as i32, p[1] as i32, p[2] as i32).0;
+        for (x, y, pixel) in marked.enumerate_pixels() {
+            assert_eq!(luma(pixel), luma(img.get_pixel(x, y)));
+            if x % 4 == 0 {
+                assert_eq!(pixel, img.get_pixel(x, y));
+            }
+        }
+        assert_ne!(marked, img);
+
+        let found = extract(
+            &output,
+            Some(&key),
+            false,
+            vec![&chroma],
+            None,
+            &policy,
+            &mut Report::default(),
+        );
+        assert_eq!(found.unwrap(), data(150));
+
+        image::GrayImage::new(8, 8).save(&cover).unwrap();
+        let err = embed(
+            &cover,
+            &output,
+            b"gray",
+            None,
+            false,
+            vec![&chroma],
+            None,
+            &mut Report::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::InvalidOption);
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
     }
 }
@@ -0,0 +1,92 @@
+use crate::errors::{AppError, AppErrorKind};
+use crate::imageio::load_image;
+use std::path::Path;
+
+/// Returns true if the options restrict the pixels that carry data.
+fn has_mask(options: &[&String]) -> bool {
+    options
+        .iter()
+        .any(|o| o.starts_with("MASK=") || o.starts_with("RECT="))
+}
+
+/// Parses a RECT=x,y,width,height option.
+fn parse_rect(option: &str) -> Result<[u32; 4], AppError> {
+    let fields = option
+        .strip_prefix("RECT=")
+        .unwrap_or_default()
+        .split(',')
+        .map(|f| f.trim().parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>();
+    match fields.as_deref() {
+        Some(&[x, y, width, height]) => Ok([x, y, width, height]),
+        _ => Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            format!(
+                "{} is not a valid rectangle, expected RECT=x,y,width,height.",
+                option
+            ),
+        )),
+    }
+}
+
+/// The positions of the pixels of a `width` x `height` image that may carry
+/// data, in row-major order, or None when all of them may. They are the white
+/// pixels of the MASK=path image and the pixels inside the RECT=x,y,w,h
+/// options. The same options are needed to extract the data.
+pub fn allowed_pixels(
+    options: &[&String],
+    (width, height): (u32, u32),
+    frames: usize,
+) -> Result<Option<Vec<u64>>, AppError> {
+    if !has_mask(options) {
+        return Ok(None);
+    }
+    if frames > 1 {
+        return Err(AppError::new(
+            AppErrorKind::InvalidOption,
+            format!(
+                "Masks only apply to still images, this one has {} frames.",
+                frames
+            ),
+        ));
+    }
+
+    let mut allowed = vec![false; width as usize * height as usize];
+    for option in options {
+        if let Some(path) = option.strip_prefix("MASK=") {
+            let mask = load_image(Path::new(path), None)?.to_luma8();
+            if mask.dimensions() != (width, height) {
+                return Err(AppError::new(
+                    AppErrorKind::InvalidOption,
+                    format!(
+                        "The mask is {}x{}, the image {}x{}.",
+                        mask.width(),
+                        mask.height(),
+                        width,
+                        height
+                    ),
+                )
+                .with_path(path));
+            }
+            for (allow, pixel) in allowed.iter_mut().zip(mask.pixels()) {
+                *allow |= pixel[0] >= 128;
+            }
+        } else if option.starts_with("RECT=") {
+            let [x, y, w, h] = parse_rect(option)?;
+            let (x_end, y_end) = (
+                x.saturating_add(w).min(width),
+                y.saturating_add(h).min(height),
+            );
+            for row in y.min(y_end)..y_end {
+                let line = row as usize * width as usize;
+                allowed[line + x.min(x_end) as usize..line + x_end as usize].fill(true);
+            }
+        }
+    }
+
+    Ok(Some(
+        (0..allowed.len() as u64)
+            .filter(|&p| allowed[p as usize])
+            .collect(),
+    ))
+}
@@ -1,4 +1,12 @@
+pub mod bpcs;
+pub mod chunk;
 pub mod lsb;
+pub mod palette;
+pub mod pvd;
+pub mod text;
+pub mod watermark;
+pub mod wav;
 
 pub mod data;
+pub mod mask;
 pub mod pixel;
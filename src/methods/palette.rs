@@ -0,0 +1,637 @@
+use crate::apng::Png;
+use crate::chunks;
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::{read_input, write_output};
+use crate::report::Report;
+use std::io::Cursor;
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{
+    check_length, package_data, pad_data, padded_len, read_bytes, slot_order, unpackage_data,
+    BitIterator, LengthPolicy,
+};
+
+use super::mask::allowed_pixels;
+use super::pixel::PixelOrder;
+
+fn image_error(err: impl std::fmt::Display) -> AppError {
+    AppError::new(AppErrorKind::Image, err.to_string())
+}
+
+/// The EzStego pairing of a palette.
+///
+/// The palette is sorted by luminance and neighbouring entries are paired, so
+/// that swapping an index with its partner barely changes the colour. The bit
+/// carried by a pixel is the parity of the rank of its index. Entries with a
+/// different transparency are never paired.
+struct Pairing {
+    /// Partner of every palette index, or None if it carries no data.
+    partner: [Option<u8>; 256],
+    /// Bit carried by every palette index.
+    bit: [u8; 256],
+}
+
+impl Pairing {
+    /// `palette` holds the RGBA entries of the palette.
+    fn new(palette: &[[u8; 4]]) -> Self {
+        let luma = |c: &[u8; 4]| 299 * c[0] as u32 + 587 * c[1] as u32 + 114 * c[2] as u32;
+        let mut sorted = (0..palette.len().min(256)).collect::<Vec<_>>();
+        sorted.sort_by_key(|&i| (palette[i][3], luma(&palette[i])));
+
+        let mut partner = [None; 256];
+        let mut bit = [0; 256];
+        for pair in sorted.chunks_exact(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if palette[a][3] == palette[b][3] {
+                partner[a] = Some(b as u8);
+                partner[b] = Some(a as u8);
+                bit[b] = 1;
+            }
+        }
+
+        Pairing { partner, bit }
+    }
+}
+
+/// The indices of all the frames, one after the other, with the pairing of
+/// the palette of each frame.
+struct Layer {
+    indices: Vec<u8>,
+    /// Index of the first pixel of every frame.
+    starts: Vec<usize>,
+    pairings: Vec<Pairing>,
+}
+
+impl Layer {
+    fn new() -> Self {
+        Layer {
+            indices: Vec::new(),
+            starts: Vec::new(),
+            pairings: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, indices: &[u8], palette: &[[u8; 4]]) {
+        self.starts.push(self.indices.len());
+        self.indices.extend_from_slice(indices);
+        self.pairings.push(Pairing::new(palette));
+    }
+
+    fn frame(&self, frame: usize) -> &[u8] {
+        let end = self.starts.get(frame + 1).copied();
+        &self.indices[self.starts[frame]..end.unwrap_or(self.indices.len())]
+    }
+
+    fn pairing(&self, pixel: usize) -> &Pairing {
+        &self.pairings[self.starts.partition_point(|&s| s <= pixel) - 1]
+    }
+
+    fn partner(&self, pixel: usize) -> Option<u8> {
+        self.pairing(pixel).partner[self.indices[pixel] as usize]
+    }
+
+    fn bit(&self, pixel: usize) -> u8 {
+        self.pairing(pixel).bit[self.indices[pixel] as usize]
+    }
+
+    fn usable(&self, pixel: usize) -> bool {
+        self.partner(pixel).is_some()
+    }
+
+    /// The number of usable pixels, among the allowed ones of a masked order.
+    fn capacity(&self, order: &PixelOrder) -> usize {
+        match order {
+            PixelOrder::Masked(_, allowed) => {
+                allowed.iter().filter(|&&p| self.usable(p as usize)).count()
+            }
+            _ => (0..self.indices.len()).filter(|&p| self.usable(p)).count(),
+        }
+    }
+
+    /// The pixels carrying data, in the given order over all the frames.
+    /// Embedding only swaps indices within their pair, so the same pixels are
+    /// found when reading.
+    fn slots<'a>(&'a self, order: &'a PixelOrder) -> impl Iterator<Item = usize> + 'a {
+        let total = order.limit().unwrap_or(self.indices.len());
+        (0..total as u64)
+            .map(|idx| order.get(idx) as usize)
+            .filter(|&p| self.usable(p))
+    }
+}
+
+enum Container {
+    Gif {
+        width: u16,
+        height: u16,
+        global_palette: Option<Vec<u8>>,
+        repeat: gif::Repeat,
+        frames: Vec<gif::Frame<'static>>,
+    },
+    Png(Png),
+}
+
+struct PaletteImage {
+    container: Container,
+    layer: Layer,
+}
+
+impl PaletteImage {
+    fn dimensions(&self) -> (u32, u32) {
+        match &self.container {
+            Container::Gif { width, height, .. } => (*width as u32, *height as u32),
+            Container::Png(png) => (png.info.width, png.info.height),
+        }
+    }
+
+    fn frame_count(&self) -> usize {
+        self.layer.starts.len()
+    }
+
+    /// The left, top, width and height of the first frame on the screen. GIF
+    /// frames may cover only part of it.
+    fn frame_rect(&self) -> (u32, u32, u32, u32) {
+        match &self.container {
+            Container::Gif { frames, .. } => (
+                frames[0].left as u32,
+                frames[0].top as u32,
+                frames[0].width as u32,
+                frames[0].height as u32,
+            ),
+            Container::Png(png) => (0, 0, png.frames[0].width, png.frames[0].height),
+        }
+    }
+
+    /// Maps the allowed pixels of the screen, in row-major order, to those of
+    /// the buffer of the first frame.
+    fn frame_pixels(&self, allowed: Vec<u64>) -> Vec<u64> {
+        let (width, _) = self.dimensions();
+        let (left, top, frame_width, frame_height) = self.frame_rect();
+        allowed
+            .into_iter()
+            .filter_map(|p| {
+                let (x, y) = ((p % width as u64) as u32, (p / width as u64) as u32);
+                let inside = (left..left + frame_width).contains(&x)
+                    && (top..top + frame_height).contains(&y);
+                inside.then(|| (y - top) as u64 * frame_width as u64 + (x - left) as u64)
+            })
+            .collect()
+    }
+}
+
+fn rgba_palette(rgb: &[u8], alpha: impl Fn(usize) -> u8) -> Vec<[u8; 4]> {
+    rgb.chunks_exact(3)
+        .enumerate()
+        .map(|(i, c)| [c[0], c[1], c[2], alpha(i)])
+        .collect()
+}
+
+fn load_gif(data: Vec<u8>) -> Result<PaletteImage, AppError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(Cursor::new(data)).map_err(image_error)?;
+
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(image_error)? {
+        frames.push(frame.clone());
+    }
+    if frames.is_empty() {
+        return Err(image_error("The GIF file has no frames."));
+    }
+    let global_palette = decoder.global_palette().map(|p| p.to_vec());
+
+    let mut layer = Layer::new();
+    for frame in &frames {
+        let rgb = frame
+            .palette
+            .as_deref()
+            .or(global_palette.as_deref())
+            .unwrap_or_default();
+        let palette = rgba_palette(rgb, |i| {
+            if frame.transparent == Some(i as u8) {
+                0
+            } else {
+                255
+            }
+        });
+        layer.push(&frame.buffer, &palette);
+    }
+
+    Ok(PaletteImage {
+        container: Container::Gif {
+            width: decoder.width(),
+            height: decoder.height(),
+            global_palette,
+            repeat: decoder.repeat(),
+            frames,
+        },
+        layer,
+    })
+}
+
+fn load_png(data: Vec<u8>) -> Result<PaletteImage, AppError> {
+    let png = Png::decode(data)?;
+    if png.info.color_type != png::ColorType::Indexed {
+        return Err(AppError::new(
+            AppErrorKind::UnsupportedMethod,
+            "The PAL method needs an indexed PNG, use LSB for truecolor images.",
+        ));
+    }
+
+    let trns = png.info.trns.as_deref().unwrap_or_default();
+    let palette = rgba_palette(png.info.palette.as_deref().unwrap_or_default(), |i| {
+        trns.get(i).copied().unwrap_or(255)
+    });
+
+    let depth = png.info.bit_depth as usize;
+    let per_byte = 8 / depth;
+    let mut layer = Layer::new();
+    for frame in &png.frames {
+        let row_len = (frame.width as usize).div_ceil(per_byte);
+        let indices = frame
+            .data
+            .chunks(row_len)
+            .flat_map(|row| {
+                (0..frame.width as usize).map(move |x| {
+                    let shift = 8 - depth * (x % per_byte + 1);
+                    (row[x / per_byte] >> shift) & ((1 << depth) - 1) as u8
+                })
+            })
+            .collect::<Vec<_>>();
+        layer.push(&indices, &palette);
+    }
+
+    Ok(PaletteImage {
+        container: Container::Png(png),
+        layer,
+    })
+}
+
+fn load(path: &Path) -> Result<PaletteImage, AppError> {
+    let data = read_input(path)?;
+    if chunks::is_png(&data) {
+        load_png(data)
+    } else if data.starts_with(b"GIF8") {
+        load_gif(data)
+    } else {
+        Err(AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            "The PAL method only supports GIF and indexed PNG images.",
+        ))
+    }
+    .context(Stage::Decode, path)
+}
+
+fn encode_gif(
+    width: u16,
+    height: u16,
+    global_palette: Option<&[u8]>,
+    repeat: gif::Repeat,
+    frames: &[gif::Frame],
+) -> Result<Vec<u8>, gif::EncodingError> {
+    let mut encoder = gif::Encoder::new(
+        Vec::new(),
+        width,
+        height,
+        global_palette.unwrap_or_default(),
+    )?;
+    encoder.set_repeat(repeat)?;
+    for frame in frames {
+        encoder.write_frame(frame)?;
+    }
+    encoder.into_inner()
+}
+
+fn pack_indices(indices: &[u8], width: usize, depth: usize) -> Vec<u8> {
+    let per_byte = 8 / depth;
+    let mut packed = Vec::with_capacity(indices.len());
+    for row in indices.chunks(width) {
+        let mut line = vec![0u8; width.div_ceil(per_byte)];
+        for (x, &index) in row.iter().enumerate() {
+            line[x / per_byte] |= index << (8 - depth * (x % per_byte + 1));
+        }
+        packed.extend(line);
+    }
+    packed
+}
+
+fn save(image: PaletteImage, path: &Path) -> Result<(), AppError> {
+    let PaletteImage { container, layer } = image;
+    let data = match container {
+        Container::Gif {
+            width,
+            height,
+            global_palette,
+            repeat,
+            mut frames,
+        } => {
+            for (idx, frame) in frames.iter_mut().enumerate() {
+                frame.buffer = layer.frame(idx).to_vec().into();
+            }
+            encode_gif(width, height, global_palette.as_deref(), repeat, &frames)
+                .map_err(image_error)?
+        }
+        Container::Png(mut png) => {
+            let depth = png.info.bit_depth as usize;
+            for (idx, frame) in png.frames.iter_mut().enumerate() {
+                frame.data = pack_indices(layer.frame(idx), frame.width as usize, depth);
+            }
+            png.encode()?
+        }
+    };
+    write_output(path, &data).context(Stage::Encode, path)
+}
+
+/// Chooses the pixel order from the options, over the pixels of the mask if
+/// one is given.
+fn pixel_order(
+    options: &[&String],
+    key: Option<&String>,
+    image: &PaletteImage,
+) -> Result<PixelOrder, AppError> {
+    let allowed = allowed_pixels(options, image.dimensions(), image.frame_count())?
+        .map(|allowed| image.frame_pixels(allowed));
+    let pixels = allowed.as_ref().map_or(image.layer.indices.len(), Vec::len);
+    let order = slot_order(options, key, pixels as u64);
+    Ok(match allowed {
+        Some(allowed) => PixelOrder::Masked(Box::new(order), allowed),
+        None => order,
+    })
+}
+
+fn check_extension(image: &PaletteImage, output_path: &Path) -> Result<(), AppError> {
+    let is_gif = matches!(image.container, Container::Gif { .. });
+    let output_gif = output_path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("gif"));
+    if crate::imageio::is_std(output_path) || is_gif == output_gif {
+        Ok(())
+    } else {
+        Err(AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            "The PAL method keeps the format of the input image.",
+        ))
+    }
+}
+
+/// Returns the number of bits the image can hold, framing included, in the
+/// pixels the options allow.
+pub fn capacity(image_path: &Path, options: &[&String]) -> Result<usize, AppError> {
+    let image = load(image_path)?;
+    let order = pixel_order(options, None, &image)?;
+    Ok(image.layer.capacity(&order))
+}
+
+pub fn embed(
+    image_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let start = Instant::now();
+    let mut image = load(image_path)?;
+    check_extension(&image, output_path)?;
+    report.time("load", start);
+    let (width, height) = image.dimensions();
+    report.width = Some(width);
+    report.height = Some(height);
+
+    let order = pixel_order(&options, key, &image)?;
+    let capacity_bits = image.layer.capacity(&order);
+    if verbose {
+        eprintln!(
+            "Indexed image: {} frames, {} pixels, {} usable",
+            image.frame_count(),
+            image.layer.indices.len(),
+            capacity_bits
+        );
+    }
+
+    let mut data = package_data(secret_data);
+    if data.len() * 8 > capacity_bits {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
+        ));
+    };
+
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits / 8)? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
+        }
+        pad_data(&mut data, len);
+    }
+    report.capacity(data.len() * 8, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let slots = image.layer.slots(&order).collect::<Vec<_>>();
+    let layer = &mut image.layer;
+    for (pixel, bit) in slots.into_iter().zip(BitIterator::new(&data)) {
+        if layer.bit(pixel) != bit {
+            layer.indices[pixel] = layer.partner(pixel).unwrap();
+        }
+    }
+    report.time("embed", start);
+
+    let start = Instant::now();
+    save(image, output_path)?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Image saved");
+    }
+
+    Ok(())
+}
+
+pub fn extract(
+    image_path: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let start = Instant::now();
+    let image = load(image_path)?;
+    report.time("load", start);
+    let (width, height) = image.dimensions();
+    report.width = Some(width);
+    report.height = Some(height);
+
+    let start = Instant::now();
+    let layer = &image.layer;
+    let order = pixel_order(&options, key, &image)?;
+    let capacity_bits = layer.capacity(&order);
+    if verbose {
+        eprintln!(
+            "Indexed image: {} frames, {} pixels, {} usable",
+            image.frame_count(),
+            layer.indices.len(),
+            capacity_bits
+        );
+    }
+
+    let mut bits = layer.slots(&order).map(|p| layer.bit(p));
+    let message_len = match read_bytes(&mut bits, 4).try_into() {
+        Ok(len) => u32::from_be_bytes(len) as usize,
+        Err(_) => 0,
+    };
+    if verbose {
+        eprintln!("Detected message length: {}", message_len)
+    };
+
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let secret = read_bytes(&mut bits, message_len + 4);
+    report.time("extract", start);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A 200x200 GIF whose only frame is 40x40, at (150, 150).
+    fn offset_gif(name: &str) -> PathBuf {
+        let palette = [0, 0, 0, 10, 10, 10, 200, 0, 0, 210, 0, 0];
+        let mut frame = gif::Frame {
+            left: 150,
+            top: 150,
+            width: 40,
+            height: 40,
+            buffer: (0..1600u32).map(|p| (p * 7 % 4) as u8).collect(),
+            ..gif::Frame::default()
+        };
+        frame.palette = None;
+        let data = encode_gif(200, 200, Some(&palette), gif::Repeat::Finite(0), &[frame]).unwrap();
+        let path = std::env::temp_dir().join(format!("kiki_{}_{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn masks_follow_an_offset_frame() {
+        let cover = offset_gif("offset.gif");
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        for rect in ["RECT=0,0,200,200", "RECT=150,150,40,40", "RECT=0,0,170,170"] {
+            let output = cover.with_extension("out.gif");
+            let options = [rect.to_string()];
+            let options = options.iter().collect::<Vec<_>>();
+            embed(
+                &cover,
+                &output,
+                b"secret",
+                None,
+                false,
+                options.clone(),
+                &mut Report::default(),
+            )
+            .unwrap();
+            let data = extract(
+                &output,
+                None,
+                false,
+                options,
+                &policy,
+                &mut Report::default(),
+            );
+            assert_eq!(data.unwrap(), b"secret", "{}", rect);
+            std::fs::remove_file(output).unwrap();
+        }
+        std::fs::remove_file(cover).unwrap();
+    }
+
+    #[test]
+    fn pairing_follows_luminance_and_transparency() {
+        let palette = [
+            [255, 255, 255, 255],
+            [0, 0, 0, 255],
+            [250, 250, 250, 255],
+            [10, 10, 10, 255],
+            [0, 0, 0, 0],
+        ];
+        let pairing = Pairing::new(&palette);
+        // Sorted: 4 (transparent), 1, 3, 2, 0. The transparent entry can't
+        // pair with black, which leaves white without a partner.
+        assert_eq!(pairing.partner[..5], [None, None, Some(3), Some(2), None]);
+        assert_eq!((pairing.bit[3], pairing.bit[2]), (0, 1));
+    }
+
+    #[test]
+    fn indexed_png_round_trip() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_indexed.png", std::process::id()));
+        let output = cover.with_extension("out.png");
+        let (width, height) = (67, 50);
+        let indices = (0..width * height)
+            .map(|p| (p * 5 % 16) as u8)
+            .collect::<Vec<_>>();
+        let palette = (0..16u8)
+            .flat_map(|i| [i * 16, 255 - i * 8, i])
+            .collect::<Vec<_>>();
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Four);
+        encoder.set_palette(palette);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&pack_indices(&indices, width, 4))
+            .unwrap();
+        writer.finish().unwrap();
+        std::fs::write(&cover, data).unwrap();
+
+        let key = "key".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        embed(
+            &cover,
+            &output,
+            b"indexed secret",
+            Some(&key),
+            false,
+            vec![],
+            &mut Report::default(),
+        )
+        .unwrap();
+        let before = load(&cover).unwrap().layer;
+        let after = load(&output).unwrap().layer;
+        let mut changed = 0;
+        for (pixel, &index) in after.indices.iter().enumerate() {
+            if index != before.indices[pixel] {
+                assert_eq!(before.partner(pixel), Some(index));
+                changed += 1;
+            }
+        }
+        assert!(changed > 0);
+
+        let data = extract(
+            &output,
+            Some(&key),
+            false,
+            vec![],
+            &policy,
+            &mut Report::default(),
+        );
+        assert_eq!(data.unwrap(), b"indexed secret");
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -21,6 +21,10 @@ impl Iterator for RandomPixelIterator {
     type Item = (u32, u32);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.used.len() as u64 >= self.imgwh.0 as u64 * self.imgwh.1 as u64 {
+            return None;
+        }
+
         let mut widx;
         let mut hidx;
         loop {
@@ -68,7 +72,7 @@ impl Iterator for SequentialPixelIterator {
 
 pub enum PixelIterator {
     Sequential(SequentialPixelIterator),
-    Random(RandomPixelIterator),
+    Random(Box<RandomPixelIterator>),
 }
 
 impl Iterator for PixelIterator {
@@ -81,3 +85,102 @@ impl Iterator for PixelIterator {
         }
     }
 }
+
+fn mix(mut x: u64) -> u64 {
+    // splitmix64 finalizer
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// A keyed permutation of `0..n`, computed index by index without any table.
+///
+/// It is a Feistel network over the smallest even number of bits covering `n`,
+/// with cycle walking to stay inside the range. Any index can be mapped on its
+/// own, so the order can be computed in parallel and in constant memory.
+#[derive(Clone)]
+pub struct KeyedPermutation {
+    n: u64,
+    half_bits: u32,
+    mask: u64,
+    keys: [u64; 4],
+}
+
+impl KeyedPermutation {
+    pub fn new(n: u64, rng: &mut StdRng) -> Self {
+        let bits = (64 - n.saturating_sub(1).leading_zeros()).max(2);
+        let half_bits = bits.div_ceil(2);
+        KeyedPermutation {
+            n,
+            half_bits,
+            mask: (1 << half_bits) - 1,
+            keys: [rng.gen(), rng.gen(), rng.gen(), rng.gen()],
+        }
+    }
+
+    fn encrypt(&self, x: u64) -> u64 {
+        let (mut left, mut right) = (x >> self.half_bits, x & self.mask);
+        for key in self.keys {
+            (left, right) = (right, left ^ (mix(right ^ key) & self.mask));
+        }
+        (left << self.half_bits) | right
+    }
+
+    pub fn get(&self, idx: u64) -> u64 {
+        let mut x = self.encrypt(idx);
+        while x >= self.n {
+            x = self.encrypt(x);
+        }
+        x
+    }
+}
+
+/// The order in which the slots of a carrier are used, as random access.
+pub enum PixelOrder {
+    Sequential,
+    Keyed(KeyedPermutation),
+    /// Precomputed positions, for orders that can only be iterated.
+    Table(Vec<u64>),
+    /// An order over the allowed positions of a mask only.
+    Masked(Box<PixelOrder>, Vec<u64>),
+}
+
+impl PixelOrder {
+    /// Position of the `idx`-th slot in the carrier.
+    pub fn get(&self, idx: u64) -> u64 {
+        match self {
+            PixelOrder::Sequential => idx,
+            PixelOrder::Keyed(perm) => perm.get(idx),
+            PixelOrder::Table(table) => table[idx as usize],
+            PixelOrder::Masked(order, allowed) => allowed[order.get(idx) as usize],
+        }
+    }
+
+    /// Number of slots of a masked order, which is fewer than the pixels.
+    pub fn limit(&self) -> Option<usize> {
+        match self {
+            PixelOrder::Masked(_, allowed) => Some(allowed.len()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn keyed_permutation_is_a_bijection() {
+        for n in [1, 2, 3, 5, 64, 1000, 4097, 65_535] {
+            let perm = KeyedPermutation::new(n, &mut StdRng::seed_from_u64(n));
+            let mut seen = vec![false; n as usize];
+            for idx in 0..n {
+                let pos = perm.get(idx);
+                assert!(pos < n, "{} out of 0..{}", pos, n);
+                assert!(!seen[pos as usize], "{} reached twice in 0..{}", pos, n);
+                seen[pos as usize] = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::errors::{AppError, AppErrorKind};
+use crate::imageio::save_image;
+use crate::report::Report;
+use image::{DynamicImage, ImageFormat};
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{
+    check_length, package_data, pad_data, padded_len, read_bytes, slot_order, unpackage_data,
+    BitIterator, LengthPolicy,
+};
+
+use super::lsb::{load_lossless, samples_8bit, Layout};
+use super::pixel::PixelOrder;
+
+// Pixel value differencing (Wu and Tsai): the difference of two neighbouring
+// samples falls into one of the ranges below, and is replaced by the one of
+// the same range and sign whose offset in it is the data. Wide ranges, at
+// edges, hold more bits than the narrow ones of smooth areas. The new pair
+// keeps the center of the old one, so whether a pair would overflow only
+// depends on its center and range, and it is skipped alike when reading.
+
+/// Bounds of the ranges of differences, each a power of two wide.
+const RANGES: [i16; 7] = [0, 8, 16, 32, 64, 128, 256];
+
+/// The lower bound of the range of a difference and the bits it holds.
+fn range(diff: i16) -> (i16, u32) {
+    let idx = RANGES.partition_point(|&bound| bound <= diff.abs()) - 1;
+    (
+        RANGES[idx],
+        (RANGES[idx + 1] - RANGES[idx]).trailing_zeros(),
+    )
+}
+
+fn center(first: i16, second: i16) -> i16 {
+    (first + second).div_euclid(2)
+}
+
+/// The two samples with the given center and difference.
+fn pair(center: i16, diff: i16) -> (i16, i16) {
+    (
+        center - diff.div_euclid(2),
+        center + (diff + 1).div_euclid(2),
+    )
+}
+
+/// The number of bits the pair holds, or None if the widest difference of its
+/// range would take a sample out of 0..=255.
+fn pair_bits(first: u8, second: u8) -> Option<u32> {
+    let (first, second) = (first as i16, second as i16);
+    let (lower, bits) = range(second - first);
+    let (low, high) = pair(center(first, second), lower + (1 << bits) - 1);
+    (0..=255)
+        .contains(&low)
+        .then_some(bits)
+        .filter(|_| (0..=255).contains(&high))
+}
+
+/// The pairs of horizontally neighbouring pixels of an image, as the indices
+/// of their samples, one pair per color channel.
+struct Pairs {
+    width: usize,
+    layout: Layout,
+    count: u64,
+}
+
+impl Pairs {
+    fn new(img: &DynamicImage) -> Self {
+        let width = img.width() as usize;
+        let color = img.color();
+        Pairs {
+            width,
+            layout: Layout::with_alpha(color.channel_count() as usize, color.has_alpha()),
+            count: (width / 2) as u64 * img.height() as u64,
+        }
+    }
+
+    /// The pairs of samples, taking the pairs of pixels in the given order.
+    fn samples<'a>(&'a self, order: &'a PixelOrder) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let Layout { stride, channels } = self.layout;
+        (0..self.count).flat_map(move |idx| {
+            let pair = order.get(idx) as usize;
+            let (row, column) = (pair / (self.width / 2), pair % (self.width / 2) * 2);
+            let first = (row * self.width + column) * stride;
+            (0..channels).map(move |channel| (first + channel, first + stride + channel))
+        })
+    }
+
+    fn capacity(&self, buf: &[u8]) -> usize {
+        self.samples(&PixelOrder::Sequential)
+            .filter_map(|(a, b)| pair_bits(buf[a], buf[b]))
+            .sum::<u32>() as usize
+    }
+}
+
+/// Returns the number of bits the image can hold, framing included.
+pub fn capacity(image_path: &Path, format: Option<ImageFormat>) -> Result<usize, AppError> {
+    let mut img = load_lossless(image_path, format)?;
+    let pairs = Pairs::new(&img);
+    Ok(pairs.capacity(samples_8bit(&mut img, "PVD")?))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn embed(
+    image_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let start = Instant::now();
+    let mut img = load_lossless(image_path, None)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+
+    let pairs = Pairs::new(&img);
+    let buf = samples_8bit(&mut img, "PVD")?;
+    let capacity_bits = pairs.capacity(buf);
+    if verbose {
+        eprintln!(
+            "Image: {} pairs of samples, {} bits",
+            pairs.count as usize * pairs.layout.channels,
+            capacity_bits
+        );
+    }
+
+    let mut data = package_data(secret_data);
+    if data.len() * 8 > capacity_bits {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
+        ));
+    };
+
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits / 8)? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
+        }
+        pad_data(&mut data, len);
+    }
+    report.capacity(data.len() * 8, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let order = slot_order(&options, key, pairs.count);
+    let mut bits = BitIterator::new(&data).peekable();
+    for (a, b) in pairs.samples(&order) {
+        if bits.peek().is_none() {
+            break;
+        }
+        let Some(count) = pair_bits(buf[a], buf[b]) else {
+            continue;
+        };
+        // The last pair may be only partly filled by the data.
+        let value = (0..count).fold(0i16, |acc, _| acc << 1 | bits.next().unwrap_or(0) as i16);
+        let (first, second) = (buf[a] as i16, buf[b] as i16);
+        let diff = second - first;
+        let magnitude = range(diff).0 + value;
+        let new = if diff < 0 { -magnitude } else { magnitude };
+        let (first, second) = pair(center(first, second), new);
+        buf[a] = first as u8;
+        buf[b] = second as u8;
+    }
+    report.time("embed", start);
+
+    let start = Instant::now();
+    save_image(&img, output_path, format)?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Image saved");
+    }
+
+    Ok(())
+}
+
+pub fn extract(
+    image_path: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let start = Instant::now();
+    let mut img = load_lossless(image_path, format)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+
+    let start = Instant::now();
+    let pairs = Pairs::new(&img);
+    let buf: &[u8] = samples_8bit(&mut img, "PVD")?;
+    let capacity_bits = pairs.capacity(buf);
+    if verbose {
+        eprintln!(
+            "Image: {} pairs of samples, {} bits",
+            pairs.count as usize * pairs.layout.channels,
+            capacity_bits
+        );
+    }
+
+    let order = slot_order(&options, key, pairs.count);
+    let mut bits = pairs.samples(&order).flat_map(|(a, b)| {
+        let count = pair_bits(buf[a], buf[b]).unwrap_or(0);
+        let diff = buf[b] as i16 - buf[a] as i16;
+        let value = diff.abs() - range(diff).0;
+        (0..count).rev().map(move |bit| (value >> bit) as u8 & 1)
+    });
+
+    let message_len = match read_bytes(&mut bits, 4).try_into() {
+        Ok(len) => u32::from_be_bytes(len) as usize,
+        Err(_) => 0,
+    };
+    if verbose {
+        eprintln!("Detected message length: {}", message_len)
+    };
+
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let secret = read_bytes(&mut bits, message_len + 4);
+    report.time("extract", start);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn pair_keeps_the_center() {
+        for first in -20..280 {
+            for diff in -255..=255 {
+                let (a, b) = pair(first, diff);
+                assert_eq!(b - a, diff);
+                assert_eq!(center(a, b), first);
+            }
+        }
+    }
+
+    #[test]
+    fn pair_bits_refuses_overflow() {
+        assert_eq!(pair_bits(0, 0), None);
+        assert_eq!(pair_bits(250, 255), None);
+        assert_eq!(pair_bits(100, 110), Some(3));
+        assert_eq!(pair_bits(100, 160), Some(5));
+        assert_eq!(pair_bits(60, 200), None);
+
+        // Every value a pair may take keeps it in range and in its range of
+        // differences, so that reading finds the same number of bits.
+        for first in 0..=255u8 {
+            for second in 0..=255u8 {
+                let Some(bits) = pair_bits(first, second) else {
+                    continue;
+                };
+                let diff = second as i16 - first as i16;
+                let mid = center(first as i16, second as i16);
+                for value in 0..1 << bits {
+                    let magnitude = range(diff).0 + value;
+                    let new = if diff < 0 { -magnitude } else { magnitude };
+                    let (a, b) = pair(mid, new);
+                    assert!((0..=255).contains(&a) && (0..=255).contains(&b));
+                    assert_eq!(pair_bits(a as u8, b as u8), Some(bits));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_near_the_bounds() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_pvd.png", std::process::id()));
+        let output = cover.with_extension("out.png");
+        // Dark, bright and edgy areas side by side.
+        let img = RgbImage::from_fn(96, 64, |x, y| {
+            let value = match x / 32 {
+                0 => (x * 7 + y * 3) % 6,
+                1 => 255 - (x * 5 + y) % 7,
+                _ => [2, 253, 128, 40][(x % 4) as usize] + y % 3,
+            };
+            image::Rgb([value as u8, (255 - value) as u8, value as u8])
+        });
+        img.save(&cover).unwrap();
+
+        let key = "key".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        let secret: Vec<u8> = (0..300).map(|i| (i * 61 + 7) as u8).collect();
+        embed(
+            &cover,
+            &output,
+            &secret,
+            Some(&key),
+            false,
+            vec![],
+            None,
+            &mut Report::default(),
+        )
+        .unwrap();
+        let data = extract(
+            &output,
+            Some(&key),
+            false,
+            vec![],
+            None,
+            &policy,
+            &mut Report::default(),
+        );
+        assert_eq!(data.unwrap(), secret);
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
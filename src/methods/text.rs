@@ -0,0 +1,396 @@
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::{read_input, write_output};
+use crate::report::Report;
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{
+    check_length, package_data, pad_data, padded_len, slot_order, unpackage_data, LengthPolicy,
+};
+
+// Text carriers hold one byte per slot, in the keyed order of the slots:
+// - ZW: the gaps between words, a space followed by a word, each holding four
+//   zero-width characters of two bits after the space;
+// - WS: the lines, each ending with eight spaces (0) and tabs (1), as SNOW
+//   does, in place of its own trailing whitespace.
+// Slots the data does not reach are left as they are.
+
+/// The methods that embed into text.
+pub const METHODS: [&str; 2] = ["ZW", "WS"];
+
+/// Zero-width characters for the values 0 to 3. ZWJ is left out, as emoji
+/// sequences use it.
+const ZERO_WIDTH: [char; 4] = ['\u{200B}', '\u{200C}', '\u{2060}', '\u{2063}'];
+const GAP_SYMBOLS: usize = 4;
+const LINE_BITS: usize = 8;
+
+fn load(path: &Path) -> Result<String, AppError> {
+    String::from_utf8(read_input(path)?).map_err(|_| {
+        AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            "The file is not UTF-8 text.",
+        )
+        .with_stage(Stage::Decode)
+        .with_path(path)
+    })
+}
+
+/// A line and its line ending.
+fn split_lines(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.split_inclusive('\n').map(|line| {
+        let content = line.trim_end_matches('\n').trim_end_matches('\r');
+        (content, &line[content.len()..])
+    })
+}
+
+fn is_trailing(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+#[derive(Clone, Copy)]
+enum Style {
+    ZeroWidth,
+    Whitespace,
+}
+
+impl Style {
+    fn new(method: &str) -> Self {
+        match method {
+            "ZW" => Style::ZeroWidth,
+            _ => Style::Whitespace,
+        }
+    }
+
+    /// Reads the byte of every slot of the text, None if it holds none.
+    fn read(self, text: &str) -> Vec<Option<u8>> {
+        match self {
+            Style::ZeroWidth => {
+                let mut slots = Vec::new();
+                let mut chars = text.chars().peekable();
+                while let Some(c) = chars.next() {
+                    if c != ' ' {
+                        continue;
+                    }
+                    let mut symbols = Vec::new();
+                    while let Some(value) = chars
+                        .peek()
+                        .and_then(|next| ZERO_WIDTH.iter().position(|z| z == next))
+                    {
+                        symbols.push(value as u8);
+                        chars.next();
+                    }
+                    if chars.peek().is_some_and(|next| !next.is_whitespace()) {
+                        slots.push(
+                            (symbols.len() == GAP_SYMBOLS)
+                                .then(|| symbols.iter().fold(0, |acc, value| acc << 2 | value)),
+                        );
+                    }
+                }
+                slots
+            }
+            Style::Whitespace => split_lines(text)
+                .map(|(content, _)| {
+                    let trailing = content.len() - content.trim_end_matches(is_trailing).len();
+                    (trailing >= LINE_BITS).then(|| {
+                        content[content.len() - LINE_BITS..]
+                            .chars()
+                            .fold(0, |acc, c| acc << 1 | (c == '\t') as u8)
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the bytes into their slots of the text.
+    fn write(self, text: &str, slots: &[Option<u8>]) -> String {
+        let mut out = String::with_capacity(text.len() + slots.len() * LINE_BITS * 3);
+        match self {
+            Style::ZeroWidth => {
+                let mut slots = slots.iter();
+                let mut chars = text.chars().peekable();
+                while let Some(c) = chars.next() {
+                    out.push(c);
+                    if c != ' ' || chars.peek().is_none_or(|next| next.is_whitespace()) {
+                        continue;
+                    }
+                    if let Some(Some(byte)) = slots.next() {
+                        out.extend(
+                            (0..GAP_SYMBOLS)
+                                .rev()
+                                .map(|idx| ZERO_WIDTH[(byte >> (2 * idx) & 3) as usize]),
+                        );
+                    }
+                }
+            }
+            Style::Whitespace => {
+                for ((content, ending), slot) in split_lines(text).zip(slots) {
+                    match slot {
+                        Some(byte) => {
+                            out.push_str(content.trim_end_matches(is_trailing));
+                            out.extend((0..LINE_BITS).rev().map(|idx| {
+                                if byte >> idx & 1 == 1 {
+                                    '\t'
+                                } else {
+                                    ' '
+                                }
+                            }));
+                        }
+                        None => out.push_str(content),
+                    }
+                    out.push_str(ending);
+                }
+            }
+        }
+        out
+    }
+
+    /// Refuses covers that would read back wrong.
+    fn check_cover(self, text: &str) -> Result<(), AppError> {
+        match self {
+            Style::ZeroWidth if text.contains(ZERO_WIDTH) => Err(AppError::new(
+                AppErrorKind::UnsupportedExtension,
+                "The text already holds zero-width characters, it may carry another payload.",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the number of bits the text can hold, framing included: a byte
+/// per gap between words with ZW, per line with WS.
+pub fn capacity(text_path: &Path, method: &str) -> Result<usize, AppError> {
+    Ok(Style::new(method).read(&load(text_path)?).len() * 8)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn embed(
+    text_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    method: &str,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let style = Style::new(method);
+    let start = Instant::now();
+    let text = load(text_path)?;
+    style.check_cover(&text).context(Stage::Decode, text_path)?;
+    report.time("load", start);
+
+    let slots = style.read(&text).len();
+    let capacity_bits = slots * 8;
+    if verbose {
+        eprintln!("Text: {} characters, {} slots", text.chars().count(), slots);
+    }
+
+    let mut data = package_data(secret_data);
+    if data.len() * 8 > capacity_bits {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
+        ));
+    };
+
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits / 8)? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
+        }
+        pad_data(&mut data, len);
+    }
+    report.capacity(data.len() * 8, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let order = slot_order(&options, key, slots as u64);
+    let mut bytes = vec![None; slots];
+    for (idx, &byte) in data.iter().enumerate() {
+        bytes[order.get(idx as u64) as usize] = Some(byte);
+    }
+    let stego = style.write(&text, &bytes);
+    report.time("embed", start);
+
+    let start = Instant::now();
+    write_output(output_path, stego.as_bytes())?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Text saved");
+    }
+
+    Ok(())
+}
+
+pub fn extract(
+    text_path: &Path,
+    method: &str,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let start = Instant::now();
+    let text = load(text_path)?;
+    report.time("load", start);
+
+    let start = Instant::now();
+    let slots = Style::new(method).read(&text);
+    let capacity_bits = slots.len() * 8;
+    if verbose {
+        eprintln!(
+            "Text: {} characters, {} slots",
+            text.chars().count(),
+            slots.len()
+        );
+    }
+    if slots.len() < 4 {
+        return Err(AppError::new(
+            AppErrorKind::NoPayload,
+            "The text is too short to hold any data.",
+        ));
+    }
+
+    let order = slot_order(&options, key, slots.len() as u64);
+    // Unlike pixels, slots can hold nothing at all, which tells an unmarked
+    // text apart.
+    let read_bytes = |start: usize, len: usize| -> Result<Vec<u8>, AppError> {
+        (start..start + len)
+            .map(|idx| slots[order.get(idx as u64) as usize])
+            .collect::<Option<_>>()
+            .ok_or_else(|| {
+                AppError::new(
+                    AppErrorKind::NoPayload,
+                    "The text holds no data where expected: wrong key or method, \
+                    or no data is present.",
+                )
+            })
+    };
+    let message_len = u32::from_be_bytes(read_bytes(0, 4)?.try_into().unwrap()) as usize;
+    if verbose {
+        eprintln!("Detected message length: {}", message_len)
+    };
+
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let secret = read_bytes(4, message_len + 4)?;
+    report.time("extract", start);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of words with CRLF and LF endings, trailing whitespace, double
+    /// spaces and an empty line.
+    fn cover() -> String {
+        (0..40)
+            .map(|line| match line % 5 {
+                0 => "Lorem ipsum  dolor sit amet, \r\n".to_string(),
+                1 => "\n".to_string(),
+                2 => format!("consectetur {} adipiscing\t\n", line),
+                _ => format!("elit sed do eiusmod {} tempor\n", line),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slots_round_trip() {
+        let text = cover();
+        for style in [Style::ZeroWidth, Style::Whitespace] {
+            let count = style.read(&text).len();
+            assert!(count >= 40);
+            assert!(style.read(&text).iter().all(Option::is_none));
+
+            let slots = (0..count)
+                .map(|idx| (idx % 3 != 1).then_some((idx * 73) as u8))
+                .collect::<Vec<_>>();
+            let stego = style.write(&text, &slots);
+            assert_eq!(style.read(&stego), slots);
+
+            let visible = |text: &str| {
+                split_lines(text)
+                    .map(|(content, ending)| {
+                        let content = content.replace(ZERO_WIDTH, "");
+                        content.trim_end_matches(is_trailing).to_string() + ending
+                    })
+                    .collect::<String>()
+            };
+            assert_eq!(visible(&stego), visible(&text));
+        }
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let dir = std::env::temp_dir();
+        let cover_path = dir.join(format!("kiki_{}_cover.txt", std::process::id()));
+        let output = cover_path.with_extension("out.txt");
+        std::fs::write(&cover_path, cover()).unwrap();
+
+        let key = "key".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        for method in METHODS {
+            embed(
+                &cover_path,
+                &output,
+                b"hidden words",
+                method,
+                Some(&key),
+                false,
+                vec![],
+                &mut Report::default(),
+            )
+            .unwrap();
+            let data = extract(
+                &output,
+                method,
+                Some(&key),
+                false,
+                vec![],
+                &policy,
+                &mut Report::default(),
+            );
+            assert_eq!(data.unwrap(), b"hidden words", "{}", method);
+
+            let err = extract(
+                &cover_path,
+                method,
+                Some(&key),
+                false,
+                vec![],
+                &policy,
+                &mut Report::default(),
+            )
+            .unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::NoPayload, "{}", method);
+
+            // The output of ZW cannot be used as a cover again.
+            if method == "ZW" {
+                let err = embed(
+                    &output,
+                    &output,
+                    b"again",
+                    method,
+                    None,
+                    false,
+                    vec![],
+                    &mut Report::default(),
+                )
+                .unwrap_err();
+                assert_eq!(err.kind, AppErrorKind::UnsupportedExtension);
+            }
+        }
+        std::fs::remove_file(cover_path).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,924 @@
+use crate::errors::{AppError, AppErrorKind};
+use crate::imageio::{self, load_image, save_image};
+use crate::report::Report;
+use image::{ColorType, DynamicImage, ImageFormat, RgbaImage};
+use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::f32::consts::PI;
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{hash_key, BitIterator, FromBits};
+
+// The mark lives in the DCT of the luminance, resampled to a fixed grid so
+// that it does not depend on the size of the image. Every coded bit is spread
+// over a few keyed coefficients and embedded by quantizing their projection
+// (spread transform dither modulation), which is insensitive to the image
+// content. A convolutional code and a CRC protect the message. The mark stays
+// clear of the borders, which a crop may remove, and a keyed noise pattern
+// covering the whole image tells where a cropped picture lies in the original.
+
+/// Side of the square grid the luminance is resampled to.
+const GRID: usize = 256;
+/// Side of the DCT blocks of the grid.
+const BLOCK: usize = 32;
+const BLOCKS: usize = GRID / BLOCK;
+/// Coefficients (u, v) with 0 < u + v <= BAND carry the mark: they survive
+/// JPEG and resampling, and tolerate a slight misalignment.
+const BAND: usize = 8;
+pub(crate) const BAND_COEFS: usize = BLOCKS * BLOCKS * ((BAND + 1) * (BAND + 2) / 2 - 1);
+
+/// Largest message, in bytes.
+pub const MAX_BYTES: usize = 32;
+/// Length byte, message padded to MAX_BYTES, CRC32.
+const FRAME_BITS: usize = 8 * (1 + MAX_BYTES + 4);
+/// Rate 1/2 convolutional code with constraint length 7.
+const CONSTRAINT: usize = 7;
+const POLYS: [u32; 2] = [0o171, 0o133];
+const CODED_BITS: usize = POLYS.len() * (FRAME_BITS + CONSTRAINT - 1);
+
+/// Quantization step of the projections at STRENGTH=1.
+const STEP: f32 = 32.0;
+/// Embedding is repeated to correct the rounding and clipping of the pixels.
+const PASSES: usize = 4;
+/// Widest crop looked for on each side, as a fraction of the original image,
+/// and the number of picture sizes tried between none and twice that.
+const MAX_CROP: f32 = 0.06;
+const CROP_STEPS: usize = 12;
+/// Amplitude of the sync noise, in gray levels, and the side of its cells on
+/// the grid.
+const SYNC_AMPLITUDE: f32 = 2.0;
+const SYNC_CELL: usize = 2;
+/// Mixed into the key so that the sync noise does not follow the spreading.
+const SYNC_SEED: u64 = 0x5359_4e43;
+
+/// The part of the original image a picture covers, as fractions of the
+/// original: left, right, top, bottom.
+type Region = [f32; 4];
+const FULL: Region = [0.0, 1.0, 0.0, 1.0];
+/// The part of the original image the mark is in.
+const INNER: Region = [MAX_CROP, 1.0 - MAX_CROP, MAX_CROP, 1.0 - MAX_CROP];
+
+/// Reads the STRENGTH=N option, and returns the quantization step it gives.
+pub(crate) fn step(options: &[&String]) -> Result<f32, AppError> {
+    match options.iter().find_map(|o| o.strip_prefix("STRENGTH")) {
+        None => Ok(STEP),
+        Some(value) => value
+            .strip_prefix('=')
+            .and_then(|v| v.parse::<f32>().ok())
+            .filter(|v| v.is_finite() && *v > 0.0)
+            .map(|v| v * STEP)
+            .ok_or_else(|| {
+                AppError::new(
+                    AppErrorKind::InvalidOption,
+                    format!("STRENGTH{} is not a valid strength option.", value),
+                )
+            }),
+    }
+}
+
+/// The luminance of an image.
+struct Luma {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+    mean: f32,
+}
+
+impl Luma {
+    fn new(img: &RgbaImage) -> Self {
+        let data: Vec<f32> = img
+            .pixels()
+            .map(|p| 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32)
+            .collect();
+        let mean = data.iter().sum::<f32>() / data.len().max(1) as f32;
+        Luma {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            data,
+            mean,
+        }
+    }
+
+    /// Averages blocks of pixels so that the image is at most a few times
+    /// larger than the grid, and returns the part of the picture it covers.
+    fn reduce(self) -> (Self, [f32; 2]) {
+        let factor = (self.width.min(self.height) / (2 * GRID)).max(1);
+        if factor == 1 {
+            return (self, [1.0, 1.0]);
+        }
+        let (width, height) = (self.width / factor, self.height / factor);
+        let mut data = vec![0.0; width * height];
+        for y in 0..height * factor {
+            for x in 0..width * factor {
+                data[y / factor * width + x / factor] += self.data[y * self.width + x];
+            }
+        }
+        let area = (factor * factor) as f32;
+        data.iter_mut().for_each(|v| *v /= area);
+        let coverage = [
+            (width * factor) as f32 / self.width as f32,
+            (height * factor) as f32 / self.height as f32,
+        ];
+        let mean = self.mean;
+        (
+            Luma {
+                width,
+                height,
+                data,
+                mean,
+            },
+            coverage,
+        )
+    }
+
+    /// Resamples `area` of the original image onto the grid by area averaging,
+    /// this picture covering `region` of the original. Cells outside the
+    /// picture get its mean.
+    fn resample(&self, region: Region, area: Region) -> Vec<f32> {
+        let columns = axis_weights(self.width, &region[..2], &area[..2]);
+        let rows = axis_weights(self.height, &region[2..], &area[2..]);
+
+        let mut horizontal = vec![0.0; self.height * GRID];
+        for y in 0..self.height {
+            let line = &self.data[y * self.width..(y + 1) * self.width];
+            for (i, weights) in columns.iter().enumerate() {
+                horizontal[y * GRID + i] = fill(weights, self.mean, |p| line[p]);
+            }
+        }
+
+        let mut grid = vec![0.0; GRID * GRID];
+        for (j, weights) in rows.iter().enumerate() {
+            for i in 0..GRID {
+                grid[j * GRID + i] = fill(weights, self.mean, |q| horizontal[q * GRID + i]);
+            }
+        }
+        grid
+    }
+}
+
+/// Weighted sum of the covered pixels, the uncovered part taking `mean`.
+fn fill(weights: &[(usize, f32)], mean: f32, value: impl Fn(usize) -> f32) -> f32 {
+    let covered: f32 = weights.iter().map(|(_, w)| w).sum();
+    weights.iter().map(|&(p, w)| w * value(p)).sum::<f32>() + (1.0 - covered) * mean
+}
+
+/// For each cell of the grid laid over `area` of the original along an axis,
+/// the pixels of a picture of `size` pixels covering `span` of the original
+/// that fall into it, with their share of the cell.
+fn axis_weights(size: usize, span: &[f32], area: &[f32]) -> Vec<Vec<(usize, f32)>> {
+    let scale = size as f32 / (span[1] - span[0]);
+    let cell = (area[1] - area[0]) / GRID as f32;
+    (0..GRID)
+        .map(|i| {
+            let s0 = (area[0] + i as f32 * cell - span[0]) * scale;
+            let s1 = (area[0] + (i + 1) as f32 * cell - span[0]) * scale;
+            let first = s0.max(0.0).floor() as usize;
+            let last = (s1.min(size as f32).ceil().max(0.0) as usize).min(size);
+            (first..last)
+                .map(|p| {
+                    let overlap = s1.min(p as f32 + 1.0) - s0.max(p as f32);
+                    (p, overlap.max(0.0) / (s1 - s0))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Samples the grid laid over `area` of a `width` x `height` image at every
+/// pixel, bilinearly, and 0 outside the area.
+fn upsample(grid: &[f32], width: usize, height: usize, area: Region) -> Vec<f32> {
+    let axis = |size: usize, start: f32, end: f32| {
+        (0..size)
+            .map(|p| {
+                let t = ((p as f32 + 0.5) / size as f32 - start) / (end - start);
+                let pos = (t * GRID as f32 - 0.5).clamp(0.0, (GRID - 1) as f32);
+                let first = (pos as usize).min(GRID - 2);
+                (first, pos - first as f32, (0.0..1.0).contains(&t))
+            })
+            .collect::<Vec<_>>()
+    };
+    let columns = axis(width, area[0], area[1]);
+    let rows = axis(height, area[2], area[3]);
+
+    let mut out = Vec::with_capacity(width * height);
+    for &(y, fy, row_inside) in &rows {
+        for &(x, fx, inside) in &columns {
+            if !(row_inside && inside) {
+                out.push(0.0);
+                continue;
+            }
+            let at = |y: usize, x: usize| grid[y * GRID + x];
+            let top = at(y, x) * (1.0 - fx) + at(y, x + 1) * fx;
+            let bottom = at(y + 1, x) * (1.0 - fx) + at(y + 1, x + 1) * fx;
+            out.push(top * (1.0 - fy) + bottom * fy);
+        }
+    }
+    out
+}
+
+/// The frequencies of a block that carry the mark.
+fn band() -> Vec<(usize, usize)> {
+    (0..=BAND)
+        .flat_map(|u| (0..=BAND - u).map(move |v| (u, v)))
+        .filter(|&(u, v)| u + v > 0)
+        .collect()
+}
+
+/// The rows of the orthonormal DCT-II matrix up to the band.
+fn dct_rows() -> Vec<[f32; BLOCK]> {
+    (0..=BAND)
+        .map(|u| {
+            let scale = if u == 0 { 1.0 } else { 2.0 } / BLOCK as f32;
+            let mut row = [0.0; BLOCK];
+            for (x, value) in row.iter_mut().enumerate() {
+                *value =
+                    scale.sqrt() * (PI * (2 * x + 1) as f32 * u as f32 / (2 * BLOCK) as f32).cos();
+            }
+            row
+        })
+        .collect()
+}
+
+/// The coefficients of the band of every block of the grid.
+fn forward(grid: &[f32]) -> Vec<f32> {
+    let (band, rows) = (band(), dct_rows());
+    let mut coefs = Vec::with_capacity(BAND_COEFS);
+    for block in 0..BLOCKS * BLOCKS {
+        let (top, left) = (block / BLOCKS * BLOCK, block % BLOCKS * BLOCK);
+        // Columns first: partial[u][x] = sum over y of row[u][y] * pixel(y, x).
+        let mut partial = vec![[0.0f32; BLOCK]; BAND + 1];
+        for (u, row) in rows.iter().enumerate() {
+            for (y, weight) in row.iter().enumerate() {
+                let line = &grid[(top + y) * GRID + left..][..BLOCK];
+                for (x, pixel) in line.iter().enumerate() {
+                    partial[u][x] += weight * pixel;
+                }
+            }
+        }
+        coefs.extend(band.iter().map(|&(u, v)| {
+            partial[u]
+                .iter()
+                .zip(&rows[v])
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+        }));
+    }
+    coefs
+}
+
+/// The grid whose band coefficients are `coefs` and all others zero.
+fn inverse(coefs: &[f32]) -> Vec<f32> {
+    let (band, rows) = (band(), dct_rows());
+    let mut grid = vec![0.0; GRID * GRID];
+    for (block, coefs) in coefs.chunks(band.len()).enumerate() {
+        let (top, left) = (block / BLOCKS * BLOCK, block % BLOCKS * BLOCK);
+        for (&(u, v), &coef) in band.iter().zip(coefs) {
+            for y in 0..BLOCK {
+                let weight = coef * rows[u][y];
+                let line = &mut grid[(top + y) * GRID + left..][..BLOCK];
+                for (pixel, basis) in line.iter_mut().zip(&rows[v]) {
+                    *pixel += weight * basis;
+                }
+            }
+        }
+    }
+    grid
+}
+
+fn parity(x: u32) -> u8 {
+    (x.count_ones() & 1) as u8
+}
+
+/// Encodes the bits with the convolutional code, ending in the zero state.
+fn conv_encode(bits: &[u8]) -> Vec<u8> {
+    let mut register = 0u32;
+    let mut out = Vec::with_capacity(CODED_BITS);
+    for &bit in bits.iter().chain(&[0; CONSTRAINT - 1]) {
+        register = (register << 1 | bit as u32) & ((1 << CONSTRAINT) - 1);
+        out.extend(POLYS.iter().map(|poly| parity(register & poly)));
+    }
+    out
+}
+
+/// Decodes soft values, positive for 0 and negative for 1, with the Viterbi
+/// algorithm.
+fn viterbi(soft: &[f32]) -> Vec<u8> {
+    const STATES: usize = 1 << (CONSTRAINT - 1);
+    let steps = soft.len() / POLYS.len();
+    let mut metrics = [f32::NEG_INFINITY; STATES];
+    metrics[0] = 0.0;
+    // For each step and state, the oldest bit of the best previous state.
+    let mut history = vec![[0u8; STATES]; steps];
+
+    for (step, soft) in soft.chunks(POLYS.len()).enumerate() {
+        let mut next = [f32::NEG_INFINITY; STATES];
+        for (state, &metric) in metrics.iter().enumerate() {
+            if metric == f32::NEG_INFINITY {
+                continue;
+            }
+            for bit in 0..2 {
+                let register = (state << 1 | bit) as u32;
+                let branch: f32 = POLYS
+                    .iter()
+                    .zip(soft)
+                    .map(|(poly, s)| if parity(register & poly) == 0 { *s } else { -s })
+                    .sum();
+                let target = register as usize & (STATES - 1);
+                if metric + branch > next[target] {
+                    next[target] = metric + branch;
+                    history[step][target] = (state >> (CONSTRAINT - 2)) as u8;
+                }
+            }
+        }
+        metrics = next;
+    }
+
+    let mut state = 0;
+    let mut bits = vec![0; steps];
+    for step in (0..steps).rev() {
+        bits[step] = (state & 1) as u8;
+        state = state >> 1 | (history[step][state] as usize) << (CONSTRAINT - 2);
+    }
+    bits.truncate(steps - (CONSTRAINT - 1));
+    bits
+}
+
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![message.len() as u8];
+    bytes.extend_from_slice(message);
+    bytes.resize(1 + MAX_BYTES, 0);
+    let crc = crc32fast::hash(&bytes);
+    bytes.extend(crc.to_be_bytes());
+    BitIterator::new(&bytes).collect()
+}
+
+fn unframe(bits: &[u8]) -> Option<Vec<u8>> {
+    let bytes: Vec<u8> = bits.chunks(8).map(u8::from_bits).collect();
+    let (body, crc) = bytes.split_at(1 + MAX_BYTES);
+    let len = body[0] as usize;
+    (crc32fast::hash(body).to_be_bytes() == crc && len <= MAX_BYTES)
+        .then(|| body[1..1 + len].to_vec())
+}
+
+/// Where each coded bit goes, derived from a seed: the coefficients it is
+/// spread over with their weights, and the dither of its quantizer.
+pub(crate) struct Spreading {
+    chips: Vec<(usize, f32)>,
+    per_bit: usize,
+    dither: Vec<f32>,
+    step: f32,
+}
+
+impl Spreading {
+    /// Spreads `bits` coded bits, at most BAND_COEFS, over the band.
+    pub(crate) fn new(seed: u64, step: f32, bits: usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut coefs: Vec<usize> = (0..BAND_COEFS).collect();
+        coefs.shuffle(&mut rng);
+        let per_bit = BAND_COEFS / bits;
+        let norm = 1.0 / (per_bit as f32).sqrt();
+        let chips = coefs[..bits * per_bit]
+            .iter()
+            .map(|&c| (c, if rng.gen() { norm } else { -norm }))
+            .collect();
+        let dither = (0..bits).map(|_| rng.gen::<f32>() * step).collect();
+        Spreading {
+            chips,
+            per_bit,
+            dither,
+            step,
+        }
+    }
+
+    fn chips(&self, bit: usize) -> &[(usize, f32)] {
+        &self.chips[bit * self.per_bit..(bit + 1) * self.per_bit]
+    }
+
+    /// The projection of the coefficients of the coded bit, less its dither.
+    fn project(&self, coefs: &[f32], bit: usize) -> f32 {
+        let projection: f32 = self.chips(bit).iter().map(|&(c, w)| coefs[c] * w).sum();
+        projection - self.dither[bit]
+    }
+
+    /// The change of the coefficients that moves every projection onto the
+    /// lattice of its coded bit, and the largest move.
+    fn delta(&self, coefs: &[f32], coded: &[u8]) -> (Vec<f32>, f32) {
+        let mut delta = vec![0.0; BAND_COEFS];
+        let mut largest = 0.0f32;
+        for (bit, &value) in coded.iter().enumerate() {
+            let projection = self.project(coefs, bit);
+            let offset = value as f32 * self.step / 2.0;
+            let target = ((projection - offset) / self.step).round() * self.step + offset;
+            let change = target - projection;
+            largest = largest.max(change.abs());
+            for &(c, w) in self.chips(bit) {
+                delta[c] += change * w;
+            }
+        }
+        (delta, largest)
+    }
+
+    /// Soft value of every coded bit: 1 on the lattice of 0, -1 on the one of 1.
+    fn soft(&self, coefs: &[f32]) -> Vec<f32> {
+        (0..self.dither.len())
+            .map(|bit| (2.0 * PI * self.project(coefs, bit) / self.step).cos())
+            .collect()
+    }
+}
+
+/// The outcome of reading the mark at one placement.
+struct Detection {
+    message: Option<Vec<u8>>,
+    score: f32,
+}
+
+/// How close the projections are to the lattices of the key, from -1 to 1:
+/// around 0 for an unmarked image or a wrong key, 1 for an intact mark.
+pub(crate) fn score(soft: &[f32]) -> f32 {
+    2.0 * soft.iter().map(|s| s * s).sum::<f32>() / soft.len() as f32 - 1.0
+}
+
+fn detect(soft: &[f32]) -> Detection {
+    Detection {
+        message: unframe(&viterbi(soft)),
+        score: score(soft),
+    }
+}
+
+/// The keyed sync noise on the grid, in cells of +1 and -1.
+fn sync_pattern(key: Option<&String>) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(hash_key(key) ^ SYNC_SEED);
+    let cells = GRID / SYNC_CELL;
+    let signs: Vec<f32> = (0..cells * cells)
+        .map(|_| if rng.gen() { 1.0 } else { -1.0 })
+        .collect();
+    (0..GRID * GRID)
+        .map(|i| signs[i / GRID / SYNC_CELL * cells + i % GRID / SYNC_CELL])
+        .collect()
+}
+
+type Complex = [f32; 2];
+
+/// The twiddle factors of an FFT of GRID points.
+fn twiddles() -> Vec<Complex> {
+    (0..GRID / 2)
+        .map(|k| {
+            let (sin, cos) = (-2.0 * PI * k as f32 / GRID as f32).sin_cos();
+            [cos, sin]
+        })
+        .collect()
+}
+
+/// In-place radix-2 FFT of GRID points; the inverse is not scaled.
+fn fft(data: &mut [Complex], twiddles: &[Complex], inverse: bool) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { -1.0 } else { 1.0 };
+    let mut len = 2;
+    while len <= n {
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let [cos, sin] = twiddles[k * (n / len)];
+                let sin = sign * sin;
+                let [ar, ai] = data[start + k];
+                let [br, bi] = data[start + k + len / 2];
+                let (tr, ti) = (br * cos - bi * sin, br * sin + bi * cos);
+                data[start + k] = [ar + tr, ai + ti];
+                data[start + k + len / 2] = [ar - tr, ai - ti];
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Two-dimensional FFT of the grid, rows then columns.
+fn fft2(grid: &mut [Complex], inverse: bool) {
+    let twiddles = twiddles();
+    for row in grid.chunks_mut(GRID) {
+        fft(row, &twiddles, inverse);
+    }
+    let mut column = vec![[0.0; 2]; GRID];
+    for x in 0..GRID {
+        for (y, value) in column.iter_mut().enumerate() {
+            *value = grid[y * GRID + x];
+        }
+        fft(&mut column, &twiddles, inverse);
+        for (y, value) in column.iter().enumerate() {
+            grid[y * GRID + x] = *value;
+        }
+    }
+}
+
+fn spectrum(grid: &[f32]) -> Vec<Complex> {
+    let mut data: Vec<Complex> = grid.iter().map(|&v| [v, 0.0]).collect();
+    fft2(&mut data, false);
+    data
+}
+
+/// The grid less its local mean, which keeps the fine detail the sync noise
+/// is made of and drops most of the picture.
+fn highpass(grid: &[f32]) -> Vec<f32> {
+    let at = |y: usize, x: usize| grid[y.min(GRID - 1) * GRID + x.min(GRID - 1)];
+    (0..GRID * GRID)
+        .map(|i| {
+            let (y, x) = (i / GRID, i % GRID);
+            let mut sum = 0.0;
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    sum += at((y + dy).saturating_sub(1), (x + dx).saturating_sub(1));
+                }
+            }
+            grid[i] - sum / 9.0
+        })
+        .collect()
+}
+
+/// The correlation of the high-passed grid with the sync noise, whose
+/// spectrum is given, for every cyclic shift of the noise, normalized by the
+/// energy of the grid.
+fn correlate(grid: &[f32], sync: &[Complex]) -> Vec<f32> {
+    let detail = highpass(grid);
+    let norm = detail.iter().map(|v| v * v).sum::<f32>().sqrt().max(1.0);
+    let mut product: Vec<Complex> = spectrum(&detail)
+        .iter()
+        .zip(sync)
+        .map(|(&[ar, ai], &[br, bi])| [ar * br + ai * bi, ar * bi - ai * br])
+        .collect();
+    fft2(&mut product, true);
+    product
+        .iter()
+        .map(|[re, _]| re / (norm * (GRID * GRID) as f32))
+        .collect()
+}
+
+/// Looks for the placement of a cropped picture in the original: for each
+/// size it may have, the shift at which it best matches the sync noise.
+fn register(luma: &Luma, coverage: [f32; 2], sync: &[Complex]) -> Region {
+    let mut best = (f32::NEG_INFINITY, FULL);
+    for steps_x in 0..=CROP_STEPS {
+        for steps_y in 0..=CROP_STEPS {
+            let width = 1.0 - 2.0 * MAX_CROP * steps_x as f32 / CROP_STEPS as f32;
+            let height = 1.0 - 2.0 * MAX_CROP * steps_y as f32 / CROP_STEPS as f32;
+            let region = covered([0.0, width, 0.0, height], coverage);
+            let grid = luma.resample(region, FULL);
+            let correlation = correlate(&grid, sync);
+            // The picture starts where the crop ends on the left and top.
+            let shifts = |size: f32| ((1.0 - size) * GRID as f32).ceil() as usize;
+            for dy in 0..=shifts(height) {
+                for dx in 0..=shifts(width) {
+                    let value = correlation[dy * GRID + dx];
+                    if value > best.0 {
+                        let (left, top) = (dx as f32 / GRID as f32, dy as f32 / GRID as f32);
+                        best = (value, [left, left + width, top, top + height]);
+                    }
+                }
+            }
+        }
+    }
+    best.1
+}
+
+/// Moves each side of the region, in finer and finer steps, to where the
+/// soft values are the most decisive.
+fn refine(mut region: Region, energy: impl Fn(Region) -> f32) -> Region {
+    let mut best = energy(region);
+    for step in [0.004, 0.002, 0.001, 0.0005] {
+        for side in 0..4 {
+            let center = region[side];
+            for offset in [-2.0, -1.0, 1.0, 2.0] {
+                let mut candidate = region;
+                candidate[side] = center + offset * step;
+                let value = energy(candidate);
+                if value > best {
+                    (best, region) = (value, candidate);
+                }
+            }
+        }
+    }
+    region
+}
+
+/// The region covered by the reduced picture, which may have lost a few
+/// pixels on the right and bottom.
+fn covered(region: Region, coverage: [f32; 2]) -> Region {
+    let [left, right, top, bottom] = region;
+    [
+        left,
+        left + (right - left) * coverage[0],
+        top,
+        top + (bottom - top) * coverage[1],
+    ]
+}
+
+/// Adds the sync noise of the key and the coded bits to the image.
+pub(crate) fn mark(
+    img: &DynamicImage,
+    key: Option<&String>,
+    spreading: &Spreading,
+    coded: &[u8],
+    verbose: bool,
+) -> RgbaImage {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut buf = img.to_rgba8();
+    let sync = upsample(&sync_pattern(key), width, height, FULL);
+    for (pixel, noise) in buf.pixels_mut().zip(sync) {
+        for channel in &mut pixel.0[..3] {
+            *channel = (*channel as f32 + SYNC_AMPLITUDE * noise)
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+    }
+    for pass in 0..PASSES {
+        let coefs = forward(&Luma::new(&buf).resample(FULL, INNER));
+        let (delta, largest) = spreading.delta(&coefs, coded);
+        if verbose {
+            eprintln!("Pass {}: largest change {:.2}", pass + 1, largest);
+        }
+        if largest < spreading.step / 16.0 {
+            break;
+        }
+        let pattern = upsample(&inverse(&delta), width, height, INNER);
+        for (pixel, change) in buf.pixels_mut().zip(pattern) {
+            for channel in &mut pixel.0[..3] {
+                *channel = (*channel as f32 + change).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    buf
+}
+
+/// Saves the marked image with the color type of the original. The mark
+/// survives lossy formats, which may not take alpha.
+pub(crate) fn save(
+    original: &DynamicImage,
+    marked: RgbaImage,
+    output_path: &Path,
+    format: Option<ImageFormat>,
+) -> Result<(), AppError> {
+    let lossy = imageio::extension(output_path, format)
+        .is_some_and(|e| matches!(e.as_str(), "jpg" | "jpeg"));
+    let marked = DynamicImage::ImageRgba8(marked);
+    let marked = match original.color() {
+        ColorType::L8 | ColorType::L16 => DynamicImage::ImageLuma8(marked.to_luma8()),
+        ColorType::La8 | ColorType::La16 if !lossy => {
+            DynamicImage::ImageLumaA8(marked.to_luma_alpha8())
+        }
+        ColorType::La8 | ColorType::La16 => DynamicImage::ImageLuma8(marked.to_luma8()),
+        color if color.has_alpha() && !lossy => marked,
+        _ => DynamicImage::ImageRgb8(marked.to_rgb8()),
+    };
+    save_image(&marked, output_path, format)
+}
+
+/// The soft values of the coded bits, read over the whole image or, when
+/// `found` rejects those, over the crop of the original it matches best.
+pub(crate) fn read(
+    img: &DynamicImage,
+    key: Option<&String>,
+    spreading: &Spreading,
+    verbose: bool,
+    found: impl Fn(&[f32]) -> bool,
+) -> Vec<f32> {
+    let (luma, coverage) = Luma::new(&img.to_rgba8()).reduce();
+    let soft =
+        |region: Region| spreading.soft(&forward(&luma.resample(covered(region, coverage), INNER)));
+
+    let whole = soft(FULL);
+    if found(&whole) {
+        return whole;
+    }
+    let sync = spectrum(&sync_pattern(key));
+    let region = refine(register(&luma, coverage, &sync), |region| {
+        score(&soft(region))
+    });
+    if verbose {
+        eprintln!(
+            "No mark on the whole image, trying the crop {:.3} {:.3} {:.3} {:.3}",
+            region[0],
+            1.0 - region[1],
+            region[2],
+            1.0 - region[3]
+        );
+    }
+    let cropped = soft(region);
+    if score(&cropped) > score(&whole) {
+        cropped
+    } else {
+        whole
+    }
+}
+
+/// Returns the number of bits the method can hold.
+pub fn capacity() -> usize {
+    MAX_BYTES * 8
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn embed(
+    image_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let start = Instant::now();
+    let img = load_image(image_path, None)?;
+    report.time("load", start);
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+    if verbose {
+        eprintln!("Image size: {}x{}", width, height);
+    }
+
+    if secret_data.len() > MAX_BYTES {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: the WM method holds up to {} bytes, {} given",
+                MAX_BYTES,
+                secret_data.len()
+            ),
+        ));
+    }
+    report.capacity((secret_data.len() + 5) * 8, FRAME_BITS);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let spreading = Spreading::new(hash_key(key), step(&options)?, CODED_BITS);
+    let marked = mark(
+        &img,
+        key,
+        &spreading,
+        &conv_encode(&frame(secret_data)),
+        verbose,
+    );
+    report.time("embed", start);
+
+    let start = Instant::now();
+    save(&img, marked, output_path, format)?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Image saved");
+    }
+    Ok(())
+}
+
+pub fn extract(
+    image_path: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    format: Option<ImageFormat>,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let start = Instant::now();
+    let img = load_image(image_path, format)?;
+    report.time("load", start);
+    report.width = Some(img.width());
+    report.height = Some(img.height());
+    if verbose {
+        eprintln!("Image size: {}x{}", img.width(), img.height());
+    }
+
+    let start = Instant::now();
+    let spreading = Spreading::new(hash_key(key), step(&options)?, CODED_BITS);
+    let soft = read(&img, key, &spreading, verbose, |soft| {
+        unframe(&viterbi(soft)).is_some()
+    });
+    let detection = detect(&soft);
+    report.time("extract", start);
+    report.score = Some(detection.score as f64);
+    if verbose {
+        eprintln!("Watermark score: {:.3}", detection.score);
+    }
+
+    match detection.message {
+        Some(message) => {
+            report.capacity((message.len() + 5) * 8, FRAME_BITS);
+            Ok(message)
+        }
+        None => Err(AppError::new(
+            AppErrorKind::NoPayload,
+            format!(
+                "No watermark found with this key and strength (score {:.3}).",
+                detection.score
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::imageops::{self, FilterType};
+    use image::RgbImage;
+
+    /// A photo-like image: smooth shapes and some grain.
+    fn photo(width: u32, height: u32) -> DynamicImage {
+        let mut rng = StdRng::seed_from_u64(7);
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let (x, y) = (x as f32 / 40.0, y as f32 / 30.0);
+            let base = 128.0 + 50.0 * x.sin() * y.cos() + 30.0 * (x * 0.3 + y * 0.7).sin();
+            let grain = rng.gen_range(-8.0..8.0);
+            let value = |shift: f32| (base + shift + grain).clamp(0.0, 255.0) as u8;
+            image::Rgb([value(20.0), value(0.0), value(-25.0)])
+        }))
+    }
+
+    fn jpeg(img: &RgbaImage, quality: u8) -> DynamicImage {
+        let mut data = Vec::new();
+        let rgb = DynamicImage::ImageRgba8(img.clone()).to_rgb8();
+        rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut data, quality))
+            .unwrap();
+        image::load_from_memory(&data).unwrap()
+    }
+
+    fn read_message(img: &DynamicImage, key: Option<&String>) -> Option<Vec<u8>> {
+        let spreading = Spreading::new(hash_key(key), STEP, CODED_BITS);
+        let soft = read(img, key, &spreading, false, |soft| {
+            unframe(&viterbi(soft)).is_some()
+        });
+        detect(&soft).message
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for message in [&b""[..], b"owner", &[0xA5; MAX_BYTES]] {
+            let bits = frame(message);
+            assert_eq!(bits.len(), FRAME_BITS);
+            assert_eq!(unframe(&bits).unwrap(), message);
+        }
+        let mut bits = frame(b"owner");
+        bits[20] ^= 1;
+        assert!(unframe(&bits).is_none());
+    }
+
+    #[test]
+    fn viterbi_corrects_bit_errors() {
+        let bits = frame(b"a short mark");
+        let coded = conv_encode(&bits);
+        assert_eq!(coded.len(), CODED_BITS);
+        let mut soft: Vec<f32> = coded
+            .iter()
+            .map(|&bit| if bit == 0 { 0.8 } else { -0.8 })
+            .collect();
+        // One coded bit in 12 read wrong, and a few more unsure.
+        for idx in (5..soft.len()).step_by(12) {
+            soft[idx] = -soft[idx];
+        }
+        for idx in (9..soft.len()).step_by(31) {
+            soft[idx] *= 0.1;
+        }
+        assert_eq!(viterbi(&soft), bits);
+        assert_eq!(unframe(&viterbi(&soft)).unwrap(), b"a short mark");
+    }
+
+    #[test]
+    fn mark_survives_jpeg_resize_and_crop() {
+        let key = "key".to_string();
+        let img = photo(320, 256);
+        let spreading = Spreading::new(hash_key(Some(&key)), STEP, CODED_BITS);
+        let coded = conv_encode(&frame(b"owner 42"));
+        let marked = mark(&img, Some(&key), &spreading, &coded, false);
+
+        let compressed = jpeg(&marked, 50);
+        assert_eq!(read_message(&compressed, Some(&key)).unwrap(), b"owner 42");
+
+        let resized = imageops::resize(&marked, 256, 204, FilterType::Triangle);
+        let resized = jpeg(&resized, 75);
+        assert_eq!(read_message(&resized, Some(&key)).unwrap(), b"owner 42");
+
+        let cropped = imageops::crop_imm(&marked, 12, 10, 320 - 28, 256 - 22).to_image();
+        let cropped = jpeg(&cropped, 75);
+        assert_eq!(read_message(&cropped, Some(&key)).unwrap(), b"owner 42");
+
+        // Over the whole image only: looking for a crop takes long unoptimized.
+        let whole = |img: &DynamicImage, key: &String| {
+            let spreading = Spreading::new(hash_key(Some(key)), STEP, CODED_BITS);
+            detect(&read(img, Some(key), &spreading, false, |_| true))
+        };
+        let other = whole(&compressed, &"other".to_string());
+        assert!(other.message.is_none() && other.score < 0.15);
+        let unmarked = whole(&img, &key);
+        assert!(unmarked.message.is_none() && unmarked.score < 0.15);
+        assert!(whole(&compressed, &key).score > 0.25);
+    }
+}
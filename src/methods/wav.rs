@@ -0,0 +1,326 @@
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::{read_input, write_output};
+use crate::report::Report;
+use std::ops::Range;
+use std::{path::Path, time::Instant};
+
+use crate::methods::data::{
+    check_length, package_data, pad_data, padded_len, read_bytes, slot_order, unpackage_data,
+    BitIterator, LengthPolicy,
+};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// A WAV file kept as raw bytes, with the location of its samples. Only the
+/// LSBs of the samples are ever changed, so every other chunk and the header
+/// are written back exactly as they were read.
+struct Wav {
+    bytes: Vec<u8>,
+    samples: Range<usize>,
+    bytes_per_sample: usize,
+    channels: u16,
+    bits: u16,
+}
+
+impl Wav {
+    fn parse(bytes: Vec<u8>) -> Result<Self, AppError> {
+        let invalid = |reason: &str| {
+            AppError::new(AppErrorKind::UnsupportedExtension, reason.to_string())
+                .with_stage(Stage::Decode)
+        };
+
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("Not a RIFF WAVE file."));
+        }
+
+        let mut fmt = None;
+        let mut samples = None;
+        let mut idx = 12;
+        while idx + 8 <= bytes.len() {
+            let id = &bytes[idx..idx + 4];
+            let size = u32::from_le_bytes(bytes[idx + 4..idx + 8].try_into().unwrap()) as usize;
+            let body = idx + 8..(idx + 8 + size).min(bytes.len());
+
+            match id {
+                b"fmt " if body.len() >= 16 => fmt = Some(body.clone()),
+                b"data" => samples = Some(body.clone()),
+                _ => {}
+            }
+            // Chunks are padded to an even size.
+            idx = body.start + size + size % 2;
+        }
+
+        let fmt = &bytes[fmt.ok_or_else(|| invalid("The WAV file has no fmt chunk."))?];
+        let samples = samples.ok_or_else(|| invalid("The WAV file has no data chunk."))?;
+
+        let field = |at: usize| u16::from_le_bytes([fmt[at], fmt[at + 1]]);
+        let format = match field(0) {
+            FORMAT_EXTENSIBLE if fmt.len() >= 26 => field(24),
+            format => format,
+        };
+        let channels = field(2);
+        let block_align = field(12) as usize;
+        let bits = field(14);
+
+        if format != FORMAT_PCM {
+            return Err(invalid("Only PCM WAV files are supported."));
+        }
+        if channels == 0
+            || !matches!(bits, 8 | 16 | 24)
+            || block_align != channels as usize * bits as usize / 8
+        {
+            return Err(invalid("Only 8, 16 and 24 bit WAV files are supported."));
+        }
+
+        Ok(Wav {
+            bytes,
+            samples,
+            bytes_per_sample: bits as usize / 8,
+            channels,
+            bits,
+        })
+    }
+
+    fn load(path: &Path) -> Result<Self, AppError> {
+        Wav::parse(read_input(path)?).context(Stage::Decode, path)
+    }
+
+    fn sample_count(&self) -> usize {
+        self.samples.len() / self.bytes_per_sample
+    }
+
+    /// Offset of the byte holding the LSB of the sample. Samples are little
+    /// endian, so it is their first byte.
+    fn lsb_offset(&self, sample: u64) -> usize {
+        self.samples.start + sample as usize * self.bytes_per_sample
+    }
+}
+
+fn print_spec(wav: &Wav) {
+    eprintln!(
+        "Audio: {} channels, {} bit, {} samples",
+        wav.channels,
+        wav.bits,
+        wav.sample_count()
+    );
+}
+
+/// Returns the number of bits the audio can hold, framing included.
+pub fn capacity(audio_path: &Path) -> Result<usize, AppError> {
+    Ok(Wav::load(audio_path)?.sample_count())
+}
+
+/// Returns the number of channels and the bits per sample of the audio.
+pub fn spec(data: Vec<u8>) -> Result<(u16, u16), AppError> {
+    let wav = Wav::parse(data)?;
+    Ok((wav.channels, wav.bits))
+}
+
+pub fn embed(
+    audio_path: &Path,
+    output_path: &Path,
+    secret_data: &[u8],
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    let start = Instant::now();
+    let mut wav = Wav::load(audio_path)?;
+    report.time("load", start);
+    if verbose {
+        print_spec(&wav);
+    }
+
+    let capacity_bits = wav.sample_count();
+    let mut data = package_data(secret_data);
+
+    if data.len() * 8 > capacity_bits {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "Data is too long: {} bytes needed, {} available",
+                data.len(),
+                capacity_bits / 8
+            ),
+        ));
+    };
+
+    if let Some(len) = padded_len(&options, data.len(), capacity_bits / 8)? {
+        if verbose {
+            eprintln!("Padding {} bytes of data to {} bytes", data.len(), len);
+        }
+        pad_data(&mut data, len);
+    }
+    report.capacity(data.len() * 8, capacity_bits);
+    report.crc_calculated = Some(crc32fast::hash(secret_data));
+
+    let start = Instant::now();
+    let order = slot_order(&options, key, wav.sample_count() as u64);
+    for (idx, bit) in BitIterator::new(&data).enumerate() {
+        let offset = wav.lsb_offset(order.get(idx as u64));
+        wav.bytes[offset] = wav.bytes[offset] & !1 | bit;
+    }
+    report.time("embed", start);
+
+    let start = Instant::now();
+    write_output(output_path, &wav.bytes)?;
+    report.time("save", start);
+
+    if verbose {
+        eprintln!("Audio saved");
+    }
+
+    Ok(())
+}
+
+pub fn extract(
+    audio_path: &Path,
+    key: Option<&String>,
+    verbose: bool,
+    options: Vec<&String>,
+    policy: &LengthPolicy,
+    report: &mut Report,
+) -> Result<Vec<u8>, AppError> {
+    let start = Instant::now();
+    let wav = Wav::load(audio_path)?;
+    report.time("load", start);
+    if verbose {
+        print_spec(&wav);
+    }
+
+    let start = Instant::now();
+    let capacity_bits = wav.sample_count();
+    if capacity_bits < 32 {
+        return Err(AppError::new(
+            AppErrorKind::NoPayload,
+            "The audio is too short to hold any data.",
+        ));
+    }
+
+    let order = slot_order(&options, key, wav.sample_count() as u64);
+    let mut bits =
+        (0..capacity_bits as u64).map(|idx| wav.bytes[wav.lsb_offset(order.get(idx))] & 1);
+    let message_len = u32::from_be_bytes(read_bytes(&mut bits, 4).try_into().unwrap()) as usize;
+    if verbose {
+        eprintln!("Detected message length: {}", message_len)
+    };
+
+    check_length(message_len, capacity_bits, policy)?;
+    report.capacity((message_len + 8) * 8, capacity_bits);
+
+    let secret = read_bytes(&mut bits, message_len + 4);
+    report.time("extract", start);
+    unpackage_data(secret, message_len, verbose, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A WAV file with an odd sized chunk before the samples.
+    fn wav(format: u16, channels: u16, bits: u16, samples: &[u8]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend(format.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(44100u32.to_le_bytes());
+        let block_align = channels * bits / 8;
+        fmt.extend((44100 * block_align as u32).to_le_bytes());
+        fmt.extend(block_align.to_le_bytes());
+        fmt.extend(bits.to_le_bytes());
+        if format == FORMAT_EXTENSIBLE {
+            fmt.extend(22u16.to_le_bytes());
+            fmt.extend(bits.to_le_bytes());
+            fmt.extend(0u32.to_le_bytes());
+            fmt.extend(FORMAT_PCM.to_le_bytes());
+            fmt.extend([0; 14]);
+        }
+
+        let mut body = b"WAVE".to_vec();
+        for (id, data) in [(b"fmt ", &fmt[..]), (b"LIST", b"odd"), (b"data", samples)] {
+            body.extend(id);
+            body.extend((data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        [
+            b"RIFF".as_slice(),
+            &(body.len() as u32).to_le_bytes(),
+            &body,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn parse_finds_the_samples() {
+        let samples: Vec<u8> = (0..120).collect();
+        for format in [FORMAT_PCM, FORMAT_EXTENSIBLE] {
+            let wav = Wav::parse(wav(format, 2, 24, &samples)).unwrap();
+            assert_eq!((wav.channels, wav.bits), (2, 24));
+            assert_eq!(wav.sample_count(), 40);
+            assert_eq!(&wav.bytes[wav.samples.clone()], &samples[..]);
+            assert_eq!(wav.bytes[wav.lsb_offset(1)], 3);
+        }
+
+        let unsupported = [
+            wav(3, 2, 32, &samples),
+            wav(FORMAT_PCM, 1, 32, &samples),
+            wav(FORMAT_PCM, 0, 16, &samples),
+            b"RIFF\0\0\0\0AVI ".to_vec(),
+            wav(FORMAT_PCM, 1, 16, &samples)[..36].to_vec(),
+        ];
+        for data in unsupported {
+            let err = Wav::parse(data).err().unwrap();
+            assert_eq!(err.kind, AppErrorKind::UnsupportedExtension);
+        }
+    }
+
+    #[test]
+    fn round_trip_only_changes_lsbs() {
+        let dir = std::env::temp_dir();
+        let cover = dir.join(format!("kiki_{}_cover.wav", std::process::id()));
+        let output = cover.with_extension("out.wav");
+        let samples: Vec<u8> = (0..4000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let original = wav(FORMAT_PCM, 2, 16, &samples);
+        std::fs::write(&cover, &original).unwrap();
+
+        let key = "key".to_string();
+        let policy = LengthPolicy {
+            limit: usize::MAX,
+            answer: Some(true),
+            stdin_free: false,
+        };
+        embed(
+            &cover,
+            &output,
+            b"a quiet secret",
+            Some(&key),
+            false,
+            vec![],
+            &mut Report::default(),
+        )
+        .unwrap();
+        let marked = std::fs::read(&output).unwrap();
+        assert_eq!(marked.len(), original.len());
+        let wav = Wav::parse(original.clone()).unwrap();
+        for (idx, (a, b)) in original.iter().zip(&marked).enumerate() {
+            let lsb = wav.samples.contains(&idx) && (idx - wav.samples.start).is_multiple_of(2);
+            assert!(a == b || lsb && a ^ b == 1, "byte {}", idx);
+        }
+
+        let data = extract(
+            &output,
+            Some(&key),
+            false,
+            vec![],
+            &policy,
+            &mut Report::default(),
+        );
+        assert_eq!(data.unwrap(), b"a quiet secret");
+        std::fs::remove_file(cover).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use crate::errors::{AppError, AppErrorKind};
+use std::io::Cursor;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, compression::DeflateLevel, Compression, TiffEncoder};
+use tiff::tags::{CompressionMethod, Tag};
+use tiff::ColorType;
+
+fn image_error(err: impl std::fmt::Display) -> AppError {
+    AppError::new(AppErrorKind::Image, err.to_string())
+}
+
+/// Returns true if the data starts with a TIFF header.
+pub fn is_tiff(data: &[u8]) -> bool {
+    data.starts_with(b"II*\0") || data.starts_with(b"MM\0*")
+}
+
+/// Returns the name of the lossy compression used by any page of the TIFF.
+pub fn lossy_compression(data: &[u8]) -> Result<Option<&'static str>, AppError> {
+    let mut decoder = Decoder::new(Cursor::new(data)).map_err(image_error)?;
+    loop {
+        let method = decoder
+            .find_tag(Tag::Compression)
+            .map_err(image_error)?
+            .and_then(|v| v.into_u16().ok())
+            .map(CompressionMethod::from_u16_exhaustive);
+        match method {
+            Some(CompressionMethod::JPEG | CompressionMethod::ModernJPEG) => {
+                return Ok(Some("JPEG"))
+            }
+            Some(CompressionMethod::WebP) => return Ok(Some("WebP")),
+            _ => {}
+        }
+        if !decoder.more_images() {
+            return Ok(None);
+        }
+        decoder.next_image().map_err(image_error)?;
+    }
+}
+
+pub enum PageData {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+}
+
+/// A page of a TIFF file, with its samples interleaved.
+pub struct Page {
+    pub width: u32,
+    pub height: u32,
+    pub color: ColorType,
+    pub data: PageData,
+}
+
+/// All the pages of a TIFF file. Pages are gray, RGB or RGBA, in 8 or 16 bits.
+pub struct Tiff {
+    pub pages: Vec<Page>,
+    compression: Compression,
+}
+
+impl Tiff {
+    pub fn decode(data: &[u8]) -> Result<Self, AppError> {
+        let mut decoder = Decoder::new(Cursor::new(data)).map_err(image_error)?;
+        let method = decoder
+            .find_tag(Tag::Compression)
+            .map_err(image_error)?
+            .and_then(|v| v.into_u16().ok())
+            .map(CompressionMethod::from_u16_exhaustive);
+        let compression = match method {
+            Some(CompressionMethod::LZW) => Compression::Lzw,
+            Some(CompressionMethod::Deflate | CompressionMethod::OldDeflate) => {
+                Compression::Deflate(DeflateLevel::default())
+            }
+            Some(CompressionMethod::PackBits) => Compression::Packbits,
+            _ => Compression::Uncompressed,
+        };
+
+        let mut pages = Vec::new();
+        loop {
+            let (width, height) = decoder.dimensions().map_err(image_error)?;
+            let color = decoder.colortype().map_err(image_error)?;
+            let data = match (color, decoder.read_image().map_err(image_error)?) {
+                (
+                    ColorType::Gray(8) | ColorType::RGB(8) | ColorType::RGBA(8),
+                    DecodingResult::U8(data),
+                ) => PageData::U8(data),
+                (
+                    ColorType::Gray(16) | ColorType::RGB(16) | ColorType::RGBA(16),
+                    DecodingResult::U16(data),
+                ) => PageData::U16(data),
+                _ => {
+                    return Err(AppError::new(
+                        AppErrorKind::UnsupportedExtension,
+                        format!("TIFF pages of type {:?} are not supported.", color),
+                    ))
+                }
+            };
+            pages.push(Page {
+                width,
+                height,
+                color,
+                data,
+            });
+
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().map_err(image_error)?;
+        }
+
+        Ok(Tiff { pages, compression })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, AppError> {
+        let mut out = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut out)
+            .map_err(image_error)?
+            .with_compression(self.compression);
+
+        for page in &self.pages {
+            let (w, h) = (page.width, page.height);
+            match (page.color, &page.data) {
+                (ColorType::Gray(_), PageData::U8(d)) => {
+                    encoder.write_image::<colortype::Gray8>(w, h, d)
+                }
+                (ColorType::RGB(_), PageData::U8(d)) => {
+                    encoder.write_image::<colortype::RGB8>(w, h, d)
+                }
+                (ColorType::RGBA(_), PageData::U8(d)) => {
+                    encoder.write_image::<colortype::RGBA8>(w, h, d)
+                }
+                (ColorType::Gray(_), PageData::U16(d)) => {
+                    encoder.write_image::<colortype::Gray16>(w, h, d)
+                }
+                (ColorType::RGB(_), PageData::U16(d)) => {
+                    encoder.write_image::<colortype::RGB16>(w, h, d)
+                }
+                (ColorType::RGBA(_), PageData::U16(d)) => {
+                    encoder.write_image::<colortype::RGBA16>(w, h, d)
+                }
+                _ => unreachable!(),
+            }
+            .map_err(image_error)?;
+        }
+
+        Ok(out.into_inner())
+    }
+}
@@ -0,0 +1,355 @@
+use crate::errors::{AppError, AppErrorKind};
+use serde::Serialize;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+// The payload is the content stored between the length and the CRC of the
+// packaged data. It is either the raw secret, or a kiki header followed by the
+// secret. The header is only written when needed, so plain secrets keep the
+// same layout as before.
+//
+// Header layout:
+//   magic    4 bytes   "KIKI"
+//   version  1 byte
+//   flags    1 byte
+//   fields   tag (1 byte), length (2 bytes BE), value. Ends with tag 0.
+
+pub const MAGIC: &[u8; 4] = b"KIKI";
+pub const VERSION: u8 = 1;
+
+pub const FLAG_FILE_META: u8 = 0b0000_0001;
+pub const FLAG_ARCHIVE: u8 = 0b0000_0010;
+
+const TAG_END: u8 = 0;
+const TAG_NAME: u8 = 1;
+const TAG_MIME: u8 = 2;
+const TAG_MTIME: u8 = 3;
+const TAG_MODE: u8 = 4;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct FileMeta {
+    pub name: Option<String>,
+    pub mime: Option<String>,
+    pub mtime: Option<u64>,
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+}
+
+impl Header {
+    pub fn is_archive(&self) -> bool {
+        self.flags & FLAG_ARCHIVE != 0
+    }
+}
+
+#[derive(Debug)]
+pub struct Payload {
+    pub header: Option<Header>,
+    pub meta: Option<FileMeta>,
+    pub data: Vec<u8>,
+}
+
+fn guess_mime(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("html" | "htm") => "text/html",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        Some("wav") => "audio/wav",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+impl FileMeta {
+    /// Collects the metadata of the file at the given path.
+    pub fn from_path(path: &Path) -> Result<Self, AppError> {
+        let metadata = fs::metadata(path)?;
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.to_string());
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Ok(FileMeta {
+            mime: name.as_deref().map(|n| guess_mime(n).to_string()),
+            name,
+            mtime,
+            mode,
+        })
+    }
+
+    /// Returns the stored filename, stripped of any directory components.
+    /// Names that would escape the current directory are rejected.
+    pub fn safe_name(&self) -> Result<Option<PathBuf>, AppError> {
+        let name = match &self.name {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let path = Path::new(name);
+        let mut components = path.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(file)), None)
+                if !name.contains(['/', '\\', '\0']) && !file.is_empty() =>
+            {
+                Ok(Some(PathBuf::from(file)))
+            }
+            _ => Err(AppError::new(
+                AppErrorKind::UnsafePath,
+                format!("Refusing to restore to unsafe filename {:?}", name),
+            )),
+        }
+    }
+
+    /// Applies the stored modification time and permissions to a file.
+    pub fn apply(&self, path: &Path) -> Result<(), AppError> {
+        if let Some(mtime) = self.mtime {
+            let file = fs::OpenOptions::new().write(true).open(path)?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        Ok(())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut field = |tag: u8, value: &[u8]| {
+            let len = value.len().min(u16::MAX as usize);
+            out.push(tag);
+            out.extend((len as u16).to_be_bytes());
+            out.extend_from_slice(&value[..len]);
+        };
+
+        if let Some(name) = &self.name {
+            field(TAG_NAME, name.as_bytes());
+        }
+        if let Some(mime) = &self.mime {
+            field(TAG_MIME, mime.as_bytes());
+        }
+        if let Some(mtime) = self.mtime {
+            field(TAG_MTIME, &mtime.to_be_bytes());
+        }
+        if let Some(mode) = self.mode {
+            field(TAG_MODE, &mode.to_be_bytes());
+        }
+        out.push(TAG_END);
+    }
+
+    fn decode(data: &[u8]) -> Result<(Self, usize), AppError> {
+        let mut meta = FileMeta::default();
+        let mut idx = 0;
+
+        loop {
+            let tag = *data.get(idx).ok_or_else(malformed)?;
+            idx += 1;
+            if tag == TAG_END {
+                return Ok((meta, idx));
+            }
+
+            let len = data.get(idx..idx + 2).ok_or_else(malformed)?;
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            idx += 2;
+            let value = data.get(idx..idx + len).ok_or_else(malformed)?;
+            idx += len;
+
+            match tag {
+                TAG_NAME => meta.name = Some(String::from_utf8_lossy(value).into_owned()),
+                TAG_MIME => meta.mime = Some(String::from_utf8_lossy(value).into_owned()),
+                TAG_MTIME => meta.mtime = value.try_into().ok().map(u64::from_be_bytes),
+                TAG_MODE => meta.mode = value.try_into().ok().map(u32::from_be_bytes),
+                // Unknown fields are skipped, so newer versions stay readable.
+                _ => {}
+            }
+        }
+    }
+}
+
+fn malformed() -> AppError {
+    AppError::new(AppErrorKind::MalformedPayload, "Malformed kiki header")
+}
+
+/// Builds the payload for the secret, adding a header when metadata or flags
+/// are given.
+pub fn encode(secret: &[u8], meta: Option<&FileMeta>, mut flags: u8) -> Vec<u8> {
+    if meta.is_some() {
+        flags |= FLAG_FILE_META;
+    }
+
+    // A raw secret that happens to start with the magic would be mistaken for
+    // a header, so it gets an empty one.
+    if flags == 0 && !secret.starts_with(MAGIC) {
+        return secret.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(secret.len() + 64);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.push(flags);
+    if let Some(meta) = meta {
+        meta.encode(&mut out);
+    }
+    out.extend_from_slice(secret);
+    out
+}
+
+/// Splits the extracted data into header, metadata and secret.
+pub fn decode(mut data: Vec<u8>) -> Result<Payload, AppError> {
+    if !data.starts_with(MAGIC) || data.len() < MAGIC.len() + 2 {
+        return Ok(Payload {
+            header: None,
+            meta: None,
+            data,
+        });
+    }
+
+    let header = Header {
+        version: data[MAGIC.len()],
+        flags: data[MAGIC.len() + 1],
+    };
+    if header.version > VERSION {
+        return Err(AppError::new(
+            AppErrorKind::MalformedPayload,
+            format!("Unsupported kiki header version {}", header.version),
+        ));
+    }
+
+    let mut start = MAGIC.len() + 2;
+    let meta = if header.flags & FLAG_FILE_META != 0 {
+        let (meta, len) = FileMeta::decode(&data[start..])?;
+        start += len;
+        Some(meta)
+    } else {
+        None
+    };
+
+    data.drain(..start);
+    Ok(Payload {
+        header: Some(header),
+        meta,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> FileMeta {
+        FileMeta {
+            name: Some("notes.txt".to_string()),
+            mime: Some(guess_mime("notes.txt").to_string()),
+            mtime: Some(1_700_000_000),
+            mode: Some(0o640),
+        }
+    }
+
+    #[test]
+    fn plain_secrets_keep_their_layout() {
+        assert_eq!(encode(b"secret", None, 0), b"secret");
+        let payload = decode(b"secret".to_vec()).unwrap();
+        assert!(payload.header.is_none() && payload.meta.is_none());
+        assert_eq!(payload.data, b"secret");
+
+        // Unless they start with the magic.
+        let tricky = b"KIKI\x01\x00data";
+        let encoded = encode(tricky, None, 0);
+        assert_ne!(encoded, tricky);
+        assert_eq!(decode(encoded).unwrap().data, tricky);
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let encoded = encode(b"secret", Some(&meta()), 0);
+        let payload = decode(encoded).unwrap();
+        let header = payload.header.unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.flags, FLAG_FILE_META);
+        assert!(!header.is_archive());
+        let decoded = payload.meta.unwrap();
+        assert_eq!(decoded.name.as_deref(), Some("notes.txt"));
+        assert_eq!(decoded.mime.as_deref(), Some("text/plain"));
+        assert_eq!(decoded.mtime, Some(1_700_000_000));
+        assert_eq!(decoded.mode, Some(0o640));
+        assert_eq!(payload.data, b"secret");
+
+        let archive = decode(encode(b"entries", None, FLAG_ARCHIVE)).unwrap();
+        assert!(archive.header.unwrap().is_archive());
+        assert_eq!(archive.data, b"entries");
+    }
+
+    #[test]
+    fn malformed_headers_are_refused() {
+        let mut encoded = encode(b"", Some(&meta()), 0);
+        // Cut in the middle of the fields.
+        encoded.truncate(MAGIC.len() + 2 + 5);
+        let err = decode(encoded).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::MalformedPayload);
+
+        let newer = [MAGIC.as_slice(), &[VERSION + 1, 0]].concat();
+        let err = decode(newer).unwrap_err();
+        assert_eq!(err.kind, AppErrorKind::MalformedPayload);
+
+        // Unknown fields are skipped.
+        let mut unknown = [MAGIC.as_slice(), &[VERSION, FLAG_FILE_META]].concat();
+        unknown.extend([9, 0, 2, 1, 2, TAG_END]);
+        unknown.extend(b"data");
+        assert_eq!(decode(unknown).unwrap().data, b"data");
+    }
+
+    #[test]
+    fn safe_names_stay_in_the_directory() {
+        let named = |name: &str| FileMeta {
+            name: Some(name.to_string()),
+            ..FileMeta::default()
+        };
+        assert_eq!(
+            named("notes.txt").safe_name().unwrap(),
+            Some(PathBuf::from("notes.txt"))
+        );
+        assert_eq!(FileMeta::default().safe_name().unwrap(), None);
+        for name in ["../x", "/etc/passwd", "a/b", "a\\b", "..", ""] {
+            let err = named(name).safe_name().unwrap_err();
+            assert_eq!(err.kind, AppErrorKind::UnsafePath, "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use crate::batch::{find_images, run_pool, BatchOptions};
+use crate::embed::embed;
+use crate::errors::{AppError, AppErrorKind, Context, Stage};
+use crate::imageio::load_image;
+use crate::methods::lsb;
+use crate::report::{CoverReport, CoverStats, Report};
+use image::DynamicImage;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::time::Instant;
+
+// Every cover is embedded into in memory the way LSB would embed the payload,
+// changing the LSB of a random fraction of the samples to random bits, and
+// both the cover and the result go through the statistics of common
+// steganalysers:
+// - RS analysis (Fridrich, Goljan and Du) estimates the fraction of samples
+//   that carry data from how flipping LSBs changes the smoothness of groups of
+//   samples. The payload raises the estimate by about its rate in any image,
+//   but what gives it away is how that compares with the readings of the
+//   clean image, measured as their spread over its tiles: they hardly vary
+//   in flat screenshots, and vary a lot in noisy photos.
+// - the chi-square attack (Westfeld and Pfitzmann) finds the pairs of values
+//   differing by their LSB evened out, which only full or sequential
+//   embedding does.
+// The risk is the higher of the probability the chi-square attack gives and
+// the rise of the RS estimate over the rise plus the spread, which is 0.5
+// when the payload moves the estimate as much as the tiles differ.
+
+/// Seed of the simulated embedding, so that rankings are reproducible.
+const SIMULATION_SEED: u64 = 0x52414e4b;
+/// Pairs of values need this many samples for the chi-square attack.
+const MIN_PAIR: u32 = 10;
+/// Side of the tiles over which the spread of RS estimates is measured.
+const TILE: usize = 64;
+/// Least spread assumed, for images too small or too flat to measure it.
+const MIN_SPREAD: f64 = 0.01;
+
+/// The 8 bit samples of the color channels, and their number per pixel. Alpha
+/// is left out, as LSB never changes it.
+fn samples(img: &DynamicImage) -> Result<(Vec<u8>, usize), AppError> {
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => {
+            Ok((img.to_luma8().into_raw(), 1))
+        }
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => {
+            Ok((img.to_rgb8().into_raw(), 3))
+        }
+        _ => Err(AppError::new(
+            AppErrorKind::UnsupportedExtension,
+            "rank analyses 8 bit images, whose LSBs steganalysis targets.",
+        )),
+    }
+}
+
+/// Mean absolute error of the median edge detector of LOCO-I, which predicts
+/// each sample from its left, upper and upper left neighbours, and the
+/// fraction of samples it predicts exactly.
+fn texture(buf: &[u8], width: usize, channels: usize) -> (f64, f64) {
+    let stride = width * channels;
+    let (mut error, mut exact, mut count) = (0u64, 0u64, 0u64);
+    for row in buf.chunks_exact(stride).collect::<Vec<_>>().windows(2) {
+        for idx in channels..stride {
+            let (a, b, c) = (
+                row[1][idx - channels] as i32,
+                row[0][idx] as i32,
+                row[0][idx - channels] as i32,
+            );
+            let predicted = if c >= a.max(b) {
+                a.min(b)
+            } else if c <= a.min(b) {
+                a.max(b)
+            } else {
+                a + b - c
+            };
+            let residual = (row[1][idx] as i32 - predicted).unsigned_abs() as u64;
+            error += residual;
+            exact += (residual == 0) as u64;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return (0.0, 1.0);
+    }
+    (error as f64 / count as f64, exact as f64 / count as f64)
+}
+
+/// Embeds random bits at the given rate, into random samples or, with SEQ,
+/// into the first ones.
+fn simulate(buf: &mut [u8], rate: f64, sequential: bool) {
+    let mut rng = StdRng::seed_from_u64(SIMULATION_SEED);
+    if sequential {
+        let len = ((buf.len() as f64 * rate).ceil() as usize).min(buf.len());
+        for sample in &mut buf[..len] {
+            *sample = *sample & !1 | rng.gen::<u8>() & 1;
+        }
+    } else {
+        for sample in buf.iter_mut() {
+            if rng.gen_bool(rate) {
+                *sample = *sample & !1 | rng.gen::<u8>() & 1;
+            }
+        }
+    }
+}
+
+/// The counts of regular and singular groups under the masks M and -M.
+#[derive(Default)]
+struct Groups {
+    regular: f64,
+    singular: f64,
+    regular_neg: f64,
+    singular_neg: f64,
+}
+
+impl Groups {
+    /// Classifies the groups of four horizontally neighbouring samples of a
+    /// channel in the rows, after flipping the LSBs of all of them if
+    /// `flipped`.
+    fn count<'a>(rows: impl Iterator<Item = &'a [u8]>, channels: usize, flipped: bool) -> Self {
+        // The mask [0, 1, 1, 0] flips the two middle samples.
+        fn smoothness(g: [i16; 4]) -> i16 {
+            (g[1] - g[0]).abs() + (g[2] - g[1]).abs() + (g[3] - g[2]).abs()
+        }
+        let flip = |v: i16| v ^ 1;
+        let flip_neg = |v: i16| ((v + 1) ^ 1) - 1;
+
+        let mut groups = Groups::default();
+        for row in rows {
+            for first in (0..row.len() / channels / 4).map(|g| g * 4 * channels) {
+                for channel in 0..channels {
+                    let g: [i16; 4] = std::array::from_fn(|i| {
+                        let v = row[first + i * channels + channel] as i16;
+                        if flipped {
+                            v ^ 1
+                        } else {
+                            v
+                        }
+                    });
+                    let f = smoothness(g);
+                    let positive = smoothness([g[0], flip(g[1]), flip(g[2]), g[3]]);
+                    let negative = smoothness([g[0], flip_neg(g[1]), flip_neg(g[2]), g[3]]);
+                    groups.regular += (positive > f) as u8 as f64;
+                    groups.singular += (positive < f) as u8 as f64;
+                    groups.regular_neg += (negative > f) as u8 as f64;
+                    groups.singular_neg += (negative < f) as u8 as f64;
+                }
+            }
+        }
+        groups
+    }
+}
+
+/// Estimates the fraction of the samples that carry embedded bits by RS
+/// analysis, from 0 to 1.
+fn rs_estimate<'a>(rows: impl Iterator<Item = &'a [u8]> + Clone, channels: usize) -> f64 {
+    let stego = Groups::count(rows.clone(), channels, false);
+    let flipped = Groups::count(rows, channels, true);
+    let d0 = stego.regular - stego.singular;
+    let d1 = flipped.regular - flipped.singular;
+    let d0_neg = stego.regular_neg - stego.singular_neg;
+    let d1_neg = flipped.regular_neg - flipped.singular_neg;
+
+    // The root of smallest magnitude of a x² + b x + c.
+    let (a, b, c) = (
+        2.0 * (d1 + d0),
+        d0_neg - d1_neg - d1 - 3.0 * d0,
+        d0 - d0_neg,
+    );
+    let x = if a.abs() < f64::EPSILON {
+        if b.abs() < f64::EPSILON {
+            return 0.0;
+        }
+        -c / b
+    } else {
+        let discriminant = (b * b - 4.0 * a * c).max(0.0).sqrt();
+        let (x1, x2) = (
+            (-b + discriminant) / (2.0 * a),
+            (-b - discriminant) / (2.0 * a),
+        );
+        if x1.abs() < x2.abs() {
+            x1
+        } else {
+            x2
+        }
+    };
+    (x / (x - 0.5)).clamp(0.0, 1.0)
+}
+
+/// The standard deviation of the RS estimates of the tiles of the image.
+fn rs_spread(buf: &[u8], width: usize, channels: usize) -> f64 {
+    let stride = width * channels;
+    let estimates = (0..buf.len() / stride / TILE)
+        .flat_map(|ty| (0..width / TILE).map(move |tx| (ty, tx)))
+        .map(|(ty, tx)| {
+            let rows = buf
+                .chunks_exact(stride)
+                .skip(ty * TILE)
+                .take(TILE)
+                .map(move |row| &row[tx * TILE * channels..(tx + 1) * TILE * channels]);
+            rs_estimate(rows, channels)
+        })
+        .collect::<Vec<_>>();
+    if estimates.len() < 2 {
+        return 0.0;
+    }
+    let mean = estimates.iter().sum::<f64>() / estimates.len() as f64;
+    let variance =
+        estimates.iter().map(|e| (e - mean).powi(2)).sum::<f64>() / (estimates.len() - 1) as f64;
+    variance.sqrt()
+}
+
+/// The probability of embedding the chi-square attack gives the samples: close
+/// to 1 when the values differing by their LSB are as frequent as each other.
+fn chi_square(buf: &[u8]) -> f64 {
+    let mut histogram = [0u32; 256];
+    buf.iter().for_each(|&v| histogram[v as usize] += 1);
+
+    let (mut chi, mut pairs) = (0.0, 0);
+    for pair in histogram.chunks_exact(2) {
+        if pair[0] + pair[1] < MIN_PAIR {
+            continue;
+        }
+        let expected = (pair[0] + pair[1]) as f64 / 2.0;
+        chi += (pair[0] as f64 - expected).powi(2) / expected;
+        pairs += 1;
+    }
+    if pairs < 2 {
+        return 0.0;
+    }
+    1.0 - gamma_p((pairs - 1) as f64 / 2.0, chi / 2.0)
+}
+
+fn ln_gamma(x: f64) -> f64 {
+    // Lanczos approximation, with g = 7.
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    let x = x - 1.0;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |acc, (i, c)| {
+            acc + c / (x + i as f64 + 1.0)
+        });
+    let t = x + 7.5;
+    0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+/// The regularized lower incomplete gamma function, by its series below a + 1
+/// and its continued fraction above.
+fn gamma_p(a: f64, x: f64) -> f64 {
+    const EPS: f64 = 1e-12;
+    const TINY: f64 = 1e-300;
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let scale = (-x + a * x.ln() - ln_gamma(a)).exp();
+    if x < a + 1.0 {
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        while term.abs() > sum.abs() * EPS {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+        }
+        return (sum * scale).min(1.0);
+    }
+
+    let mut b = x + 1.0 - a;
+    let (mut c, mut d) = (1.0 / TINY, 1.0 / b);
+    let mut h = d;
+    for i in 1..1000 {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.0;
+        d = an * d + b;
+        d = if d.abs() < TINY { TINY } else { d };
+        c = b + an / c;
+        c = if c.abs() < TINY { TINY } else { c };
+        d = 1.0 / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < EPS {
+            break;
+        }
+    }
+    (1.0 - h * scale).max(0.0)
+}
+
+/// Scores a cover for a payload of the given size.
+fn analyse(image: &Path, payload_bits: usize, opts: &BatchOptions) -> Result<CoverStats, AppError> {
+    let capacity_bits = lsb::capacity(image, opts.format, &opts.options)?;
+    let img = load_image(image, opts.format).context(Stage::Decode, image)?;
+    let (mut buf, channels) =
+        samples(&img).map_err(|err| err.with_stage(Stage::Decode).with_path(image))?;
+    let width = img.width() as usize;
+
+    let (noise, flat) = texture(&buf, width, channels);
+    let stride = width * channels;
+    let clean = rs_estimate(buf.chunks_exact(stride), channels);
+    let spread = rs_spread(&buf, width, channels);
+    let capacity_used = payload_bits as f64 / capacity_bits.max(1) as f64;
+    let sequential = opts.options.iter().any(|o| *o == "SEQ");
+    simulate(&mut buf, capacity_used.min(1.0), sequential);
+
+    let rs = rs_estimate(buf.chunks_exact(stride), channels);
+    let rise = (rs - clean).max(0.0);
+    // The attack scans growing parts of the image, of which the embedded part
+    // is the telling one.
+    let scanned = match sequential {
+        true => (buf.len() as f64 * capacity_used.min(1.0)).ceil() as usize,
+        false => buf.len(),
+    };
+    let chi_square = chi_square(&buf[..scanned.clamp(1, buf.len())]);
+
+    Ok(CoverStats {
+        capacity_bits,
+        capacity_used,
+        noise,
+        flat,
+        rs_rise: rise,
+        rs_spread: spread,
+        chi_square,
+        risk: (rise / (rise + spread.max(MIN_SPREAD))).max(chi_square),
+    })
+}
+
+fn print_ranking(covers: &[CoverReport]) {
+    println!(
+        "{:>4}  {:>5}  {:>5}  {:>5}  {:>5}  {:>5}  {:>5}  {:>6}  image",
+        "rank", "risk", "RS+", "RS sd", "chi2", "noise", "flat", "used"
+    );
+    for (idx, cover) in covers.iter().enumerate() {
+        match (&cover.stats, &cover.error) {
+            (Some(s), _) if s.capacity_used <= 1.0 => println!(
+                "{:>4}  {:>5.3}  {:>5.3}  {:>5.3}  {:>5.3}  {:>5.2}  {:>4.0}%  {:>5.1}%  {}",
+                idx + 1,
+                s.risk,
+                s.rs_rise,
+                s.rs_spread,
+                s.chi_square,
+                s.noise,
+                s.flat * 100.0,
+                s.capacity_used * 100.0,
+                cover.image
+            ),
+            (Some(s), _) => println!(
+                "{:>4}  {:>40}  {:>6}  {}",
+                "-",
+                format!("too small, holds {} bytes", s.capacity_bits / 8),
+                "",
+                cover.image
+            ),
+            (None, error) => println!(
+                "FAIL  {}: {}",
+                cover.image,
+                error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Ranks the images selected by the pattern by how safely they would hide the
+/// secret with LSB, the covers it does not fit in last, and embeds it into the
+/// best one if an output is given, as `embed` would.
+pub fn rank(
+    pattern: &str,
+    secret: &[u8],
+    output: Option<&Path>,
+    verify: bool,
+    strip_metadata: bool,
+    opts: BatchOptions,
+    report: &mut Report,
+) -> Result<(), AppError> {
+    if let Some(method) = opts.method.filter(|m| *m != "LSB") {
+        return Err(AppError::new(
+            AppErrorKind::UnsupportedMethod,
+            format!("rank predicts the detectability of LSB, not {}.", method),
+        ));
+    }
+    let images = find_images(pattern)?;
+    // The length and the CRC are embedded along with the secret.
+    let payload_bits = (secret.len() + 8) * 8;
+
+    let start = Instant::now();
+    let mut covers = run_pool(opts.jobs, || {
+        images
+            .par_iter()
+            .map(|image| {
+                let result = analyse(image, payload_bits, &opts);
+                if opts.verbose {
+                    if let Err(err) = &result {
+                        eprintln!("{}: {}", image.display(), err);
+                    }
+                }
+                CoverReport {
+                    image: image.display().to_string(),
+                    stats: result.as_ref().ok().copied(),
+                    error: result.err().map(|err| err.to_string()),
+                }
+            })
+            .collect::<Vec<_>>()
+    })?;
+    covers.sort_by(|a, b| match (&a.stats, &b.stats) {
+        (Some(a), Some(b)) => (a.capacity_used > 1.0)
+            .cmp(&(b.capacity_used > 1.0))
+            // Risks within a hundredth are even, the noisier cover wins.
+            .then(
+                (a.risk * 100.0)
+                    .round()
+                    .total_cmp(&(b.risk * 100.0).round()),
+            )
+            .then(b.noise.total_cmp(&a.noise)),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    report.time("rank", start);
+
+    if !report.json {
+        print_ranking(&covers);
+    }
+    let best = covers.first().and_then(|c| {
+        c.stats
+            .filter(|s| s.capacity_used <= 1.0)
+            .map(|_| c.image.clone())
+    });
+    report.covers = Some(covers);
+
+    let Some(best) = best else {
+        return Err(AppError::new(
+            AppErrorKind::DataOverflow,
+            format!(
+                "None of the {} images can hold the {} bytes of the secret with LSB.",
+                images.len(),
+                secret.len()
+            ),
+        ));
+    };
+
+    let Some(output) = output else {
+        return Ok(());
+    };
+    if !report.json {
+        println!("Embedding into {}", best);
+    }
+    let lsb = "LSB".to_string();
+    report.image = Some(best.clone());
+    report.output = Some(output.display().to_string());
+    embed(
+        Path::new(&best),
+        output,
+        secret,
+        Some(&lsb),
+        opts.key,
+        opts.verbose,
+        opts.options,
+        opts.format,
+        verify,
+        strip_metadata,
+        report,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A smooth 256x256 grey image with some noise, like a photo.
+    fn photo() -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..256 * 256)
+            .map(|p| {
+                let (x, y) = ((p % 256) as f64, (p / 256) as f64);
+                let v =
+                    128.0 + 60.0 * (x / 23.0).sin() * (y / 31.0).cos() + rng.gen_range(-3.0..3.0);
+                v.round() as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gamma_matches_known_values() {
+        assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-12);
+        assert!((ln_gamma(0.5) - PI.sqrt().ln()).abs() < 1e-12);
+        for x in [0.1, 1.0, 2.5, 10.0] {
+            // P(1, x) = 1 - e^-x
+            assert!((gamma_p(1.0, x) - (1.0 - (-x).exp())).abs() < 1e-10);
+        }
+        // P(1/2, x) = erf(√x), on both sides of a + 1.
+        assert!((gamma_p(0.5, 1.0) - 0.842_700_792_949_714_9).abs() < 1e-10);
+        assert!((gamma_p(0.5, 4.0) - 0.995_322_265_018_952_7).abs() < 1e-10);
+        assert_eq!(gamma_p(3.0, 0.0), 0.0);
+        assert!(gamma_p(3.0, 200.0) > 1.0 - 1e-12);
+    }
+
+    #[test]
+    fn chi_square_finds_evened_pairs() {
+        let clean = photo();
+        assert!(chi_square(&clean) < 0.1);
+        let mut full = clean.clone();
+        simulate(&mut full, 1.0, false);
+        assert!(chi_square(&full) > 0.99);
+        // Values too rare to pair count for nothing.
+        assert_eq!(chi_square(&[0, 1, 2, 3, 4, 5]), 0.0);
+    }
+
+    #[test]
+    fn rs_estimates_the_rate() {
+        for rate in [0.0, 0.25, 0.5] {
+            let mut buf = photo();
+            simulate(&mut buf, rate, false);
+            let estimate = rs_estimate(buf.chunks_exact(256), 1);
+            assert!((estimate - rate).abs() < 0.06, "{} {}", rate, estimate);
+        }
+        assert_eq!(rs_estimate([[9u8; 16].as_slice()].into_iter(), 1), 0.0);
+    }
+}
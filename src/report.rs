@@ -0,0 +1,230 @@
+use crate::archive::Entry;
+use crate::errors::AppError;
+use crate::payload::{FileMeta, Header};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// Machine-readable summary of an operation, printed with --json.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    #[serde(skip)]
+    pub json: bool,
+    pub command: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    pub options: Vec<String>,
+    /// Format of the carrier, reported by inspect.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_depth: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Size of the secret, as given to embed or as extracted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_bytes: Option<usize>,
+    /// Bits written into or read from the carrier, including framing and padding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_bits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity_bits: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity_used: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crc_stored: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crc_calculated: Option<u32>,
+    /// Whether the saved image was read back and holds the data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verified: Option<bool>,
+    /// Correlation of the watermark read with the expected one, from -1 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Blocks of the image complex enough for BPCS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<BlockReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<HeaderReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMeta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<Entry>>,
+    /// What each method finds in the carrier, reported by inspect.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub methods: Option<Vec<MethodReport>>,
+    /// Recipients ranked by trace, the most likely first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspects: Option<Vec<SuspectReport>>,
+    /// Candidate covers ranked by rank, the safest first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub covers: Option<Vec<CoverReport>>,
+    /// Reports of the single files of a batch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<Report>>,
+    pub timings_ms: BTreeMap<&'static str, f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReport>,
+}
+
+/// Outcome of reading a payload with one method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadStatus {
+    /// A payload was found and passed the CRC check.
+    Found,
+    /// The length read does not fit in the carrier.
+    None,
+    /// A length was read, but the CRC does not match: wrong key, or damaged data.
+    CrcMismatch,
+    /// The CRC matches but the kiki header is invalid.
+    Malformed,
+    /// The method cannot read the carrier.
+    Unreadable,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MethodReport {
+    pub method: String,
+    pub capacity_bits: usize,
+    /// Largest secret that fits, once the length and the CRC are stored.
+    pub capacity_bytes: usize,
+    pub payload: PayloadStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<BlockReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<HeaderReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMeta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// How many 8x8 bit plane blocks are complex enough to carry data.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BlockReport {
+    pub qualified: usize,
+    pub total: usize,
+}
+
+/// How strongly a suspect image carries the fingerprint of a recipient.
+#[derive(Debug, Serialize)]
+pub struct SuspectReport {
+    pub recipient: String,
+    pub score: f64,
+    pub accused: bool,
+}
+
+/// How well a candidate cover would hide the payload.
+#[derive(Debug, Serialize)]
+pub struct CoverReport {
+    pub image: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<CoverStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CoverStats {
+    pub capacity_bits: usize,
+    /// Fraction of the capacity the payload takes, above 1 if it does not fit.
+    pub capacity_used: f64,
+    /// Mean error of predicting each sample from its neighbours: the texture.
+    pub noise: f64,
+    /// Fraction of the samples predicted exactly, as in flat areas.
+    pub flat: f64,
+    /// How much the payload raises the embedding rate RS analysis finds.
+    pub rs_rise: f64,
+    /// Standard deviation of the RS estimates of the tiles of the cover.
+    pub rs_spread: f64,
+    /// Probability of embedding that the chi-square attack gives then.
+    pub chi_square: f64,
+    /// From 0 to 1, the higher of the chi-square probability and the RS rise
+    /// over the rise plus the spread. Covers are ranked by it.
+    pub risk: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeaderReport {
+    pub version: u8,
+    pub flags: u8,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub kind: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub exit_code: i32,
+}
+
+impl Report {
+    pub fn new(command: &str) -> Self {
+        Report {
+            command: command.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Records the time elapsed since `start` under the given step.
+    pub fn time(&mut self, step: &'static str, start: Instant) {
+        self.timings_ms
+            .insert(step, start.elapsed().as_secs_f64() * 1000.0);
+    }
+
+    /// Records the carrier size and how much of it is used.
+    pub fn capacity(&mut self, used_bits: usize, capacity_bits: usize) {
+        self.used_bits = Some(used_bits);
+        self.capacity_bits = Some(capacity_bits);
+        if capacity_bits > 0 {
+            self.capacity_used = Some(used_bits as f64 / capacity_bits as f64);
+        }
+    }
+
+    pub fn header(&mut self, header: &Header) {
+        self.header = Some(HeaderReport {
+            version: header.version,
+            flags: header.flags,
+        });
+    }
+
+    pub fn error(&mut self, err: &AppError) {
+        self.success = false;
+        self.error = Some(ErrorReport {
+            kind: format!("{:?}", err.kind),
+            message: err.to_string(),
+            stage: err.stage.map(|s| format!("{:?}", s)),
+            path: err.path.as_ref().map(|p| p.display().to_string()),
+            exit_code: err.exit_code(),
+        });
+    }
+
+    /// Prints the report as a single line of JSON, to stderr if stdout is
+    /// already used for data.
+    pub fn print(&self, to_stderr: bool) {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        let _ = if to_stderr {
+            writeln!(io::stderr(), "{}", json)
+        } else {
+            writeln!(io::stdout(), "{}", json)
+        };
+    }
+}